@@ -0,0 +1,24 @@
+//! Embeds the git commit as `GIT_HASH`, used as the code version of releases.
+//! An already set `GIT_HASH` (e.g. from a build system without `.git`) wins.
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    if std::env::var("GIT_HASH").is_ok() {
+        return;
+    }
+    let hash = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty());
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=GIT_HASH={hash}");
+    }
+}
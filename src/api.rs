@@ -0,0 +1,706 @@
+//! Read-only HTTP JSON API for group results.
+//!
+//! Endpoints (all `GET`, list endpoints take `offset` and `limit`):
+//! - `/groups`
+//! - `/groups/{group_id}/months`
+//! - `/groups/{group_id}/months/{year}/{month}`
+//! - `/groups/{group_id}/months/{year}/{month}/sites`
+//! - `/groups/{group_id}/months/{year}/{month}/top_files`
+//! - `/groups/{group_id}/months/{year}/{month}/top_pages`
+//!
+//! Responses carry an `ETag`; a matching `If-None-Match` gets a 304.
+//! [`ApiBackend::Sqlite`] serves a database following
+//! `baglama.sqlite3_schema`, for testing and local use.
+
+use crate::{
+    group_report::GroupReport,
+    results_reader::{
+        GroupResults, PageViews, ResultPage, ResultsReader, Sqlite3Reader, StorageReader,
+    },
+    row_group::RowGroup,
+    row_group_status::{RowGroupStatus, StorageType},
+    wiki_report::WikiReport,
+    Baglama2, DbId, GroupId, YearMonth,
+};
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use log::error;
+use mysql_async::{from_row, prelude::*};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_PAGE_LIMIT: usize = 50;
+pub const MAX_PAGE_LIMIT: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pagination {
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl Pagination {
+    pub fn from_query(query: &HashMap<String, String>) -> Result<Self> {
+        let parse = |key: &str, default: usize| -> Result<usize> {
+            match query.get(key) {
+                Some(s) => s.parse().map_err(|_| anyhow!("Bad value for {key}: '{s}'")),
+                None => Ok(default),
+            }
+        };
+        Ok(Self {
+            offset: parse("offset", 0)?,
+            limit: parse("limit", DEFAULT_PAGE_LIMIT)?.clamp(1, MAX_PAGE_LIMIT),
+        })
+    }
+
+    fn to_json(self, total: usize, items: Vec<Value>) -> Value {
+        json!({
+            "offset": self.offset,
+            "limit": self.limit,
+            "total": total,
+            "items": items,
+        })
+    }
+
+    fn slice<T: Clone>(&self, items: &[T]) -> Vec<T> {
+        items
+            .iter()
+            .skip(self.offset)
+            .take(self.limit)
+            .cloned()
+            .collect()
+    }
+}
+
+fn group_status_json(gs: &RowGroupStatus) -> Value {
+    json!({
+        "group_id": gs.group_id,
+        "month": format!("{}-{:02}", gs.year, gs.month),
+        "status": gs.status,
+        "total_views": gs.total_views,
+        "usage_views": gs.usage_views,
+        "excluded_views": gs.excluded_views,
+    })
+}
+
+pub enum ApiBackend {
+    /// `is_private_column` is false for a tool DB without `groups.is_private`,
+    /// where all groups are public.
+    Mysql {
+        baglama: Arc<Baglama2>,
+        is_private_column: bool,
+    },
+    Sqlite(Mutex<rusqlite::Connection>),
+}
+
+impl ApiBackend {
+    pub async fn mysql(baglama: Arc<Baglama2>) -> Result<Self> {
+        let sql = "SELECT COUNT(*) FROM information_schema.COLUMNS WHERE TABLE_SCHEMA=DATABASE() AND TABLE_NAME='groups' AND COLUMN_NAME='is_private'";
+        let columns: usize = baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_first(sql, ())
+            .await?
+            .unwrap_or_default();
+        Ok(Self::Mysql {
+            baglama,
+            is_private_column: columns > 0,
+        })
+    }
+
+    /// Condition on `groups` for groups that are shown.
+    fn public_groups_sql(is_private_column: bool) -> &'static str {
+        if is_private_column {
+            "`is_private`=0"
+        } else {
+            "1"
+        }
+    }
+
+    pub fn sqlite(path: &str) -> Result<Self> {
+        let conn = rusqlite::Connection::open_with_flags(
+            path,
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+        )?;
+        Ok(Self::Sqlite(Mutex::new(conn)))
+    }
+
+    fn sqlite_conn(
+        conn: &Mutex<rusqlite::Connection>,
+    ) -> std::sync::MutexGuard<'_, rusqlite::Connection> {
+        conn.lock().expect("API SQLite connection mutex poisoned")
+    }
+
+    pub async fn groups(&self, pagination: &Pagination) -> Result<Value> {
+        let (total, items) = match self {
+            Self::Mysql {
+                baglama,
+                is_private_column,
+            } => {
+                let public = Self::public_groups_sql(*is_private_column);
+                let mut conn = baglama.get_tooldb_conn().await?;
+                let total: usize = conn
+                    .exec_first(format!("SELECT COUNT(*) FROM `groups` WHERE {public}"), ())
+                    .await?
+                    .unwrap_or_default();
+                let sql = format!(
+                    "{} WHERE {public} ORDER BY `id` LIMIT ? OFFSET ?",
+                    RowGroup::sql_select()
+                );
+                let groups = conn
+                    .exec_iter(sql, (pagination.limit, pagination.offset))
+                    .await?
+                    .map_and_drop(from_row::<RowGroup>)
+                    .await?;
+                let items = groups
+                    .iter()
+                    .map(|g| {
+                        json!({
+                            "id": g.id(),
+                            "category": g.category(),
+                            "depth": g.depth(),
+                            "added_by": g.added_by(),
+                            "is_active": g.is_active() == 1,
+                        })
+                    })
+                    .collect();
+                (total, items)
+            }
+            Self::Sqlite(conn) => {
+                let conn = Self::sqlite_conn(conn);
+                let total: usize =
+                    conn.query_row("SELECT COUNT(*) FROM `groups`", (), |row| row.get(0))?;
+                let items = conn
+                    .prepare("SELECT id,category,depth,added_by FROM `groups` ORDER BY id LIMIT ?1 OFFSET ?2")?
+                    .query_map((pagination.limit, pagination.offset), |row| {
+                        Ok(json!({
+                            "id": row.get::<_, i64>(0)?,
+                            "category": row.get::<_, String>(1)?,
+                            "depth": row.get::<_, i64>(2)?,
+                            "added_by": row.get::<_, String>(3)?,
+                        }))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                (total, items)
+            }
+        };
+        Ok(pagination.to_json(total, items))
+    }
+
+    async fn all_group_status(&self, group_id: DbId) -> Result<Vec<RowGroupStatus>> {
+        match self {
+            Self::Mysql {
+                baglama,
+                is_private_column,
+            } => {
+                let sql = format!(
+                    "SELECT {} FROM `group_status` WHERE `group_id`=? AND `group_id` IN (SELECT `id` FROM `groups` WHERE {}) ORDER BY `year`,`month`",
+                    RowGroupStatus::sql_all(),
+                    Self::public_groups_sql(*is_private_column)
+                );
+                let rows = baglama
+                    .get_tooldb_conn()
+                    .await?
+                    .exec_iter(sql, (group_id,))
+                    .await?
+                    .map_and_drop(from_row::<RowGroupStatus>)
+                    .await?;
+                Ok(rows)
+            }
+            Self::Sqlite(conn) => {
+                let conn = Self::sqlite_conn(conn);
+                let rows = conn
+                    .prepare("SELECT id,group_id,year,month,status,total_views,usage_views,excluded_views FROM `group_status` WHERE group_id=?1 ORDER BY year,month")?
+                    .query_map((group_id,), |row| {
+                        Ok(RowGroupStatus {
+                            id: row.get(0)?,
+                            group_id: row.get(1)?,
+                            year: row.get(2)?,
+                            month: row.get(3)?,
+                            status: row.get(4)?,
+                            total_views: row.get(5)?,
+                            usage_views: row.get(6)?,
+                            excluded_views: row.get(7)?,
+                            file: None,
+                            sqlite3: None,
+                            storage: StorageType::Sqlite3,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(rows)
+            }
+        }
+    }
+
+    async fn group_status(&self, group_id: DbId, ym: &YearMonth) -> Result<Option<RowGroupStatus>> {
+        Ok(self
+            .all_group_status(group_id)
+            .await?
+            .into_iter()
+            .find(|gs| gs.year == ym.year() && gs.month == ym.month()))
+    }
+
+    pub async fn group_months(&self, group_id: DbId, pagination: &Pagination) -> Result<Value> {
+        let rows = self.all_group_status(group_id).await?;
+        let items = pagination
+            .slice(&rows)
+            .iter()
+            .map(group_status_json)
+            .collect();
+        Ok(pagination.to_json(rows.len(), items))
+    }
+
+    /// Reads all results of a group month from the SQLite stand-in.
+    fn sqlite_results(conn: &rusqlite::Connection, gs: &RowGroupStatus) -> Result<GroupResults> {
+        let mut ret = GroupResults::new(gs)?;
+        let rows = conn
+            .prepare(&format!(
+                "SELECT group2view.image,COALESCE(sites.server,''),views.namespace_id,views.title,views.views,views.page_id,{}
+                FROM group2view,views,sites
+                WHERE group2view.group_status_id=?1 AND views.id=group2view.view_id AND sites.id=views.site
+                ORDER BY group2view.id",
+                Sqlite3Reader::excluded_column(conn)
+            ))?
+            .query_map((gs.id,), |row| {
+                let views: Option<i64> = row.get(4)?;
+                let page_id: i64 = row.get(5)?;
+                let excluded: i64 = row.get(6)?;
+                Ok((
+                    row.get::<_, String>(0)?,
+                    ResultPage {
+                        server: row.get(1)?,
+                        namespace_id: row.get(2)?,
+                        title: row.get(3)?,
+                    },
+                    PageViews {
+                        views: views.and_then(|v| u64::try_from(v).ok()),
+                        page_id: page_id as DbId,
+                        excluded: excluded != 0,
+                    },
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        ret.add_usages(rows);
+        Ok(ret)
+    }
+
+    /// Top files and pages, up to `limit` each.
+    async fn report(&self, gs: &RowGroupStatus, limit: usize) -> Result<GroupReport> {
+        match self {
+            Self::Mysql { baglama, .. } => {
+                let group_id: GroupId = gs.group_id.try_into()?;
+                let ym = YearMonth::new(gs.year, gs.month)?;
+                GroupReport::generate(baglama, group_id, &ym, limit).await
+            }
+            Self::Sqlite(conn) => {
+                let results = Self::sqlite_results(&Self::sqlite_conn(conn), gs)?;
+                Ok(GroupReport::from_results(&results, limit))
+            }
+        }
+    }
+
+    pub async fn group_month(&self, group_id: DbId, ym: &YearMonth) -> Result<Option<Value>> {
+        let Some(gs) = self.group_status(group_id, ym).await? else {
+            return Ok(None);
+        };
+        let mut ret = group_status_json(&gs);
+        if gs.status == "VIEW DATA COMPLETE" {
+            let report = self.report(&gs, 0).await?;
+            let files = match self {
+                Self::Mysql { baglama, .. } => {
+                    StorageReader::new(baglama).read_counts(&gs).await?.files
+                }
+                Self::Sqlite(conn) => {
+                    Self::sqlite_results(&Self::sqlite_conn(conn), &gs)?
+                        .counts()
+                        .files
+                }
+            };
+            ret["files"] = json!(files);
+            ret["pages"] = json!(report.distinct_pages);
+            ret["wikis"] = json!(report.distinct_wikis);
+        }
+        Ok(Some(ret))
+    }
+
+    pub async fn sites(
+        &self,
+        group_id: DbId,
+        ym: &YearMonth,
+        pagination: &Pagination,
+    ) -> Result<Option<Value>> {
+        let Some(gs) = self.group_status(group_id, ym).await? else {
+            return Ok(None);
+        };
+        let rows: Vec<(String, u64, u64)> = match self {
+            // gs2site is only written for mysql2 months
+            Self::Mysql { baglama, .. } => {
+                let mut rows: Vec<_> = WikiReport::load_sites(baglama, &gs)
+                    .await?
+                    .into_iter()
+                    .map(|site| (site.server, site.pages, site.views))
+                    .collect();
+                rows.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+                rows
+            }
+            Self::Sqlite(conn) => {
+                let sql = "SELECT COALESCE(sites.server,''),gs2site.pages,gs2site.views FROM gs2site,sites WHERE gs2site.group_status_id=? AND sites.id=gs2site.site_id ORDER BY gs2site.views DESC,sites.server";
+                Self::sqlite_conn(conn)
+                    .prepare(sql)?
+                    .query_map((gs.id,), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+        };
+        let items = pagination
+            .slice(&rows)
+            .into_iter()
+            .map(|(server, pages, views)| json!({"server": server, "pages": pages, "views": views}))
+            .collect();
+        Ok(Some(pagination.to_json(rows.len(), items)))
+    }
+
+    pub async fn top_files(
+        &self,
+        group_id: DbId,
+        ym: &YearMonth,
+        pagination: &Pagination,
+    ) -> Result<Option<Value>> {
+        let Some(gs) = self.group_status(group_id, ym).await? else {
+            return Ok(None);
+        };
+        let report = self
+            .report(&gs, pagination.offset + pagination.limit)
+            .await?;
+        let items = pagination
+            .slice(&report.top_files)
+            .into_iter()
+            .map(|f| json!({"file": f.name, "views": f.views, "pages": f.pages}))
+            .collect();
+        Ok(Some(pagination.to_json(report.distinct_files, items)))
+    }
+
+    pub async fn top_pages(
+        &self,
+        group_id: DbId,
+        ym: &YearMonth,
+        pagination: &Pagination,
+    ) -> Result<Option<Value>> {
+        let Some(gs) = self.group_status(group_id, ym).await? else {
+            return Ok(None);
+        };
+        let report = self
+            .report(&gs, pagination.offset + pagination.limit)
+            .await?;
+        let items = pagination
+            .slice(&report.top_pages)
+            .into_iter()
+            .map(|p| {
+                json!({
+                    "server": p.server,
+                    "namespace_id": p.namespace_id,
+                    "title": p.title,
+                    "views": p.views,
+                    "files": p.files,
+                })
+            })
+            .collect();
+        Ok(Some(pagination.to_json(report.distinct_pages, items)))
+    }
+}
+
+type ApiState = State<Arc<ApiBackend>>;
+type ApiQuery = Query<HashMap<String, String>>;
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    (
+        status,
+        [(header::CONTENT_TYPE, "application/json")],
+        json!({"error": message}).to_string(),
+    )
+        .into_response()
+}
+
+/// Turns a result into a JSON response, with ETag handling.
+fn json_response(headers: &HeaderMap, result: Result<Option<Value>>) -> Response {
+    let value = match result {
+        Ok(Some(value)) => value,
+        Ok(None) => return error_response(StatusCode::NOT_FOUND, "Not found"),
+        Err(e) => {
+            error!("API request failed: {e}");
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error");
+        }
+    };
+    let body = value.to_string();
+    let etag = format!("\"{:x}\"", Sha256::digest(body.as_bytes()));
+    let if_none_match = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok());
+    if if_none_match == Some(etag.as_str()) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (header::ETAG, etag),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Parses pagination and the month; errors are bad requests.
+fn parse_request(
+    query: &HashMap<String, String>,
+    ym: Option<(i32, u32)>,
+) -> Result<(Pagination, Option<YearMonth>)> {
+    let pagination = Pagination::from_query(query)?;
+    let ym = match ym {
+        Some((year, month)) => Some(YearMonth::new(year, month)?),
+        None => None,
+    };
+    Ok((pagination, ym))
+}
+
+async fn get_groups(State(api): ApiState, Query(query): ApiQuery, headers: HeaderMap) -> Response {
+    match parse_request(&query, None) {
+        Ok((pagination, _)) => json_response(&headers, api.groups(&pagination).await.map(Some)),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    }
+}
+
+async fn get_group_months(
+    State(api): ApiState,
+    Path(group_id): Path<DbId>,
+    Query(query): ApiQuery,
+    headers: HeaderMap,
+) -> Response {
+    match parse_request(&query, None) {
+        Ok((pagination, _)) => json_response(
+            &headers,
+            api.group_months(group_id, &pagination).await.map(Some),
+        ),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    }
+}
+
+async fn get_group_month(
+    State(api): ApiState,
+    Path((group_id, year, month)): Path<(DbId, i32, u32)>,
+    Query(query): ApiQuery,
+    headers: HeaderMap,
+) -> Response {
+    match parse_request(&query, Some((year, month))) {
+        Ok((_, Some(ym))) => json_response(&headers, api.group_month(group_id, &ym).await),
+        Ok(_) => error_response(StatusCode::BAD_REQUEST, "Year and month expected"),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    }
+}
+
+async fn get_sites(
+    State(api): ApiState,
+    Path((group_id, year, month)): Path<(DbId, i32, u32)>,
+    Query(query): ApiQuery,
+    headers: HeaderMap,
+) -> Response {
+    match parse_request(&query, Some((year, month))) {
+        Ok((pagination, Some(ym))) => {
+            json_response(&headers, api.sites(group_id, &ym, &pagination).await)
+        }
+        Ok(_) => error_response(StatusCode::BAD_REQUEST, "Year and month expected"),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    }
+}
+
+async fn get_top_files(
+    State(api): ApiState,
+    Path((group_id, year, month)): Path<(DbId, i32, u32)>,
+    Query(query): ApiQuery,
+    headers: HeaderMap,
+) -> Response {
+    match parse_request(&query, Some((year, month))) {
+        Ok((pagination, Some(ym))) => {
+            json_response(&headers, api.top_files(group_id, &ym, &pagination).await)
+        }
+        Ok(_) => error_response(StatusCode::BAD_REQUEST, "Year and month expected"),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    }
+}
+
+async fn get_top_pages(
+    State(api): ApiState,
+    Path((group_id, year, month)): Path<(DbId, i32, u32)>,
+    Query(query): ApiQuery,
+    headers: HeaderMap,
+) -> Response {
+    match parse_request(&query, Some((year, month))) {
+        Ok((pagination, Some(ym))) => {
+            json_response(&headers, api.top_pages(group_id, &ym, &pagination).await)
+        }
+        Ok(_) => error_response(StatusCode::BAD_REQUEST, "Year and month expected"),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    }
+}
+
+pub fn router(api: Arc<ApiBackend>) -> Router {
+    let month = "/groups/{group_id}/months/{year}/{month}";
+    Router::new()
+        .route("/groups", get(get_groups))
+        .route("/groups/{group_id}/months", get(get_group_months))
+        .route(month, get(get_group_month))
+        .route(&format!("{month}/sites"), get(get_sites))
+        .route(&format!("{month}/top_files"), get(get_top_files))
+        .route(&format!("{month}/top_pages"), get(get_top_pages))
+        .with_state(api)
+}
+
+pub async fn serve(api: ApiBackend, port: u16) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
+    axum::serve(listener, router(Arc::new(api))).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    fn test_db(name: &str) -> String {
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(&std::fs::read_to_string("baglama.sqlite3_schema").unwrap())
+            .unwrap();
+        conn.execute_batch(
+            "INSERT INTO groups (id,category,depth,added_by,just_added) VALUES
+                (1,'Paintings',3,'Alice',0),(2,'Maps',1,'Bob',0);
+            INSERT INTO group_status (id,group_id,year,month,status,total_views) VALUES
+                (10,1,2024,4,'VIEW DATA COMPLETE',90),(11,1,2024,5,'VIEW DATA COMPLETE',110);
+            INSERT INTO sites (id,server) VALUES (1,'de.wikipedia.org'),(2,'en.wikipedia.org');
+            INSERT INTO views (id,site,title,month,year,done,namespace_id,page_id,views) VALUES
+                (1,1,'Berlin',5,2024,1,0,11,100),(2,2,'Berlin',5,2024,1,0,12,10);
+            INSERT INTO group2view (group_status_id,view_id,image) VALUES
+                (11,1,'A.jpg'),(11,1,'B.jpg'),(11,2,'B.jpg');
+            INSERT INTO gs2site (group_status_id,site_id,pages,views) VALUES
+                (11,1,1,100),(11,2,1,10);",
+        )
+        .unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    async fn get(
+        app: &Router,
+        uri: &str,
+        etag: Option<&str>,
+    ) -> (StatusCode, Option<String>, Value) {
+        let mut request = Request::builder().uri(uri);
+        if let Some(etag) = etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        let response = app
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .map(|v| v.to_str().unwrap().to_string());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        (status, etag, json)
+    }
+
+    #[test]
+    fn test_pagination() {
+        let query = |s: &[(&str, &str)]| {
+            s.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        let p = Pagination::from_query(&query(&[])).unwrap();
+        assert_eq!(
+            p,
+            Pagination {
+                offset: 0,
+                limit: DEFAULT_PAGE_LIMIT
+            }
+        );
+        let p = Pagination::from_query(&query(&[("offset", "5"), ("limit", "100000")])).unwrap();
+        assert_eq!(
+            p,
+            Pagination {
+                offset: 5,
+                limit: MAX_PAGE_LIMIT
+            }
+        );
+        assert!(Pagination::from_query(&query(&[("limit", "x")])).is_err());
+        assert_eq!(p.slice(&[1, 2, 3, 4, 5, 6, 7]), vec![6, 7]);
+    }
+
+    #[tokio::test]
+    async fn test_json_response_hides_errors() {
+        let response = json_response(&HeaderMap::new(), Err(anyhow!("Table 'secret' missing")));
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"], "Internal server error");
+    }
+
+    #[tokio::test]
+    async fn test_api_sqlite() {
+        let path = test_db("baglama2_test_api.sqlite3");
+        let app = router(Arc::new(ApiBackend::sqlite(&path).unwrap()));
+
+        let (status, etag, json) = get(&app, "/groups?limit=1", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["total"], 2);
+        assert_eq!(json["items"][0]["category"], "Paintings");
+
+        let (status, _, _) = get(&app, "/groups?limit=1", etag.as_deref()).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+
+        let (_, _, json) = get(&app, "/groups/1/months", None).await;
+        assert_eq!(json["total"], 2);
+        assert_eq!(json["items"][1]["month"], "2024-05");
+
+        let (_, _, json) = get(&app, "/groups/1/months/2024/5", None).await;
+        assert_eq!(json["total_views"], 110);
+        assert_eq!(json["files"], 2);
+        assert_eq!(json["pages"], 2);
+        assert_eq!(json["wikis"], 2);
+
+        let (_, _, json) = get(&app, "/groups/1/months/2024/5/sites", None).await;
+        assert_eq!(json["items"][0]["server"], "de.wikipedia.org");
+
+        let (_, _, json) = get(&app, "/groups/1/months/2024/5/top_files?limit=1", None).await;
+        assert_eq!(json["total"], 2);
+        assert_eq!(json["items"][0]["file"], "B.jpg");
+        assert_eq!(json["items"][0]["views"], 110);
+
+        let (_, _, json) = get(&app, "/groups/1/months/2024/5/top_pages?offset=1", None).await;
+        assert_eq!(json["total"], 2);
+        assert_eq!(json["items"][0]["server"], "en.wikipedia.org");
+
+        let (status, _, _) = get(&app, "/groups/2/months/2024/5", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = get(&app, "/groups/1/months/2024/13", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
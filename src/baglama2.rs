@@ -1,3 +1,5 @@
+use crate::category_tree::CategoryTree;
+use crate::group_exclusions::GroupExclusions;
 use crate::row_group::RowGroup;
 use crate::row_group_status::RowGroupStatus;
 use crate::DbId;
@@ -6,6 +8,7 @@ use crate::Site;
 use crate::YearMonth;
 use anyhow::Result;
 use core::time::Duration;
+use log::{info, warn};
 use mysql_async::{from_row, prelude::*, Conn};
 
 use serde_json::Value;
@@ -27,6 +30,7 @@ pub struct Baglama2 {
     config: Value,
     tfdb: ToolforgeDB,
     apis: Arc<Mutex<HashMap<String, Api>>>,
+    bot_apis: Arc<Mutex<HashMap<String, Api>>>,
     sites_cache: Vec<Site>,
     site_matrix: SiteMatrix,
 }
@@ -44,6 +48,7 @@ impl Baglama2 {
             config: config.clone(),
             tfdb: ToolforgeDB::default(),
             apis: Arc::new(Mutex::new(HashMap::new())),
+            bot_apis: Arc::new(Mutex::new(HashMap::new())),
             sites_cache: vec![],
             site_matrix: SiteMatrix::new(&wikidata_api).await?,
         };
@@ -74,6 +79,78 @@ impl Baglama2 {
         Some(api)
     }
 
+    /// An API for the wiki, logged in with `bot_user`/`bot_password` from the
+    /// config. Logs in once per wiki; later calls reuse the session.
+    async fn bot_api(&self, wiki: &str) -> Result<Api> {
+        let mut bot_apis = self.bot_apis.lock().await;
+        if let Some(api) = bot_apis.get(wiki) {
+            return Ok(api.clone());
+        }
+        let mut api = self
+            .add_api(wiki)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No API for {wiki}"))?;
+        let user = self.config["bot_user"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("bot_user not found in config"))?;
+        let password = self.config["bot_password"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("bot_password not found in config"))?;
+        api.login(user, password).await?;
+        bot_apis.insert(wiki.to_string(), api.clone());
+        Ok(api)
+    }
+
+    /// Saves a page on a wiki, logged in as the bot user.
+    pub async fn edit_page(
+        &self,
+        wiki: &str,
+        title: &str,
+        text: &str,
+        summary: &str,
+    ) -> Result<()> {
+        let api = self.bot_api(wiki).await?;
+        let token = api.get_edit_token().await?;
+        let params: HashMap<String, String> = [
+            ("action", "edit"),
+            ("title", title),
+            ("text", text),
+            ("summary", summary),
+            ("bot", "1"),
+            ("token", &token),
+            ("format", "json"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let result = api.post_query_api_json(&params).await?;
+        if result["edit"]["result"].as_str() != Some("Success") {
+            return Err(anyhow::anyhow!(
+                "Could not edit {title} on {wiki}: {result}"
+            ));
+        }
+        Ok(())
+    }
+
+    /// The `general` and `namespaces` site info of a wiki.
+    pub async fn site_info(&self, wiki: &str) -> Result<Value> {
+        let api = self
+            .api(wiki)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No API for {wiki}"))?;
+        let params: HashMap<String, String> = [
+            ("action", "query"),
+            ("meta", "siteinfo"),
+            ("siprop", "general|namespaces"),
+            ("format", "json"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let result = api.get_query_api_json(&params).await?;
+        Ok(result["query"].to_owned())
+    }
+
     pub fn config(&self) -> &Value {
         &self.config
     }
@@ -96,74 +173,6 @@ impl Baglama2 {
             .to_string()
     }
 
-    pub async fn deactivate_nonexistent_categories(&self) -> Result<()> {
-        let sql = format!(
-            "{} WHERE is_user_name=0 AND is_active=1",
-            RowGroup::sql_select()
-        );
-        let groups = self
-            .get_tooldb_conn()
-            .await?
-            .exec_iter(sql, ())
-            .await?
-            .map_and_drop(from_row::<RowGroup>)
-            .await?;
-        let active_categories = groups
-            .iter()
-            .map(|group| group.category().to_owned())
-            .collect::<Vec<String>>();
-        let existing_categories = self.get_existing_categories(&active_categories).await?;
-        let non_existing_categories = active_categories
-            .iter()
-            .filter(|category| !existing_categories.contains(*category))
-            .cloned()
-            .collect::<Vec<String>>();
-        let groups_to_deactivate = groups
-            .iter()
-            .filter(|group| non_existing_categories.contains(group.category()))
-            .map(|group| group.id())
-            .collect::<Vec<DbId>>();
-        if groups_to_deactivate.is_empty() {
-            return Ok(());
-        }
-        self.deactivate_groups(&groups_to_deactivate).await?;
-        Ok(())
-    }
-
-    async fn deactivate_groups(&self, group_ids: &[DbId]) -> Result<()> {
-        let placeholders = Baglama2::sql_placeholders(group_ids.len());
-        let sql = format!("UPDATE `groups` SET is_active=0 WHERE id IN ({placeholders})");
-        self.get_tooldb_conn()
-            .await?
-            .exec_drop(sql, group_ids.to_owned())
-            .await?;
-        Ok(())
-    }
-
-    async fn get_existing_categories(&self, categories: &[String]) -> Result<Vec<String>> {
-        if categories.is_empty() {
-            return Ok(vec![]);
-        }
-        let categories = categories
-            .iter()
-            .map(|category| category.replace(" ", "_"))
-            .collect::<Vec<String>>();
-        let placeholders = Baglama2::sql_placeholders(categories.len());
-        let sql = format!("SELECT `page_title` FROM `page` WHERE `page_namespace`=14 AND `page_title` IN ({placeholders})");
-        let results = self
-            .get_commons_conn()
-            .await?
-            .exec_iter(sql, categories.to_owned())
-            .await?
-            .map_and_drop(from_row::<String>)
-            .await?;
-        let results = results
-            .iter()
-            .map(|category| category.replace("_", " "))
-            .collect::<Vec<String>>();
-        Ok(results)
-    }
-
     pub fn get_config_from_file(filename: &str) -> Result<serde_json::Value> {
         let path = if filename.starts_with('/') {
             Path::new(filename).to_path_buf()
@@ -314,17 +323,17 @@ impl Baglama2 {
         placeholders
     }
 
-    async fn query_commons_repeat(
+    /// Runs a query against the Commons replica, retrying up to five times.
+    async fn query_commons_repeat<T>(
         &self,
         sql: &str,
         remaining_queries: &[String],
-    ) -> Result<Vec<String>> {
+    ) -> Result<Vec<T>>
+    where
+        T: FromRow + Send + 'static,
+    {
         let mut attempts_left = 5;
-        let mut ret = vec![];
         loop {
-            if attempts_left == 0 {
-                break;
-            }
             attempts_left -= 1;
             let mut conn = match self.get_commons_conn().await {
                 Ok(conn) => conn,
@@ -347,8 +356,8 @@ impl Baglama2 {
                     }
                 }
             };
-            ret = match result.map_and_drop(from_row::<String>).await {
-                Ok(ret) => ret,
+            match result.map_and_drop(from_row::<T>).await {
+                Ok(ret) => return Ok(ret),
                 Err(e) => {
                     if attempts_left == 0 {
                         return Err(e.into());
@@ -359,51 +368,66 @@ impl Baglama2 {
                 }
             }
         }
-        Ok(ret)
     }
 
-    // TESTED
-    async fn find_subcats(&self, root: &[String], depth: isize) -> Result<Vec<String>> {
-        let mut depth = depth;
-        let mut check = root.to_owned();
-        // Use a HashSet for O(1) membership tests; a Vec would give O(n) per check,
-        // leading to O(n²) behaviour over deep category trees.
-        let mut subcats: HashSet<String> = HashSet::new();
-        loop {
-            if depth == 0 {
-                break;
-            }
-            // Keep only categories we haven't visited yet.
-            let remaining: Vec<String> = check
-                .into_iter()
-                .filter(|category| !subcats.contains(category))
-                .collect();
-            if remaining.is_empty() {
-                break;
+    /// Walks the category tree breadth-first from `root` (see `CategoryTree::walk`),
+    /// skipping excluded categories and their subtrees.
+    pub async fn get_category_tree(
+        &self,
+        root: &[String],
+        depth: isize,
+        exclusions: &GroupExclusions,
+    ) -> Result<CategoryTree> {
+        CategoryTree::walk(root, depth, exclusions, |check| async move {
+            let mut pairs = vec![];
+            for cats in check.chunks(1000) {
+                let placeholders = Baglama2::sql_placeholders(cats.len());
+                let sql = format!(
+                    "SELECT DISTINCT FROM_BASE64(TO_BASE64(lt_title)),FROM_BASE64(TO_BASE64(page_title))
+                    FROM page,categorylinks,linktarget
+                    WHERE page_id=cl_from
+                    AND cl_target_id=lt_id AND lt_namespace=14
+                    AND lt_title IN ({placeholders})
+                    AND cl_type='subcat'"
+                );
+                let mut result = self
+                    .query_commons_repeat::<(String, String)>(&sql, cats)
+                    .await?;
+                pairs.append(&mut result);
             }
-            subcats.extend(remaining.iter().cloned());
-            let placeholders = Baglama2::sql_placeholders(remaining.len());
+            Ok(pairs)
+        })
+        .await
+    }
+
+    /// Sets the number of files in each category of the tree, from the Commons `category` table.
+    pub async fn add_file_counts(&self, tree: &mut CategoryTree) -> Result<()> {
+        let mut counts = HashMap::new();
+        for cats in tree.categories().chunks(1000) {
+            let placeholders = Baglama2::sql_placeholders(cats.len());
             let sql = format!(
-                "SELECT DISTINCT FROM_BASE64(TO_BASE64(page_title))
-	            FROM page,categorylinks,linktarget
-	            WHERE page_id=cl_from
-	            AND cl_target_id=lt_id AND lt_namespace=14
-	            AND lt_title IN ({})
-	            AND cl_type='subcat'",
-                placeholders
+                "SELECT FROM_BASE64(TO_BASE64(cat_title)),cat_files FROM category WHERE cat_title IN ({placeholders})"
             );
-            check = self.query_commons_repeat(&sql, &remaining).await?;
-            if check.is_empty() {
-                break;
-            }
-            subcats.extend(check.iter().cloned());
-            depth -= 1;
+            let result = self
+                .query_commons_repeat::<(String, usize)>(&sql, cats)
+                .await?;
+            counts.extend(result);
         }
-        // Convert to a sorted Vec to match the previous behaviour (callers rely on
-        // the result being usable as SQL IN-list parameters).
-        let mut result: Vec<String> = subcats.into_iter().collect();
-        result.sort();
-        Ok(result)
+        tree.set_file_counts(&counts);
+        Ok(())
+    }
+
+    // TESTED
+    async fn find_subcats(
+        &self,
+        root: &[String],
+        depth: isize,
+        exclusions: &GroupExclusions,
+    ) -> Result<Vec<String>> {
+        Ok(self
+            .get_category_tree(root, depth, exclusions)
+            .await?
+            .categories())
     }
 
     // TESTED
@@ -412,14 +436,25 @@ impl Baglama2 {
         category: &str,
         depth: isize,
         namespace: isize,
+        exclusions: &GroupExclusions,
     ) -> Result<Vec<String>> {
         let category = category.replace(" ", "_");
         let categories = self
-            .find_subcats(std::slice::from_ref(&category), depth)
+            .find_subcats(std::slice::from_ref(&category), depth, exclusions)
             .await?;
         if namespace == 14 {
             return Ok(categories);
         }
+        let pages = self.get_pages_in_categories(&categories, namespace).await?;
+        Ok(exclusions.filter_files(pages))
+    }
+
+    /// Returns the (non-redirect) pages in `namespace` that are directly in any of `categories`.
+    pub async fn get_pages_in_categories(
+        &self,
+        categories: &[String],
+        namespace: isize,
+    ) -> Result<Vec<String>> {
         let mut ret = vec![];
         for cats in categories.chunks(1000) {
             let placeholders = Baglama2::sql_placeholders(cats.len());
@@ -432,7 +467,7 @@ impl Baglama2 {
                 AND page_is_redirect=0",
                 placeholders
             );
-            let mut result = self.query_commons_repeat(&sql, cats).await?;
+            let mut result = self.query_commons_repeat::<String>(&sql, cats).await?;
             ret.append(&mut result);
         }
         ret.sort();
@@ -452,6 +487,149 @@ impl Baglama2 {
         Ok(results)
     }
 
+    /// Gets all images uploaded by a user, by user ID
+    pub async fn get_files_from_user_id(&self, user_id: DbId) -> Result<Vec<String>> {
+        let sql = "SELECT DISTINCT FROM_BASE64(TO_BASE64(img_name)) FROM image,actor WHERE img_actor=actor_id AND actor_user=?";
+        let results = self
+            .get_commons_conn()
+            .await?
+            .exec_iter(sql, (user_id,))
+            .await?
+            .map_and_drop(from_row::<String>)
+            .await?;
+        Ok(results)
+    }
+
+    /// Gets all images uploaded by the user of a group. The user ID is stored
+    /// in `group_users` the first time, so the group follows user renames.
+    pub async fn get_files_from_group_user(
+        &self,
+        group_id: DbId,
+        user_name: &str,
+    ) -> Result<Vec<String>> {
+        match self.get_user_id_for_group(group_id, user_name).await? {
+            Some(user_id) => self.get_files_from_user_id(user_id).await,
+            None => {
+                warn!("Group {group_id}: no user ID for user '{user_name}'");
+                self.get_files_from_user_name(user_name).await
+            }
+        }
+    }
+
+    /// The user IDs of user groups, by name. Called from `DbMySql2::ensure_table_exists`.
+    pub async fn ensure_group_users_table_exists(&self) -> Result<()> {
+        let sql = "CREATE TABLE IF NOT EXISTS `group_users` (
+              `id` int(11) unsigned NOT NULL AUTO_INCREMENT,
+              `group_id` int(11) unsigned NOT NULL,
+              `user_name` varbinary(255) NOT NULL,
+              `user_id` int(11) unsigned NOT NULL,
+              PRIMARY KEY (`id`),
+              UNIQUE KEY `group_user` (`group_id`,`user_name`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4";
+        self.get_tooldb_conn().await?.exec_drop(sql, ()).await?;
+        Ok(())
+    }
+
+    async fn get_user_id_for_group(&self, group_id: DbId, user_name: &str) -> Result<Option<DbId>> {
+        let user_name = user_name.trim().replace('_', " ");
+        let mut conn = self.get_tooldb_conn().await?;
+        let sql = "SELECT `user_id` FROM `group_users` WHERE `group_id`=? AND `user_name`=?";
+        let stored = conn
+            .exec_iter(sql, (group_id, &user_name))
+            .await?
+            .map_and_drop(from_row::<DbId>)
+            .await?;
+        if let Some(user_id) = stored.first() {
+            let sql = "SELECT FROM_BASE64(TO_BASE64(user_name)) FROM user WHERE user_id=?";
+            let current_names = self
+                .get_commons_conn()
+                .await?
+                .exec_iter(sql, (*user_id,))
+                .await?
+                .map_and_drop(from_row::<String>)
+                .await?;
+            if let Some(current_name) = current_names.first() {
+                if *current_name != user_name {
+                    info!("Group {group_id}: user '{user_name}' was renamed to '{current_name}'");
+                }
+            }
+            return Ok(Some(*user_id));
+        }
+        let user_ids = self
+            .query_commons_repeat::<DbId>(
+                "SELECT user_id FROM user WHERE user_name=?",
+                std::slice::from_ref(&user_name),
+            )
+            .await?;
+        let user_id = match user_ids.first() {
+            Some(user_id) => *user_id,
+            None => return Ok(None),
+        };
+        let sql =
+            "INSERT IGNORE INTO `group_users` (`group_id`,`user_name`,`user_id`) VALUES (?,?,?)";
+        conn.exec_drop(sql, (group_id, &user_name, user_id)).await?;
+        Ok(Some(user_id))
+    }
+
+    /// Returns the Commons page IDs of files.
+    pub async fn get_file_page_ids(&self, files: &[String]) -> Result<HashMap<String, DbId>> {
+        let mut ret = HashMap::new();
+        for chunk in files.chunks(1000) {
+            let placeholders = Baglama2::sql_placeholders(chunk.len());
+            let sql = format!("SELECT FROM_BASE64(TO_BASE64(page_title)),page_id FROM page WHERE page_namespace=6 AND page_title IN ({placeholders})");
+            let rows = self
+                .query_commons_repeat::<(String, DbId)>(&sql, chunk)
+                .await?;
+            ret.extend(rows);
+        }
+        Ok(ret)
+    }
+
+    /// Returns all file redirects pointing to the given files, as a map from
+    /// redirect to target.
+    pub async fn get_file_redirects(&self, files: &[String]) -> Result<HashMap<String, String>> {
+        let mut ret = HashMap::new();
+        for chunk in files.chunks(1000) {
+            let placeholders = Baglama2::sql_placeholders(chunk.len());
+            let sql = format!(
+                "SELECT FROM_BASE64(TO_BASE64(page_title)),FROM_BASE64(TO_BASE64(rd_title))
+                FROM page,redirect
+                WHERE rd_from=page_id AND page_namespace=6 AND rd_namespace=6
+                AND rd_title IN ({placeholders})"
+            );
+            let rows = self
+                .query_commons_repeat::<(String, String)>(&sql, chunk)
+                .await?;
+            ret.extend(rows);
+        }
+        Ok(ret)
+    }
+
+    /// Replaces files that are redirects (usually after a move) with their
+    /// targets, and removes duplicates.
+    pub async fn resolve_file_redirects(&self, files: Vec<String>) -> Result<Vec<String>> {
+        let mut targets = HashMap::new();
+        for chunk in files.chunks(1000) {
+            let placeholders = Baglama2::sql_placeholders(chunk.len());
+            let sql = format!(
+                "SELECT FROM_BASE64(TO_BASE64(page_title)),FROM_BASE64(TO_BASE64(rd_title))
+                FROM page,redirect
+                WHERE rd_from=page_id AND page_namespace=6 AND rd_namespace=6
+                AND page_title IN ({placeholders})"
+            );
+            let rows = self
+                .query_commons_repeat::<(String, String)>(&sql, chunk)
+                .await?;
+            targets.extend(rows);
+        }
+        let mut seen = HashSet::new();
+        Ok(files
+            .into_iter()
+            .map(|file| targets.get(&file).cloned().unwrap_or(file))
+            .filter(|file| seen.insert(file.to_owned()))
+            .collect())
+    }
+
     // TESTED
     pub async fn get_next_group_id(
         &self,
@@ -645,7 +823,7 @@ mod tests {
     async fn test_get_pages_in_category() {
         let baglama = Baglama2::new().await.unwrap();
         let images = baglama
-            .get_pages_in_category("Blue sky in Berlin", 3, 6)
+            .get_pages_in_category("Blue sky in Berlin", 3, 6, &GroupExclusions::default())
             .await
             .unwrap();
         assert!(images.contains(&"2013-06-07_Kindergartenfest_Berlin-Karow_03.jpg".to_string()));
@@ -683,6 +861,8 @@ mod tests {
             month: 10,
             status: "VIEW DATA COMPLETE".to_string(),
             total_views: Some(2062290),
+            usage_views: None,
+            excluded_views: None,
             file: None,
             sqlite3: Some("/data/project/glamtools/viewdata/202210/782.sqlite3".to_string()),
             storage: StorageType::Sqlite3,
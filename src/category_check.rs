@@ -0,0 +1,515 @@
+//! Checks that the root categories of active category groups still exist on
+//! Commons, and deactivates (or re-points) the groups whose categories do not.
+//!
+//! Nothing is changed silently: every decision is written to the
+//! `group_deactivation_log` table, and a dry run only reports what would happen.
+
+use crate::group_source::GroupSource;
+use crate::row_group::RowGroup;
+use crate::{Baglama2, DbId};
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use mysql_async::{from_row, prelude::*};
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+/// Number of category titles per Commons query.
+const CATEGORY_CHUNK_SIZE: usize = 1000;
+
+/// Default for the `max_deactivation_ratio` config key. If more than this
+/// fraction of active categories appear to be missing, something is most
+/// likely wrong with the replica, and nothing is deactivated without `--force`.
+const DEFAULT_MAX_DEACTIVATION_RATIO: f64 = 0.05;
+
+static MOVE_TARGET_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#""4::target";s:\d+:"(.*?)";"#).expect("Bad move target regex"));
+
+/// What happened to a group's root category on Commons.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CategoryState {
+    /// The category page exists and is not a redirect.
+    Exists,
+    /// The category was moved, or is a hard redirect, to the given category.
+    Moved(String),
+    /// The category page carries `{{Category redirect}}`; the target is only
+    /// known if it could be found in the move log.
+    SoftRedirect(Option<String>),
+    /// The category page does not exist, and no move was logged.
+    Missing,
+}
+
+impl CategoryState {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            CategoryState::Exists => "exists",
+            CategoryState::Moved(_) => "moved",
+            CategoryState::SoftRedirect(_) => "soft_redirect",
+            CategoryState::Missing => "missing",
+        }
+    }
+
+    pub fn new_category(&self) -> Option<&String> {
+        match self {
+            CategoryState::Moved(target) => Some(target),
+            CategoryState::SoftRedirect(target) => target.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// What was (or, in a dry run, would have been) done to a group.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CategoryAction {
+    Deactivated,
+    Updated,
+    Reported,
+}
+
+impl CategoryAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CategoryAction::Deactivated => "deactivated",
+            CategoryAction::Updated => "updated",
+            CategoryAction::Reported => "reported",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CategoryCheckOptions {
+    /// Only report, do not change or log anything.
+    pub dry_run: bool,
+    /// Point groups with a moved/redirected category at the new category.
+    pub update_moved: bool,
+    /// Deactivate even if an implausibly large number of categories is missing.
+    pub force: bool,
+}
+
+impl CategoryCheckOptions {
+    pub fn from_args(args: &[String]) -> Self {
+        let has = |flag: &str| args.iter().any(|arg| arg == flag);
+        Self {
+            dry_run: has("--dry-run"),
+            update_moved: has("--update-moved"),
+            force: has("--force"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CategoryCheckResult {
+    pub group_id: DbId,
+    pub category: String,
+    pub state: CategoryState,
+    pub action: CategoryAction,
+}
+
+#[derive(Debug)]
+pub struct CategoryCheck<'a> {
+    baglama: &'a Baglama2,
+    options: CategoryCheckOptions,
+}
+
+impl<'a> CategoryCheck<'a> {
+    pub fn new(baglama: &'a Baglama2, options: CategoryCheckOptions) -> Self {
+        Self { baglama, options }
+    }
+
+    /// Checks all active category groups, and returns the groups whose
+    /// category is not (or no longer) a plain existing category. Groups with
+    /// entries in `group_sources` do not use `groups.category`, and are skipped.
+    pub async fn run(&self) -> Result<Vec<CategoryCheckResult>> {
+        let sql = format!(
+            "{} WHERE is_user_name=0 AND is_active=1",
+            RowGroup::sql_select()
+        );
+        let groups = self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, ())
+            .await?
+            .map_and_drop(from_row::<RowGroup>)
+            .await?;
+        let with_sources = GroupSource::groups_with_sources(self.baglama).await?;
+        let (groups, skipped): (Vec<RowGroup>, Vec<RowGroup>) = groups
+            .into_iter()
+            .partition(|group| !with_sources.contains(&group.id()));
+        if !skipped.is_empty() {
+            info!(
+                "Skipping {} group(s) with entries in group_sources",
+                skipped.len()
+            );
+        }
+        let mut categories: Vec<String> = groups
+            .iter()
+            .map(|group| Self::db_title(group.category()))
+            .collect();
+        categories.sort();
+        categories.dedup();
+
+        let states = self.get_category_states(&categories).await?;
+        let missing = states
+            .values()
+            .filter(|state| **state == CategoryState::Missing)
+            .count();
+        let max_ratio = self.baglama.config()["max_deactivation_ratio"]
+            .as_f64()
+            .unwrap_or(DEFAULT_MAX_DEACTIVATION_RATIO);
+        if !self.options.force {
+            if let Err(e) = Self::check_plausibility(categories.len(), missing, max_ratio) {
+                if !self.options.dry_run {
+                    return Err(e);
+                }
+                warn!("DRY RUN: {e}");
+            }
+        }
+
+        let results: Vec<CategoryCheckResult> = groups
+            .iter()
+            .filter_map(|group| {
+                let state = states
+                    .get(&Self::db_title(group.category()))
+                    .cloned()
+                    .unwrap_or(CategoryState::Missing);
+                let action = self.decide_action(&state)?;
+                Some(CategoryCheckResult {
+                    group_id: group.id(),
+                    category: group.category().to_owned(),
+                    state,
+                    action,
+                })
+            })
+            .collect();
+
+        for result in &results {
+            info!(
+                "{}group {} [{}]: {} => {}{}",
+                if self.options.dry_run {
+                    "DRY RUN: "
+                } else {
+                    ""
+                },
+                result.group_id,
+                result.category,
+                result.state.reason(),
+                result.action.as_str(),
+                result
+                    .state
+                    .new_category()
+                    .map(|c| format!(" (now: {c})"))
+                    .unwrap_or_default()
+            );
+        }
+        if !self.options.dry_run {
+            self.apply(&results).await?;
+        }
+        Ok(results)
+    }
+
+    fn decide_action(&self, state: &CategoryState) -> Option<CategoryAction> {
+        match state {
+            CategoryState::Exists => None,
+            CategoryState::Missing => Some(CategoryAction::Deactivated),
+            CategoryState::Moved(_) | CategoryState::SoftRedirect(Some(_)) => {
+                if self.options.update_moved {
+                    Some(CategoryAction::Updated)
+                } else {
+                    Some(CategoryAction::Reported)
+                }
+            }
+            CategoryState::SoftRedirect(None) => Some(CategoryAction::Reported),
+        }
+    }
+
+    /// Refuses to go on if no category was found at all, or if too many are
+    /// missing; both usually mean a replica outage rather than mass deletion.
+    fn check_plausibility(active: usize, missing: usize, max_ratio: f64) -> Result<()> {
+        if active == 0 || missing == 0 {
+            return Ok(());
+        }
+        if missing == active {
+            return Err(anyhow!(
+                "None of the {active} active categories were found on Commons; replica outage?"
+            ));
+        }
+        let ratio = missing as f64 / active as f64;
+        if ratio > max_ratio {
+            return Err(anyhow!(
+                "{missing} of {active} active categories appear to be missing ({:.1}% > {:.1}%); use --force to deactivate anyway",
+                ratio * 100.0,
+                max_ratio * 100.0
+            ));
+        }
+        Ok(())
+    }
+
+    async fn apply(&self, results: &[CategoryCheckResult]) -> Result<()> {
+        if results.is_empty() {
+            return Ok(());
+        }
+        self.ensure_log_table_exists().await?;
+        let mut conn = self.baglama.get_tooldb_conn().await?;
+        for result in results {
+            match (result.action, result.state.new_category()) {
+                (CategoryAction::Deactivated, _) => {
+                    conn.exec_drop(
+                        "UPDATE `groups` SET is_active=0 WHERE id=?",
+                        (result.group_id,),
+                    )
+                    .await?;
+                }
+                (CategoryAction::Updated, Some(new_category)) => {
+                    conn.exec_drop(
+                        "UPDATE `groups` SET category=? WHERE id=?",
+                        (new_category, result.group_id),
+                    )
+                    .await?;
+                }
+                _ => {}
+            }
+            conn.exec_drop(
+                "INSERT INTO `group_deactivation_log` (`group_id`,`category`,`reason`,`new_category`,`action`) VALUES (?,?,?,?,?)",
+                (
+                    result.group_id,
+                    &result.category,
+                    result.state.reason(),
+                    result.state.new_category(),
+                    result.action.as_str(),
+                ),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn ensure_log_table_exists(&self) -> Result<()> {
+        let sql = "CREATE TABLE IF NOT EXISTS `group_deactivation_log` (
+              `id` int(11) unsigned NOT NULL AUTO_INCREMENT,
+              `group_id` int(11) unsigned NOT NULL,
+              `category` varbinary(255) NOT NULL,
+              `reason` varchar(32) NOT NULL,
+              `new_category` varbinary(255) DEFAULT NULL,
+              `action` varchar(32) NOT NULL,
+              `timestamp` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+              PRIMARY KEY (`id`),
+              KEY `group_id` (`group_id`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4";
+        self.baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_drop(sql, ())
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the state of every category (as DB title, with underscores).
+    async fn get_category_states(
+        &self,
+        categories: &[String],
+    ) -> Result<HashMap<String, CategoryState>> {
+        let mut states = HashMap::new();
+        for chunk in categories.chunks(CATEGORY_CHUNK_SIZE) {
+            let pages = self.get_category_pages(chunk).await?;
+            let redirects = self.get_hard_redirect_targets(chunk).await?;
+            let soft_redirects = self.get_soft_redirects(chunk).await?;
+            let to_check: Vec<String> = chunk
+                .iter()
+                .filter(|c| pages.get(*c) != Some(&false) || soft_redirects.contains(*c))
+                .cloned()
+                .collect();
+            let moves = self.get_move_targets(&to_check).await?;
+            for category in chunk {
+                let state = match pages.get(category) {
+                    Some(true) => match redirects
+                        .get(category)
+                        .map(|target| Self::display_title(target))
+                        .or_else(|| moves.get(category).cloned())
+                    {
+                        Some(target) => CategoryState::Moved(target),
+                        // A redirect without a known target still is no reason to deactivate
+                        None => CategoryState::SoftRedirect(None),
+                    },
+                    Some(false) if soft_redirects.contains(category) => {
+                        CategoryState::SoftRedirect(moves.get(category).cloned())
+                    }
+                    Some(false) => CategoryState::Exists,
+                    None => match moves.get(category) {
+                        Some(target) => CategoryState::Moved(target.to_owned()),
+                        None => CategoryState::Missing,
+                    },
+                };
+                states.insert(category.to_owned(), state);
+            }
+        }
+        Ok(states)
+    }
+
+    /// Returns category title => is_redirect for existing category pages.
+    async fn get_category_pages(&self, categories: &[String]) -> Result<HashMap<String, bool>> {
+        let placeholders = Baglama2::sql_placeholders(categories.len());
+        let sql = format!("SELECT FROM_BASE64(TO_BASE64(page_title)),page_is_redirect FROM `page` WHERE `page_namespace`=14 AND `page_title` IN ({placeholders})");
+        let rows = self
+            .baglama
+            .get_commons_conn()
+            .await?
+            .exec_iter(sql, categories.to_owned())
+            .await?
+            .map_and_drop(from_row::<(String, u8)>)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(title, is_redirect)| (title, is_redirect == 1))
+            .collect())
+    }
+
+    async fn get_hard_redirect_targets(
+        &self,
+        categories: &[String],
+    ) -> Result<HashMap<String, String>> {
+        let placeholders = Baglama2::sql_placeholders(categories.len());
+        let sql = format!(
+            "SELECT FROM_BASE64(TO_BASE64(page_title)),FROM_BASE64(TO_BASE64(rd_title))
+            FROM `page`,`redirect`
+            WHERE rd_from=page_id AND rd_namespace=14
+            AND `page_namespace`=14 AND `page_title` IN ({placeholders})"
+        );
+        let rows = self
+            .baglama
+            .get_commons_conn()
+            .await?
+            .exec_iter(sql, categories.to_owned())
+            .await?
+            .map_and_drop(from_row::<(String, String)>)
+            .await?;
+        Ok(rows.into_iter().collect())
+    }
+
+    /// Returns the categories that use `{{Category redirect}}`.
+    async fn get_soft_redirects(&self, categories: &[String]) -> Result<Vec<String>> {
+        let placeholders = Baglama2::sql_placeholders(categories.len());
+        let sql = format!(
+            "SELECT DISTINCT FROM_BASE64(TO_BASE64(page_title))
+            FROM `page`,`templatelinks`,`linktarget`
+            WHERE tl_from=page_id AND tl_target_id=lt_id
+            AND lt_namespace=10 AND lt_title='Category_redirect'
+            AND `page_namespace`=14 AND `page_title` IN ({placeholders})"
+        );
+        let rows = self
+            .baglama
+            .get_commons_conn()
+            .await?
+            .exec_iter(sql, categories.to_owned())
+            .await?
+            .map_and_drop(from_row::<String>)
+            .await?;
+        Ok(rows)
+    }
+
+    /// Returns old category title => most recent move target, from the move log.
+    async fn get_move_targets(&self, categories: &[String]) -> Result<HashMap<String, String>> {
+        if categories.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let placeholders = Baglama2::sql_placeholders(categories.len());
+        let sql = format!(
+            "SELECT FROM_BASE64(TO_BASE64(log_title)),FROM_BASE64(TO_BASE64(log_params))
+            FROM `logging_logindex`
+            WHERE log_type='move' AND log_namespace=14 AND log_title IN ({placeholders})
+            ORDER BY log_timestamp"
+        );
+        let rows = self
+            .baglama
+            .get_commons_conn()
+            .await?
+            .exec_iter(sql, categories.to_owned())
+            .await?
+            .map_and_drop(from_row::<(String, String)>)
+            .await?;
+        // Later moves overwrite earlier ones.
+        let mut ret = HashMap::new();
+        for (title, params) in rows {
+            match Self::parse_move_target(&params) {
+                Some(target) => {
+                    ret.insert(title, target);
+                }
+                None => warn!("Could not parse move log params for {title}: {params}"),
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Extracts the target category (without namespace prefix, with spaces)
+    /// from serialized move log parameters.
+    fn parse_move_target(params: &str) -> Option<String> {
+        let target = MOVE_TARGET_RE.captures(params)?.get(1)?.as_str();
+        let target = target.strip_prefix("Category:")?;
+        Some(Self::display_title(target))
+    }
+
+    fn db_title(category: &str) -> String {
+        category.trim().replace(' ', "_")
+    }
+
+    fn display_title(category: &str) -> String {
+        category.replace('_', " ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_move_target() {
+        let params =
+            r#"a:2:{s:9:"4::target";s:21:"Category:New_name foo";s:10:"5::noredir";s:1:"0";}"#;
+        assert_eq!(
+            CategoryCheck::parse_move_target(params),
+            Some("New name foo".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_move_target_other_namespace() {
+        let params = r#"a:2:{s:9:"4::target";s:8:"User:Foo";s:10:"5::noredir";s:1:"0";}"#;
+        assert_eq!(CategoryCheck::parse_move_target(params), None);
+        assert_eq!(CategoryCheck::parse_move_target("garbage"), None);
+    }
+
+    #[test]
+    fn test_check_plausibility() {
+        assert!(CategoryCheck::check_plausibility(100, 0, 0.05).is_ok());
+        assert!(CategoryCheck::check_plausibility(100, 5, 0.05).is_ok());
+        assert!(CategoryCheck::check_plausibility(100, 6, 0.05).is_err());
+        // Nothing found at all is always suspicious
+        assert!(CategoryCheck::check_plausibility(1, 1, 1.0).is_err());
+        assert!(CategoryCheck::check_plausibility(0, 0, 0.05).is_ok());
+    }
+
+    #[test]
+    fn test_options_from_args() {
+        let args = vec![
+            "baglama2".to_string(),
+            "check_categories".to_string(),
+            "--dry-run".to_string(),
+            "--update-moved".to_string(),
+        ];
+        let options = CategoryCheckOptions::from_args(&args);
+        assert!(options.dry_run);
+        assert!(options.update_moved);
+        assert!(!options.force);
+    }
+
+    #[test]
+    fn test_category_state_new_category() {
+        assert_eq!(
+            CategoryState::Moved("Foo".to_string()).new_category(),
+            Some(&"Foo".to_string())
+        );
+        assert_eq!(CategoryState::SoftRedirect(None).new_category(), None);
+        assert_eq!(CategoryState::Missing.new_category(), None);
+        assert_eq!(CategoryState::Missing.reason(), "missing");
+    }
+}
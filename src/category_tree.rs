@@ -0,0 +1,536 @@
+//! The category tree of a category group, as found by a breadth-first walk
+//! from the root category.
+//!
+//! Besides the flat list of categories used to collect files, the tree keeps
+//! the parent→child edges, the depth at which each category was first reached,
+//! the edges that close a cycle, and (optionally) the number of files in each
+//! category. Trees are stored as JSON per group and month, so a month can be
+//! diffed against the previous one to spot categories that suddenly pull in
+//! unrelated content.
+
+use crate::group_exclusions::GroupExclusions;
+use crate::{Baglama2, DbId, YearMonth};
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::future::Future;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CategoryNode {
+    /// Depth at which the category was first reached; roots have depth 0.
+    pub depth: usize,
+    /// The category through which it was first reached; `None` for roots.
+    pub parent: Option<String>,
+    pub file_count: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CategoryTree {
+    max_depth: isize,
+    nodes: BTreeMap<String, CategoryNode>,
+    edges: BTreeSet<(String, String)>,
+    cycles: BTreeSet<(String, String)>,
+    excluded: BTreeSet<String>,
+}
+
+impl CategoryTree {
+    /// Creates a tree containing only the roots. As in the flat category list
+    /// this replaces, a `max_depth` of 0 (or less) yields an empty tree.
+    pub fn new(roots: &[String], max_depth: isize) -> Self {
+        let mut ret = Self {
+            max_depth,
+            ..Default::default()
+        };
+        if max_depth > 0 {
+            for root in roots {
+                ret.nodes.entry(root.to_owned()).or_insert(CategoryNode {
+                    depth: 0,
+                    parent: None,
+                    file_count: None,
+                });
+            }
+        }
+        ret
+    }
+
+    /// Walks the tree breadth-first from `roots`, down to `max_depth` levels.
+    /// `subcats` returns the (parent, child) pairs of subcategories below the
+    /// given categories.
+    pub async fn walk<F, Fut>(
+        roots: &[String],
+        max_depth: isize,
+        exclusions: &GroupExclusions,
+        mut subcats: F,
+    ) -> Result<Self>
+    where
+        F: FnMut(Vec<String>) -> Fut,
+        Fut: Future<Output = Result<Vec<(String, String)>>>,
+    {
+        let mut tree = Self::new(roots, max_depth);
+        let mut check = tree.roots();
+        let mut level = 0;
+        while (level as isize) < max_depth && !check.is_empty() {
+            let pairs = subcats(check).await?;
+            check = tree.add_level(level, pairs, exclusions);
+            level += 1;
+        }
+        Ok(tree)
+    }
+
+    pub fn roots(&self) -> Vec<String> {
+        self.nodes
+            .iter()
+            .filter(|(_, node)| node.depth == 0)
+            .map(|(title, _)| title.to_owned())
+            .collect()
+    }
+
+    pub fn max_depth(&self) -> isize {
+        self.max_depth
+    }
+
+    pub fn nodes(&self) -> &BTreeMap<String, CategoryNode> {
+        &self.nodes
+    }
+
+    pub fn edges(&self) -> &BTreeSet<(String, String)> {
+        &self.edges
+    }
+
+    pub fn cycles(&self) -> &BTreeSet<(String, String)> {
+        &self.cycles
+    }
+
+    /// Categories that were reached, but skipped because of group exclusion rules.
+    pub fn excluded(&self) -> &BTreeSet<String> {
+        &self.excluded
+    }
+
+    /// All categories in the tree, sorted.
+    pub fn categories(&self) -> Vec<String> {
+        self.nodes.keys().cloned().collect()
+    }
+
+    /// Adds the (parent, child) pairs found for the categories at `depth`.
+    /// Excluded children are not added, so their subtrees are never walked.
+    /// Returns the newly reached categories, which form the next frontier.
+    pub fn add_level(
+        &mut self,
+        depth: usize,
+        pairs: Vec<(String, String)>,
+        exclusions: &GroupExclusions,
+    ) -> Vec<String> {
+        let mut frontier = vec![];
+        for (parent, child) in pairs {
+            if exclusions.excludes_category(&child) {
+                self.excluded.insert(child);
+                continue;
+            }
+            if !self.nodes.contains_key(&child) {
+                self.nodes.insert(
+                    child.to_owned(),
+                    CategoryNode {
+                        depth: depth + 1,
+                        parent: Some(parent.to_owned()),
+                        file_count: None,
+                    },
+                );
+                frontier.push(child.to_owned());
+            } else if self.is_ancestor_or_self(&child, &parent) {
+                self.cycles.insert((parent.to_owned(), child.to_owned()));
+            }
+            self.edges.insert((parent, child));
+        }
+        frontier.sort();
+        frontier
+    }
+
+    /// Whether `candidate` is on the first-reached path from a root to `category`.
+    fn is_ancestor_or_self(&self, candidate: &str, category: &str) -> bool {
+        let mut current = Some(category.to_string());
+        while let Some(title) = current {
+            if title == candidate {
+                return true;
+            }
+            current = self
+                .nodes
+                .get(&title)
+                .and_then(|node| node.parent.to_owned());
+        }
+        false
+    }
+
+    pub fn set_file_counts(&mut self, counts: &HashMap<String, usize>) {
+        for (title, node) in self.nodes.iter_mut() {
+            node.file_count = Some(counts.get(title).copied().unwrap_or(0));
+        }
+    }
+
+    pub fn total_file_count(&self) -> usize {
+        self.nodes.values().filter_map(|node| node.file_count).sum()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let nodes: Vec<Value> = self
+            .nodes
+            .iter()
+            .map(|(title, node)| {
+                json!({
+                    "title": title,
+                    "depth": node.depth,
+                    "parent": node.parent,
+                    "file_count": node.file_count,
+                })
+            })
+            .collect();
+        let edges: Vec<Value> = self.edges.iter().map(|(p, c)| json!([p, c])).collect();
+        let cycles: Vec<Value> = self.cycles.iter().map(|(p, c)| json!([p, c])).collect();
+        json!({
+            "max_depth": self.max_depth,
+            "nodes": nodes,
+            "edges": edges,
+            "cycles": cycles,
+            "excluded": self.excluded,
+        })
+    }
+
+    pub fn from_json(j: &Value) -> Result<Self> {
+        let pair = |v: &Value| -> Option<(String, String)> {
+            Some((
+                v.get(0)?.as_str()?.to_string(),
+                v.get(1)?.as_str()?.to_string(),
+            ))
+        };
+        let mut ret = Self {
+            max_depth: j["max_depth"]
+                .as_i64()
+                .ok_or_else(|| anyhow!("Category tree JSON: max_depth missing"))?
+                as isize,
+            ..Default::default()
+        };
+        for node in j["nodes"].as_array().unwrap_or(&vec![]) {
+            let title = node["title"]
+                .as_str()
+                .ok_or_else(|| anyhow!("Category tree JSON: node without title"))?;
+            ret.nodes.insert(
+                title.to_string(),
+                CategoryNode {
+                    depth: node["depth"].as_u64().unwrap_or(0) as usize,
+                    parent: node["parent"].as_str().map(|s| s.to_string()),
+                    file_count: node["file_count"].as_u64().map(|n| n as usize),
+                },
+            );
+        }
+        ret.edges = j["edges"]
+            .as_array()
+            .unwrap_or(&vec![])
+            .iter()
+            .filter_map(pair)
+            .collect();
+        ret.cycles = j["cycles"]
+            .as_array()
+            .unwrap_or(&vec![])
+            .iter()
+            .filter_map(pair)
+            .collect();
+        ret.excluded = j["excluded"]
+            .as_array()
+            .unwrap_or(&vec![])
+            .iter()
+            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+            .collect();
+        Ok(ret)
+    }
+
+    /// Renders the tree in Graphviz DOT format. Cycle edges are drawn in red.
+    pub fn to_dot(&self) -> String {
+        let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut ret = "digraph categories {\n  rankdir=LR;\n".to_string();
+        for (title, node) in &self.nodes {
+            let label = match node.file_count {
+                Some(count) => format!("{title}\\n{count} files"),
+                None => title.to_owned(),
+            };
+            ret += &format!("  {} [label={}];\n", quote(title), quote(&label));
+        }
+        for (parent, child) in &self.edges {
+            let style = if self.cycles.contains(&(parent.to_owned(), child.to_owned())) {
+                " [color=red]"
+            } else {
+                ""
+            };
+            ret += &format!("  {} -> {}{style};\n", quote(parent), quote(child));
+        }
+        ret += "}\n";
+        ret
+    }
+
+    /// Adds another tree, e.g. of a second category source of the same group.
+    /// Categories in both keep the node with the lower depth.
+    pub fn merge(&mut self, other: CategoryTree) {
+        self.max_depth = self.max_depth.max(other.max_depth);
+        for (title, node) in other.nodes {
+            match self.nodes.get(&title) {
+                Some(existing) if existing.depth <= node.depth => {}
+                _ => {
+                    self.nodes.insert(title, node);
+                }
+            }
+        }
+        self.edges.extend(other.edges);
+        self.cycles.extend(other.cycles);
+        self.excluded.extend(other.excluded);
+    }
+
+    /// Where the tree for a group and month is stored.
+    pub fn path(baglama: &Baglama2, ym: &YearMonth, group_id: DbId) -> Result<String> {
+        let dir = format!("{}/category_trees", ym.make_production_directory(baglama)?);
+        std::fs::create_dir_all(&dir)?;
+        Ok(format!("{dir}/{group_id}.json"))
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(&self.to_json())?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let j: Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        Self::from_json(&j)
+    }
+
+    /// Changes from `old` (usually last month) to `self`.
+    pub fn diff(&self, old: &CategoryTree) -> CategoryTreeDiff {
+        let added_categories = self
+            .nodes
+            .keys()
+            .filter(|title| !old.nodes.contains_key(*title))
+            .cloned()
+            .collect();
+        let removed_categories = old
+            .nodes
+            .keys()
+            .filter(|title| !self.nodes.contains_key(*title))
+            .cloned()
+            .collect();
+        let added_edges = self.edges.difference(&old.edges).cloned().collect();
+        let removed_edges = old.edges.difference(&self.edges).cloned().collect();
+        let file_count_changes = self
+            .nodes
+            .iter()
+            .filter_map(|(title, node)| {
+                let old_count = old.nodes.get(title)?.file_count;
+                if old_count != node.file_count {
+                    Some((title.to_owned(), old_count, node.file_count))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        CategoryTreeDiff {
+            added_categories,
+            removed_categories,
+            added_edges,
+            removed_edges,
+            file_count_changes,
+            old_total_files: old.total_file_count(),
+            new_total_files: self.total_file_count(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CategoryTreeDiff {
+    pub added_categories: Vec<String>,
+    pub removed_categories: Vec<String>,
+    pub added_edges: Vec<(String, String)>,
+    pub removed_edges: Vec<(String, String)>,
+    pub file_count_changes: Vec<(String, Option<usize>, Option<usize>)>,
+    pub old_total_files: usize,
+    pub new_total_files: usize,
+}
+
+impl CategoryTreeDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_categories.is_empty()
+            && self.removed_categories.is_empty()
+            && self.added_edges.is_empty()
+            && self.removed_edges.is_empty()
+            && self.file_count_changes.is_empty()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let edges = |edges: &[(String, String)]| -> Vec<Value> {
+            edges.iter().map(|(p, c)| json!([p, c])).collect()
+        };
+        let file_count_changes: Vec<Value> = self
+            .file_count_changes
+            .iter()
+            .map(|(title, old, new)| json!({"title": title, "old": old, "new": new}))
+            .collect();
+        json!({
+            "added_categories": self.added_categories,
+            "removed_categories": self.removed_categories,
+            "added_edges": edges(&self.added_edges),
+            "removed_edges": edges(&self.removed_edges),
+            "file_count_changes": file_count_changes,
+            "old_total_files": self.old_total_files,
+            "new_total_files": self.new_total_files,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(x: &str) -> String {
+        x.to_string()
+    }
+
+    fn sample_tree() -> CategoryTree {
+        let mut tree = CategoryTree::new(&[s("Root")], 3);
+        let none = GroupExclusions::default();
+        let frontier = tree.add_level(0, vec![(s("Root"), s("A")), (s("Root"), s("B"))], &none);
+        assert_eq!(frontier, vec![s("A"), s("B")]);
+        let frontier = tree.add_level(
+            1,
+            vec![(s("A"), s("C")), (s("B"), s("C")), (s("B"), s("Root"))],
+            &none,
+        );
+        assert_eq!(frontier, vec![s("C")]);
+        let frontier = tree.add_level(2, vec![(s("C"), s("A"))], &none);
+        assert!(frontier.is_empty());
+        tree
+    }
+
+    #[test]
+    fn test_depths_and_edges() {
+        let tree = sample_tree();
+        assert_eq!(tree.categories(), vec![s("A"), s("B"), s("C"), s("Root")]);
+        assert_eq!(tree.nodes()["Root"].depth, 0);
+        assert_eq!(tree.nodes()["A"].depth, 1);
+        assert_eq!(tree.nodes()["C"].depth, 2);
+        assert_eq!(tree.nodes()["C"].parent, Some(s("A")));
+        assert_eq!(tree.edges().len(), 6);
+        assert_eq!(tree.roots(), vec![s("Root")]);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut tree = sample_tree();
+        let none = GroupExclusions::default();
+        let mut other = CategoryTree::new(&[s("C"), s("Other")], 5);
+        other.add_level(0, vec![(s("Other"), s("D"))], &none);
+        tree.merge(other);
+        assert_eq!(tree.max_depth(), 5);
+        assert_eq!(tree.roots(), vec![s("C"), s("Other"), s("Root")]);
+        assert_eq!(tree.nodes()["C"].parent, None);
+        assert_eq!(tree.nodes()["D"].depth, 1);
+        assert_eq!(tree.edges().len(), 7);
+        assert_eq!(tree.cycles().len(), 2);
+    }
+
+    #[test]
+    fn test_cycles() {
+        let tree = sample_tree();
+        // B -> Root and C -> A close cycles; B -> C merely joins two branches.
+        let expected: BTreeSet<(String, String)> = [(s("B"), s("Root")), (s("C"), s("A"))]
+            .into_iter()
+            .collect();
+        assert_eq!(*tree.cycles(), expected);
+    }
+
+    #[test]
+    fn test_excluded_subtree() {
+        let exclusions = GroupExclusions::new(&[s("Maintenance")], &[]).unwrap();
+        let mut tree = CategoryTree::new(&[s("Root")], 3);
+        let frontier = tree.add_level(
+            0,
+            vec![(s("Root"), s("A")), (s("Root"), s("Maintenance"))],
+            &exclusions,
+        );
+        assert_eq!(frontier, vec![s("A")]);
+        assert_eq!(tree.categories(), vec![s("A"), s("Root")]);
+        assert!(tree.excluded().contains("Maintenance"));
+        assert_eq!(tree.edges().len(), 1);
+    }
+
+    /// Subcategory pairs below `cats` in a fixed tree, Root > A > B > C > D.
+    async fn chain_subcats(cats: Vec<String>) -> Result<Vec<(String, String)>> {
+        let chain = ["Root", "A", "B", "C", "D"];
+        Ok(chain
+            .windows(2)
+            .filter(|w| cats.contains(&s(w[0])))
+            .map(|w| (s(w[0]), s(w[1])))
+            .collect())
+    }
+
+    #[tokio::test]
+    async fn test_walk_several_levels() {
+        let none = GroupExclusions::default();
+        let tree = CategoryTree::walk(&[s("Root")], 3, &none, chain_subcats)
+            .await
+            .unwrap();
+        assert_eq!(tree.categories(), vec![s("A"), s("B"), s("C"), s("Root")]);
+        assert_eq!(tree.nodes()["C"].depth, 3);
+        assert_eq!(tree.nodes()["C"].parent, Some(s("B")));
+        let tree = CategoryTree::walk(&[s("Root")], 1, &none, chain_subcats)
+            .await
+            .unwrap();
+        assert_eq!(tree.categories(), vec![s("A"), s("Root")]);
+        let tree = CategoryTree::walk(&[s("Root")], 10, &none, chain_subcats)
+            .await
+            .unwrap();
+        assert_eq!(tree.categories().len(), 5);
+    }
+
+    #[test]
+    fn test_zero_depth_is_empty() {
+        let tree = CategoryTree::new(&[s("Root")], 0);
+        assert!(tree.categories().is_empty());
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let mut tree = sample_tree();
+        let counts: HashMap<String, usize> = [(s("Root"), 3), (s("C"), 7)].into_iter().collect();
+        tree.set_file_counts(&counts);
+        assert_eq!(tree.total_file_count(), 10);
+        let tree2 = CategoryTree::from_json(&tree.to_json()).unwrap();
+        assert_eq!(tree, tree2);
+    }
+
+    #[test]
+    fn test_dot() {
+        let mut tree = CategoryTree::new(&[s("Root \"x\"")], 1);
+        tree.add_level(
+            0,
+            vec![(s("Root \"x\""), s("A"))],
+            &GroupExclusions::default(),
+        );
+        let dot = tree.to_dot();
+        assert!(dot.starts_with("digraph categories {"));
+        assert!(dot.contains("\"Root \\\"x\\\"\" -> \"A\";"));
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = sample_tree();
+        let mut new = sample_tree();
+        new.add_level(
+            2,
+            vec![(s("C"), s("Unrelated"))],
+            &GroupExclusions::default(),
+        );
+        let counts: HashMap<String, usize> = [(s("Unrelated"), 5000)].into_iter().collect();
+        new.set_file_counts(&counts);
+        let diff = new.diff(&old);
+        assert!(!diff.is_empty());
+        assert_eq!(diff.added_categories, vec![s("Unrelated")]);
+        assert!(diff.removed_categories.is_empty());
+        assert_eq!(diff.added_edges, vec![(s("C"), s("Unrelated"))]);
+        assert_eq!(diff.new_total_files, 5000);
+        assert!(old.diff(&old).is_empty());
+    }
+}
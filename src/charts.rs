@@ -0,0 +1,454 @@
+//! Standalone SVG charts: monthly total views (line), views of the top wikis
+//! per month (stacked area), and top files (bar).
+//!
+//! Output depends only on the input data (fixed sizes, palette and number
+//! formatting), so charts can be compared against golden files.
+
+use crate::{
+    group_report::TopFile,
+    row_group_status::RowGroupStatus,
+    trends::GroupTrend,
+    wiki_report::{html_escape, WikiReport},
+    Baglama2, DbId, YearMonth,
+};
+use anyhow::Result;
+use log::warn;
+use mysql_async::{from_row, prelude::*};
+use std::collections::{BTreeMap, HashMap};
+
+const WIDTH: f64 = 640.0;
+const HEIGHT: f64 = 320.0;
+const MARGIN_TOP: f64 = 40.0;
+const MARGIN_BOTTOM: f64 = 40.0;
+const MARGIN_LEFT: f64 = 60.0;
+const MARGIN_RIGHT: f64 = 20.0;
+const LEGEND_WIDTH: f64 = 160.0;
+const BAR_LABEL_WIDTH: f64 = 220.0;
+const Y_TICKS: u64 = 4;
+const PALETTE: [&str; 8] = [
+    "#36c", "#d33", "#fc3", "#14866d", "#a66200", "#6b4ba1", "#72777d", "#ac6600",
+];
+pub const DEFAULT_TOP_WIKIS: usize = 5;
+
+/// Rounds up to 1, 2 or 5 times a power of ten.
+pub fn nice_ceiling(value: u64) -> u64 {
+    let mut magnitude = 1;
+    loop {
+        for factor in [1, 2, 5] {
+            if value <= factor * magnitude {
+                return factor * magnitude;
+            }
+        }
+        magnitude *= 10;
+    }
+}
+
+/// Short form of a count, e.g. 1500 => "1.5k".
+pub fn format_count(value: u64) -> String {
+    match value {
+        0..=999 => value.to_string(),
+        1_000..=999_999 => format!("{}k", trim_decimal(value as f64 / 1e3)),
+        1_000_000..=999_999_999 => format!("{}M", trim_decimal(value as f64 / 1e6)),
+        _ => format!("{}G", trim_decimal(value as f64 / 1e9)),
+    }
+}
+
+fn trim_decimal(value: f64) -> String {
+    let s = format!("{value:.1}");
+    s.strip_suffix(".0").map(|s| s.to_string()).unwrap_or(s)
+}
+
+fn svg_start(width: f64, title: &str) -> String {
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{HEIGHT}\" viewBox=\"0 0 {width} {HEIGHT}\" font-family=\"sans-serif\" font-size=\"11\">\n\
+        <rect width=\"{width}\" height=\"{HEIGHT}\" fill=\"#fff\"/>\n\
+        <text x=\"{:.1}\" y=\"20\" text-anchor=\"middle\" font-size=\"14\" font-weight=\"bold\">{}</text>\n",
+        width / 2.0,
+        html_escape(title)
+    )
+}
+
+/// Y axis with grid lines; returns the SVG, and the maximum of the axis.
+fn y_axis(max_value: u64, x_end: f64) -> (String, u64) {
+    let max = nice_ceiling(max_value.max(1));
+    let mut ret = String::new();
+    for tick in 0..=Y_TICKS {
+        let value = max * tick / Y_TICKS;
+        let y = y_pos(value, max);
+        ret += &format!(
+            "<line x1=\"{MARGIN_LEFT}\" y1=\"{y:.1}\" x2=\"{x_end:.1}\" y2=\"{y:.1}\" stroke=\"#ddd\"/>\n\
+            <text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\">{}</text>\n",
+            MARGIN_LEFT - 5.0,
+            y + 4.0,
+            format_count(value)
+        );
+    }
+    (ret, max)
+}
+
+fn y_pos(value: u64, max: u64) -> f64 {
+    let plot_height = HEIGHT - MARGIN_TOP - MARGIN_BOTTOM;
+    HEIGHT - MARGIN_BOTTOM - plot_height * value as f64 / max as f64
+}
+
+fn x_pos(index: usize, count: usize, x_end: f64) -> f64 {
+    if count <= 1 {
+        return (MARGIN_LEFT + x_end) / 2.0;
+    }
+    MARGIN_LEFT + (x_end - MARGIN_LEFT) * index as f64 / (count - 1) as f64
+}
+
+/// Month labels along the X axis; at most about 12 are shown.
+fn x_labels(labels: &[String], x_end: f64) -> String {
+    let step = labels.len().div_ceil(12).max(1);
+    labels
+        .iter()
+        .enumerate()
+        .filter(|(num, _)| num % step == 0)
+        .map(|(num, label)| {
+            format!(
+                "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}</text>\n",
+                x_pos(num, labels.len(), x_end),
+                HEIGHT - MARGIN_BOTTOM + 16.0,
+                html_escape(label)
+            )
+        })
+        .collect()
+}
+
+/// Line chart of one value per label.
+pub fn line_chart(title: &str, points: &[(String, u64)]) -> String {
+    let x_end = WIDTH - MARGIN_RIGHT;
+    let mut ret = svg_start(WIDTH, title);
+    let (axis, max) = y_axis(points.iter().map(|(_, v)| *v).max().unwrap_or(0), x_end);
+    ret += &axis;
+    let labels: Vec<String> = points.iter().map(|(label, _)| label.to_owned()).collect();
+    ret += &x_labels(&labels, x_end);
+    let coordinates: Vec<String> = points
+        .iter()
+        .enumerate()
+        .map(|(num, (_, value))| {
+            format!(
+                "{:.1},{:.1}",
+                x_pos(num, points.len(), x_end),
+                y_pos(*value, max)
+            )
+        })
+        .collect();
+    ret += &format!(
+        "<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"2\"/>\n",
+        coordinates.join(" "),
+        PALETTE[0]
+    );
+    for point in &coordinates {
+        let (x, y) = point.split_once(',').unwrap_or_default();
+        ret += &format!(
+            "<circle cx=\"{x}\" cy=\"{y}\" r=\"3\" fill=\"{}\"/>\n",
+            PALETTE[0]
+        );
+    }
+    ret + "</svg>\n"
+}
+
+/// Stacked area chart; each series has one value per label.
+pub fn stacked_area_chart(title: &str, labels: &[String], series: &[(String, Vec<u64>)]) -> String {
+    let width = WIDTH + LEGEND_WIDTH;
+    let x_end = WIDTH - MARGIN_RIGHT;
+    let mut ret = svg_start(width, title);
+    let totals: Vec<u64> = (0..labels.len())
+        .map(|num| series.iter().map(|(_, values)| values[num]).sum())
+        .collect();
+    let (axis, max) = y_axis(totals.iter().copied().max().unwrap_or(0), x_end);
+    ret += &axis;
+    ret += &x_labels(labels, x_end);
+    let mut base = vec![0; labels.len()];
+    for (num, (name, values)) in series.iter().enumerate() {
+        let color = PALETTE[num % PALETTE.len()];
+        let top: Vec<u64> = base.iter().zip(values).map(|(b, v)| b + v).collect();
+        let mut coordinates: Vec<String> = top
+            .iter()
+            .enumerate()
+            .map(|(i, v)| format!("{:.1},{:.1}", x_pos(i, labels.len(), x_end), y_pos(*v, max)))
+            .collect();
+        coordinates.extend(
+            base.iter().enumerate().rev().map(|(i, v)| {
+                format!("{:.1},{:.1}", x_pos(i, labels.len(), x_end), y_pos(*v, max))
+            }),
+        );
+        ret += &format!(
+            "<polygon points=\"{}\" fill=\"{color}\" fill-opacity=\"0.8\"/>\n",
+            coordinates.join(" ")
+        );
+        let legend_y = MARGIN_TOP + 16.0 * num as f64;
+        ret += &format!(
+            "<rect x=\"{:.1}\" y=\"{legend_y:.1}\" width=\"10\" height=\"10\" fill=\"{color}\"/>\n\
+            <text x=\"{:.1}\" y=\"{:.1}\">{}</text>\n",
+            WIDTH,
+            WIDTH + 15.0,
+            legend_y + 9.0,
+            html_escape(name)
+        );
+        base = top;
+    }
+    ret + "</svg>\n"
+}
+
+/// Horizontal bar chart, one bar per label, in the given order.
+pub fn bar_chart(title: &str, bars: &[(String, u64)]) -> String {
+    let mut ret = svg_start(WIDTH, title);
+    let max = nice_ceiling(bars.iter().map(|(_, v)| *v).max().unwrap_or(0).max(1));
+    let plot_width = WIDTH - BAR_LABEL_WIDTH - MARGIN_RIGHT - 50.0;
+    let bar_height = ((HEIGHT - MARGIN_TOP - 10.0) / bars.len().max(1) as f64).min(24.0);
+    for (num, (label, value)) in bars.iter().enumerate() {
+        let y = MARGIN_TOP + bar_height * num as f64;
+        let width = plot_width * *value as f64 / max as f64;
+        let label = if label.chars().count() > 32 {
+            format!("{}…", label.chars().take(31).collect::<String>())
+        } else {
+            label.to_owned()
+        };
+        ret += &format!(
+            "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\">{}</text>\n\
+            <rect x=\"{BAR_LABEL_WIDTH}\" y=\"{:.1}\" width=\"{width:.1}\" height=\"{:.1}\" fill=\"{}\"/>\n\
+            <text x=\"{:.1}\" y=\"{:.1}\">{}</text>\n",
+            BAR_LABEL_WIDTH - 5.0,
+            y + bar_height / 2.0 + 4.0,
+            html_escape(&label),
+            y + 2.0,
+            bar_height - 4.0,
+            PALETTE[0],
+            BAR_LABEL_WIDTH + width + 5.0,
+            y + bar_height / 2.0 + 4.0,
+            format_count(*value)
+        );
+    }
+    ret + "</svg>\n"
+}
+
+/// Line chart of the total views of a group.
+pub fn views_chart(trend: &GroupTrend) -> String {
+    let points: Vec<(String, u64)> = trend
+        .points
+        .iter()
+        .map(|(ym, point)| (ym.to_string(), point.total_views.unwrap_or_default()))
+        .collect();
+    line_chart(
+        &format!("Group {}: page views per month", trend.group_id),
+        &points,
+    )
+}
+
+/// Bar chart of the top files of a group month.
+pub fn top_files_chart(title: &str, top_files: &[TopFile], limit: usize) -> String {
+    let bars: Vec<(String, u64)> = top_files
+        .iter()
+        .take(limit)
+        .map(|f| (f.name.replace('_', " "), f.views))
+        .collect();
+    bar_chart(title, &bars)
+}
+
+/// Views per month of the `top` wikis with the most views overall; all
+/// others are summed up as "Other".
+pub fn wiki_series(
+    months: &[YearMonth],
+    rows: &[(YearMonth, String, u64)],
+    top: usize,
+) -> Vec<(String, Vec<u64>)> {
+    let mut server_totals: HashMap<&str, u64> = HashMap::new();
+    for (_, server, views) in rows {
+        *server_totals.entry(server).or_default() += views;
+    }
+    let mut servers: Vec<(&str, u64)> = server_totals.into_iter().collect();
+    servers.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    let top_servers: Vec<&str> = servers.iter().take(top).map(|(s, _)| *s).collect();
+    let month_index: BTreeMap<&YearMonth, usize> =
+        months.iter().enumerate().map(|(i, ym)| (ym, i)).collect();
+
+    let mut ret: Vec<(String, Vec<u64>)> = top_servers
+        .iter()
+        .map(|s| (s.to_string(), vec![0; months.len()]))
+        .collect();
+    let mut other = vec![0; months.len()];
+    for (ym, server, views) in rows {
+        let Some(&index) = month_index.get(ym) else {
+            continue;
+        };
+        match top_servers.iter().position(|s| s == server) {
+            Some(pos) => ret[pos].1[index] += views,
+            None => other[index] += views,
+        }
+    }
+    if other.iter().any(|v| *v > 0) {
+        ret.push(("Other".to_string(), other));
+    }
+    ret
+}
+
+/// Loads (month, server, views) rows for a group. Months whose results
+/// cannot be read are left out, with a warning.
+pub async fn load_wiki_views(
+    baglama: &Baglama2,
+    group_id: DbId,
+    from: &YearMonth,
+    to: &YearMonth,
+) -> Result<Vec<(YearMonth, String, u64)>> {
+    let sql = format!(
+        "SELECT {} FROM `group_status` WHERE `group_id`=? AND `status`='VIEW DATA COMPLETE' AND `year`*100+`month` BETWEEN ? AND ?",
+        RowGroupStatus::sql_all()
+    );
+    let group_status = baglama
+        .get_tooldb_conn()
+        .await?
+        .exec_iter(
+            sql,
+            (
+                group_id,
+                from.year() * 100 + from.month() as i32,
+                to.year() * 100 + to.month() as i32,
+            ),
+        )
+        .await?
+        .map_and_drop(from_row::<RowGroupStatus>)
+        .await?;
+    let mut ret = vec![];
+    for gs in group_status {
+        let ym = YearMonth::new(gs.year, gs.month)?;
+        match WikiReport::load_sites(baglama, &gs).await {
+            Ok(sites) => ret.extend(sites.into_iter().map(|site| (ym, site.server, site.views))),
+            Err(e) => warn!("No views per wiki for group {group_id} in {ym}: {e}"),
+        }
+    }
+    Ok(ret)
+}
+
+/// Stacked area chart of views per wiki for a group.
+pub fn wikis_chart(
+    group_id: DbId,
+    months: &[YearMonth],
+    rows: &[(YearMonth, String, u64)],
+) -> String {
+    let labels: Vec<String> = months.iter().map(|ym| ym.to_string()).collect();
+    let series = wiki_series(months, rows, DEFAULT_TOP_WIKIS);
+    stacked_area_chart(
+        &format!("Group {group_id}: page views per wiki"),
+        &labels,
+        &series,
+    )
+}
+
+/// All months from `from` to `to`, inclusive.
+pub fn month_range(from: &YearMonth, to: &YearMonth) -> Vec<YearMonth> {
+    let mut ret = vec![];
+    let mut ym = *to;
+    while ym >= *from {
+        ret.push(ym);
+        match ym.previous() {
+            Ok(previous) => ym = previous,
+            Err(_) => break,
+        }
+    }
+    ret.reverse();
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trends::TrendPoint;
+
+    fn ym(year: i32, month: u32) -> YearMonth {
+        YearMonth::new(year, month).unwrap()
+    }
+
+    #[test]
+    fn test_nice_ceiling() {
+        assert_eq!(nice_ceiling(0), 1);
+        assert_eq!(nice_ceiling(3), 5);
+        assert_eq!(nice_ceiling(11), 20);
+        assert_eq!(nice_ceiling(5000), 5000);
+        assert_eq!(nice_ceiling(5001), 10000);
+    }
+
+    #[test]
+    fn test_format_count() {
+        assert_eq!(format_count(999), "999");
+        assert_eq!(format_count(1500), "1.5k");
+        assert_eq!(format_count(2_000_000), "2M");
+    }
+
+    #[test]
+    fn test_wiki_series() {
+        let months = month_range(&ym(2024, 1), &ym(2024, 2));
+        let rows = vec![
+            (ym(2024, 1), "de.wikipedia.org".to_string(), 100),
+            (ym(2024, 2), "de.wikipedia.org".to_string(), 50),
+            (ym(2024, 1), "en.wikipedia.org".to_string(), 80),
+            (ym(2024, 2), "fr.wikipedia.org".to_string(), 5),
+        ];
+        let series = wiki_series(&months, &rows, 2);
+        assert_eq!(
+            series,
+            vec![
+                ("de.wikipedia.org".to_string(), vec![100, 50]),
+                ("en.wikipedia.org".to_string(), vec![80, 0]),
+                ("Other".to_string(), vec![0, 5]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_views_chart_golden() {
+        let mut trend = GroupTrend::new(5);
+        for (month, views) in [(1, 1200), (2, 1800), (3, 900)] {
+            trend.points.insert(
+                ym(2024, month),
+                TrendPoint {
+                    total_views: Some(views),
+                    files: None,
+                    pages: None,
+                },
+            );
+        }
+        assert_eq!(
+            views_chart(&trend),
+            include_str!("../test_data/charts/views.svg")
+        );
+    }
+
+    #[test]
+    fn test_wikis_chart_golden() {
+        let months = month_range(&ym(2024, 1), &ym(2024, 3));
+        let rows = vec![
+            (ym(2024, 1), "de.wikipedia.org".to_string(), 600),
+            (ym(2024, 2), "de.wikipedia.org".to_string(), 700),
+            (ym(2024, 3), "de.wikipedia.org".to_string(), 400),
+            (ym(2024, 1), "en.wikipedia.org".to_string(), 300),
+            (ym(2024, 3), "en.wikipedia.org".to_string(), 350),
+        ];
+        assert_eq!(
+            wikis_chart(5, &months, &rows),
+            include_str!("../test_data/charts/wikis.svg")
+        );
+    }
+
+    #[test]
+    fn test_top_files_chart_golden() {
+        let top_files = vec![
+            TopFile {
+                name: "Mona_Lisa.jpg".to_string(),
+                views: 25_000,
+                pages: 12,
+            },
+            TopFile {
+                name: "Tom_&_Jerry.png".to_string(),
+                views: 800,
+                pages: 2,
+            },
+        ];
+        assert_eq!(
+            top_files_chart("Top files", &top_files, 10),
+            include_str!("../test_data/charts/top_files.svg")
+        );
+    }
+}
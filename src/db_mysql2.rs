@@ -1,14 +1,21 @@
 use crate::{
     db_trait::{DbTrait, FilePart, ViewIdSiteIdTitle},
     file::File,
+    file_list::FileList,
+    file_query::FileQuery,
+    file_usage_counts::{FileUsageCounts, UsageCount},
     global_image_links::GlobalImageLinks,
+    group_exclusions::GroupExclusions,
+    group_source::GroupSource,
     page::Page,
+    page_exclusions::PageExclusions,
     pageviews::dump_reader::{self, SiteViewData, TitleFilter},
+    view_anomalies::ViewAnomalies,
     Baglama2, DbId, GroupId, Site, ViewCount, YearMonth,
 };
 use anyhow::{anyhow, Result};
 use log::{error, info, warn};
-use mysql_async::{from_row_opt, prelude::*};
+use mysql_async::{from_row, from_row_opt, prelude::*};
 use serde_json::{json, Value};
 use std::{
     collections::{HashMap, HashSet},
@@ -24,9 +31,19 @@ const MATCH_EXISTING_FILES_CHUNK_SIZE: usize = 10000;
 /// view counts obtained from the dump file.
 const DUMP_UPDATE_BATCH_SIZE: usize = 5000;
 
+/// Condition on `group_status gs` for groups whose totals need to be calculated.
+const TOTALS_MISSING: &str =
+    "(gs.total_views IS NULL OR gs.usage_views IS NULL OR gs.excluded_views IS NULL)";
+
+/// Values of `pageview_sources.source`.
+pub const PAGEVIEW_SOURCE_DUMP: &str = "dump";
+pub const PAGEVIEW_SOURCE_API: &str = "api";
+
 struct PageFile {
     page: Page,
     file: File,
+    /// Wiki page ID of the page, from `globalimagelinks`
+    wiki_page_id: DbId,
 }
 
 impl PageFile {
@@ -44,11 +61,17 @@ pub struct DbMySql2 {
     sites: HashMap<DbId, Site>,
     wiki2site_id: HashMap<String, DbId>,
     table_name: String,
+    full_scan: bool,
 }
 
 impl DbMySql2 {
+    /// The name of the viewdata table for a month.
+    pub fn viewdata_table(ym: &YearMonth) -> String {
+        format!("viewdata_{:04}_{:02}", ym.year(), ym.month())
+    }
+
     pub async fn new(ym: YearMonth, baglama: Arc<Baglama2>) -> Result<Self> {
-        let table_name = format!("viewdata_{:04}_{:02}", ym.year(), ym.month());
+        let table_name = Self::viewdata_table(&ym);
         let mut ret = Self {
             baglama,
             ym,
@@ -57,11 +80,22 @@ impl DbMySql2 {
             sites: HashMap::new(),
             wiki2site_id: HashMap::new(),
             table_name,
+            full_scan: false,
         };
         ret.initialize_sites().await?;
         Ok(ret)
     }
 
+    /// Loads the usages of all files, instead of copying the pages of files
+    /// whose usages did not change since last month. Incremental months build
+    /// on each other, so a full scan should be scheduled regularly.
+    pub fn full_scan(self) -> Self {
+        Self {
+            full_scan: true,
+            ..self
+        }
+    }
+
     /// Load missing page-view counts.
     ///
     /// Tries the fast dump-based path first (a single streaming scan of the
@@ -72,7 +106,7 @@ impl DbMySql2 {
         match self.load_views_from_dump().await {
             Ok(()) => {
                 info!("Pageview dump processed successfully — all views loaded from dump");
-                return Ok(());
+                return self.set_pageview_source(PAGEVIEW_SOURCE_DUMP).await;
             }
             Err(e) => {
                 warn!(
@@ -82,7 +116,26 @@ impl DbMySql2 {
             }
         }
         println!("Pageview strategy: per-page REST API (dump unavailable)");
-        self.load_views_from_api().await
+        self.load_views_from_api().await?;
+        self.set_pageview_source(PAGEVIEW_SOURCE_API).await
+    }
+
+    /// Records where the page views of this month came from.
+    async fn set_pageview_source(&self, source: &str) -> Result<()> {
+        let sql = "REPLACE INTO `pageview_sources` (`year`,`month`,`source`) VALUES (?,?,?)";
+        self.exec_with_params(sql, (self.ym.year(), self.ym.month(), source))
+            .await
+    }
+
+    /// Returns the source of the page views of a month (`dump` or `api`), if known.
+    pub async fn get_pageview_source(baglama: &Baglama2, ym: &YearMonth) -> Result<Option<String>> {
+        let sql = "SELECT `source` FROM `pageview_sources` WHERE `year`=? AND `month`=?";
+        let source = baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_first(sql, (ym.year(), ym.month()))
+            .await?;
+        Ok(source)
     }
 
     // ------------------------------------------------------------------
@@ -522,6 +575,7 @@ impl DbMySql2 {
               `files_id` int(11) unsigned NOT NULL,
               `pages_id` int(11) unsigned NOT NULL,
               `page_views` int(10) unsigned DEFAULT NULL,
+              `excluded` tinyint(1) unsigned NOT NULL DEFAULT 0,
               PRIMARY KEY (`id`),
               UNIQUE KEY `{table_name}_idx1` (`group_status_id`,`files_id`,`pages_id`),
               KEY `{table_name}_idx2` (`pages_id`),
@@ -529,6 +583,70 @@ impl DbMySql2 {
             ) ENGINE=InnoDB DEFAULT CHARSET=ascii;"
         );
         self.execute(&sql).await?;
+        let sql = format!(
+            "ALTER TABLE `{table_name}` ADD COLUMN IF NOT EXISTS `excluded` tinyint(1) unsigned NOT NULL DEFAULT 0"
+        );
+        self.execute(&sql).await?;
+        let sql = "CREATE TABLE IF NOT EXISTS `gs2site` (
+              `id` int(11) unsigned NOT NULL AUTO_INCREMENT,
+              `group_status_id` int(11) unsigned NOT NULL,
+              `site_id` int(11) unsigned NOT NULL,
+              `pages` int(11) unsigned NOT NULL,
+              `views` bigint(20) unsigned NOT NULL,
+              PRIMARY KEY (`id`),
+              UNIQUE KEY `group_status_site` (`group_status_id`,`site_id`),
+              KEY `site_id` (`site_id`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=ascii";
+        self.execute(sql).await?;
+        // Commons page ID, to recognise files after they were moved
+        let sql = "ALTER TABLE `files`
+            ADD COLUMN IF NOT EXISTS `page_id` int(11) unsigned DEFAULT NULL,
+            ADD INDEX IF NOT EXISTS `page_id` (`page_id`)";
+        self.execute(sql).await?;
+        // Wiki page ID, for exports to the SQLite format
+        let sql =
+            "ALTER TABLE `pages` ADD COLUMN IF NOT EXISTS `page_id` int(11) unsigned DEFAULT NULL";
+        self.execute(sql).await?;
+        // Per-usage view sum, next to the distinct-page total_views, and the views of excluded pages
+        let sql = "ALTER TABLE `group_status`
+            ADD COLUMN IF NOT EXISTS `usage_views` bigint(20) unsigned DEFAULT NULL AFTER `total_views`,
+            ADD COLUMN IF NOT EXISTS `excluded_views` bigint(20) unsigned DEFAULT NULL AFTER `usage_views`";
+        self.execute(sql).await?;
+        // Private groups are left out of releases and the public API
+        let sql = "ALTER TABLE `groups` ADD COLUMN IF NOT EXISTS `is_private` tinyint(1) NOT NULL DEFAULT 0";
+        self.execute(sql).await?;
+        let sql = "CREATE TABLE IF NOT EXISTS `file_usage_counts` (
+              `year` int(11) NOT NULL,
+              `month` int(11) NOT NULL,
+              `files_id` int(11) unsigned NOT NULL,
+              `usages` int(11) unsigned NOT NULL,
+              PRIMARY KEY (`year`,`month`,`files_id`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=ascii";
+        self.execute(sql).await?;
+        // Checksum of the pages using a file, see `UsageCount`
+        let sql = "ALTER TABLE `file_usage_counts` ADD COLUMN IF NOT EXISTS `checksum` bigint(20) unsigned DEFAULT NULL";
+        self.execute(sql).await?;
+        let sql = "CREATE TABLE IF NOT EXISTS `pageview_sources` (
+              `year` int(11) NOT NULL,
+              `month` int(11) NOT NULL,
+              `source` varchar(16) NOT NULL,
+              PRIMARY KEY (`year`,`month`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=ascii";
+        self.execute(sql).await?;
+        FileQuery::ensure_table_exists(&self.baglama).await?;
+        // Tables from before a group could have several queries; their rows
+        // have no hash, so those queries are run again
+        let sql = "ALTER TABLE `group_query_files`
+            ADD COLUMN IF NOT EXISTS `query_hash` char(64) NOT NULL DEFAULT '' AFTER `group_id`,
+            DROP INDEX IF EXISTS `group_month_file`,
+            ADD UNIQUE KEY IF NOT EXISTS `group_query_month_file` (`group_id`,`query_hash`,`year`,`month`,`file`)";
+        self.execute(sql).await?;
+        // Tables of the group settings, so reading them needs no DDL
+        GroupExclusions::ensure_table_exists(&self.baglama).await?;
+        GroupSource::ensure_table_exists(&self.baglama).await?;
+        FileList::ensure_table_exists(&self.baglama).await?;
+        ViewAnomalies::ensure_table_exists(&self.baglama).await?;
+        self.baglama.ensure_group_users_table_exists().await?;
         Ok(())
     }
 
@@ -538,19 +656,9 @@ impl DbMySql2 {
             .get_group(&group_id)
             .await?
             .ok_or_else(|| anyhow!("Could not find group {} in MySQL database", group_id))?;
-
-        // Get files in category tree from Commons
-        let files = if group.is_user_name() {
-            // self.get_files_from_user_name(group.category()).await?
-            self.baglama
-                .get_files_from_user_name(group.category())
-                .await
-        } else {
-            // self.get_files_from_commons_category_tree(group.category(), group.depth()).await?
-            self.baglama
-                .get_pages_in_category(group.category(), group.depth(), 6)
-                .await
-        }?;
+        let exclusions = GroupExclusions::load(&self.baglama, group_id.get()).await?;
+        let files =
+            GroupSource::files_for_group(&self.baglama, &group, &self.ym, &exclusions).await?;
         if files.len() < 5 {
             warn!(
                 "{} / {} has {} files",
@@ -599,8 +707,21 @@ impl DbMySql2 {
             let files = self.get_files_for_group(group_id).await?;
             info!("Group ID: {}", group_id);
             info!("Files: {}", files.len());
-            self.add_files_and_pages_for_group(&files, group_id, group_status_id)
-                .await?;
+            let previous_group_status_id = if self.full_scan {
+                None
+            } else {
+                self.get_previous_group_status_id(group_id).await?
+            };
+            if let Some(id) = previous_group_status_id {
+                info!("Group ID {group_id}: building incrementally from group_status {id}");
+            }
+            self.add_files_and_pages_for_group(
+                &files,
+                group_id,
+                group_status_id,
+                previous_group_status_id,
+            )
+            .await?;
         }
         // TODO views
         Ok(())
@@ -611,6 +732,7 @@ impl DbMySql2 {
         all_files: &[String],
         group_id: GroupId,
         group_status_id: DbId,
+        previous_group_status_id: Option<DbId>,
     ) -> Result<()> {
         if all_files.is_empty() {
             // Nothing to do, call it done.
@@ -621,23 +743,77 @@ impl DbMySql2 {
         }
         // let mut futures = Vec::new();
         for files in all_files.chunks(FILES_CHUNK_SIZE) {
-            self.add_files_and_pages_for_group_chunks(group_status_id, files)
-                .await?;
+            self.add_files_and_pages_for_group_chunks(
+                group_status_id,
+                previous_group_status_id,
+                files,
+            )
+            .await?;
             // futures.push(self.add_files_and_pages_for_group_chunks(group_status_id, files));
         }
         // try_join_all(futures).await?;
         self.baglama2()
             .set_group_status(group_id, &self.ym, "SCANNED", 0, "")
             .await?;
+        // A group that is scanned again needs new totals once complete
+        self.reset_totals(&[group_status_id]).await?;
         Ok(())
     }
 
+    /// The status of the group last month, if its pages were collected into
+    /// a viewdata table that still exists.
+    async fn get_previous_group_status_id(&self, group_id: GroupId) -> Result<Option<DbId>> {
+        let previous = self.ym.previous()?;
+        let mut conn = self.baglama.get_tooldb_conn().await?;
+        let sql = "SELECT COUNT(*) FROM information_schema.tables WHERE table_schema=DATABASE() AND table_name=?";
+        let count: Option<usize> = conn
+            .exec_first(sql, (Self::viewdata_table(&previous),))
+            .await?;
+        if count.unwrap_or_default() == 0 {
+            return Ok(None);
+        }
+        let sql = "SELECT `id` FROM `group_status`
+            WHERE `group_id`=? AND `year`=? AND `month`=? AND `storage`='mysql2'
+            AND `status` IN ('SCANNED','VIEW DATA COMPLETE')";
+        let id = conn
+            .exec_first(sql, (group_id.get(), previous.year(), previous.month()))
+            .await?;
+        Ok(id)
+    }
+
     async fn add_files_and_pages_for_group_chunks(
         &self,
         group_status_id: usize,
+        previous_group_status_id: Option<DbId>,
         files: &[String],
     ) -> Result<()> {
-        let globalimagelinks = GlobalImageLinks::load(files, self.baglama2()).await?;
+        // Usages of a moved file may still use the old name (a redirect)
+        let redirects = self.baglama.get_file_redirects(files).await?;
+        let files = match previous_group_status_id {
+            Some(previous_group_status_id) => {
+                self.copy_unchanged_file_pages(
+                    group_status_id,
+                    previous_group_status_id,
+                    files,
+                    &redirects,
+                )
+                .await?
+            }
+            None => files.to_vec(),
+        };
+        if files.is_empty() {
+            return Ok(());
+        }
+        let targets: HashSet<&String> = files.iter().collect();
+        let mut files_with_redirects = files.to_vec();
+        files_with_redirects.extend(
+            redirects
+                .iter()
+                .filter(|(_, to)| targets.contains(to))
+                .map(|(from, _)| from.to_owned()),
+        );
+        let globalimagelinks =
+            GlobalImageLinks::load(&files_with_redirects, self.baglama2()).await?;
         let mut page_files = Vec::new();
         for gil in &globalimagelinks {
             let site = match self.get_site_for_wiki(&gil.wiki) {
@@ -648,27 +824,151 @@ impl DbMySql2 {
                 }
             };
 
-            let file = File::new_no_id(&gil.to);
+            let file = File::new_no_id(redirects.get(&gil.to).unwrap_or(&gil.to));
             let page = Page::new(site.id(), gil.page_title.to_owned(), gil.page_namespace_id);
-            page_files.push(PageFile { page, file });
+            page_files.push(PageFile {
+                page,
+                file,
+                wiki_page_id: gil.page,
+            });
         }
         self.ensure_files_exist(&mut page_files).await?;
         self.ensure_pages_exist(&mut page_files).await?;
         self.insert_file_pages(&page_files, group_status_id).await?;
+        let page_ids: Vec<(DbId, DbId)> = page_files
+            .iter()
+            .filter_map(|pf| Some((pf.page.id?, pf.wiki_page_id)))
+            .collect();
+        let mut conn = self.baglama.get_tooldb_conn().await?;
+        Self::backfill_page_ids(&mut conn, &page_ids).await?;
+
+        // Usage counts of all loaded usages, including those on unknown wikis
+        let counts = FileUsageCounts::resolve(
+            globalimagelinks
+                .iter()
+                .map(|gil| (&gil.to, UsageCount::of(gil))),
+            &redirects,
+        );
+        let file2id: HashMap<&String, DbId> = page_files
+            .iter()
+            .filter_map(|pf| Some((&pf.file.name, pf.file.id?)))
+            .collect();
+        let counts: Vec<(DbId, UsageCount)> = counts
+            .iter()
+            .filter_map(|(file, count)| Some((*file2id.get(file)?, *count)))
+            .collect();
+        FileUsageCounts::save(&mut conn, &self.ym, &counts).await?;
         Ok(())
     }
 
+    /// Copies last month's pages of the files whose usages did not change.
+    /// Returns the files whose usages need to be loaded.
+    async fn copy_unchanged_file_pages(
+        &self,
+        group_status_id: DbId,
+        previous_group_status_id: DbId,
+        files: &[String],
+        redirects: &HashMap<String, String>,
+    ) -> Result<Vec<String>> {
+        let mut files_with_redirects = files.to_vec();
+        files_with_redirects.extend(redirects.keys().cloned());
+        let counts = GlobalImageLinks::counts(&files_with_redirects, self.baglama2()).await?;
+        let counts = FileUsageCounts::resolve(counts.iter().map(|(f, c)| (f, *c)), redirects);
+
+        let mut conn = self.baglama.get_tooldb_conn().await?;
+        let placeholders = Self::get_placeholders("?,", files.len())?;
+        let sql = format!(
+            "SELECT FROM_BASE64(TO_BASE64(`name`)),`id` FROM `files` WHERE `name` IN ({placeholders})"
+        );
+        let file2id: HashMap<String, DbId> = conn
+            .exec_iter(sql, files.to_owned())
+            .await?
+            .map_and_drop(from_row::<(String, DbId)>)
+            .await?
+            .into_iter()
+            .collect();
+        if file2id.is_empty() {
+            return Ok(files.to_vec());
+        }
+        let ids: Vec<DbId> = file2id.values().copied().collect();
+        let previous = self.ym.previous()?;
+        let previous_counts = FileUsageCounts::load(&mut conn, &previous, &ids).await?;
+
+        let previous_table = Self::viewdata_table(&previous);
+        let placeholders = Self::get_placeholders("?,", ids.len())?;
+        let sql = format!(
+            "SELECT DISTINCT `files_id` FROM `{previous_table}` WHERE `group_status_id`=? AND `files_id` IN ({placeholders})"
+        );
+        let mut params = vec![previous_group_status_id];
+        params.extend(&ids);
+        let previous_files: HashSet<DbId> = conn
+            .exec_iter(sql, params)
+            .await?
+            .map_and_drop(from_row::<DbId>)
+            .await?
+            .into_iter()
+            .collect();
+
+        let unchanged = FileUsageCounts::unchanged_files(
+            files,
+            &counts,
+            &file2id,
+            &previous_counts,
+            &previous_files,
+        );
+        if unchanged.is_empty() {
+            return Ok(files.to_vec());
+        }
+        let unchanged_ids: Vec<DbId> = unchanged.iter().map(|(_, id)| *id).collect();
+        let placeholders = Self::get_placeholders("?,", unchanged_ids.len())?;
+        let table_name = self.table_name();
+        let sql = format!(
+            "INSERT IGNORE INTO `{table_name}` (`group_status_id`,`files_id`,`pages_id`)
+            SELECT ?,`files_id`,`pages_id` FROM `{previous_table}`
+            WHERE `group_status_id`=? AND `files_id` IN ({placeholders})"
+        );
+        let mut params = vec![group_status_id, previous_group_status_id];
+        params.extend(&unchanged_ids);
+        conn.exec_drop(sql, params).await?;
+        let unchanged_counts: Vec<(DbId, UsageCount)> = unchanged
+            .iter()
+            .map(|(file, id)| (*id, counts.get(file).copied().unwrap_or_default()))
+            .collect();
+        FileUsageCounts::save(&mut conn, &self.ym, &unchanged_counts).await?;
+
+        let unchanged: HashSet<String> = unchanged.into_iter().map(|(file, _)| file).collect();
+        info!(
+            "Copied pages of {} of {} files from {previous}",
+            unchanged.len(),
+            files.len()
+        );
+        Ok(files
+            .iter()
+            .filter(|file| !unchanged.contains(*file))
+            .cloned()
+            .collect())
+    }
+
     async fn ensure_files_exist(&self, page_files: &mut [PageFile]) -> Result<()> {
         let files = page_files
             .iter()
             .map(|pf| pf.file.name.to_owned())
             .collect::<Vec<_>>();
 
+        self.backfill_file_page_ids(&files).await?;
         let files_to_create = self.match_existing_files(page_files, files).await?;
         if files_to_create.is_empty() {
             return Ok(());
         }
-        self.create_files(&files_to_create).await?;
+        let page_ids = self.baglama.get_file_page_ids(&files_to_create).await?;
+        self.rename_moved_files(&page_ids).await?;
+        let files_to_create = self
+            .match_existing_files(page_files, files_to_create)
+            .await?;
+        if files_to_create.is_empty() {
+            return Ok(());
+        }
+        self.create_files(&files_to_create, &page_ids).await?;
         let failed_to_create = self
             .match_existing_files(page_files, files_to_create)
             .await?;
@@ -690,16 +990,124 @@ impl DbMySql2 {
         Ok(placeholders)
     }
 
-    async fn create_files(&self, all_files: &[String]) -> Result<()> {
+    async fn create_files(
+        &self,
+        all_files: &[String],
+        page_ids: &HashMap<String, DbId>,
+    ) -> Result<()> {
         if all_files.is_empty() {
             return Ok(());
         }
         // Acquire one connection for all chunks rather than one per chunk.
         let mut conn = self.baglama2().get_tooldb_conn().await?;
         for files in all_files.chunks(1000) {
-            let placeholders = Self::get_placeholders("(?),", files.len())?;
-            let sql = format!("INSERT IGNORE INTO `files` (`name`) VALUES {placeholders}");
-            conn.exec_drop(sql, files.to_owned()).await?;
+            let placeholders = Self::get_placeholders("(?,?),", files.len())?;
+            let sql =
+                format!("INSERT IGNORE INTO `files` (`name`,`page_id`) VALUES {placeholders}");
+            let params: Vec<mysql_async::Value> = files
+                .iter()
+                .flat_map(|file| [file.to_owned().into(), page_ids.get(file).copied().into()])
+                .collect();
+            conn.exec_drop(sql, params).await?;
+        }
+        Ok(())
+    }
+
+    /// Sets the Commons page ID for files that were created without one.
+    async fn backfill_file_page_ids(&self, all_files: &[String]) -> Result<()> {
+        let mut conn = self.baglama2().get_tooldb_conn().await?;
+        for files in all_files.chunks(MATCH_EXISTING_FILES_CHUNK_SIZE) {
+            let placeholders = Self::get_placeholders("?,", files.len())?;
+            let sql = format!(
+                "SELECT `name` FROM `files` WHERE `page_id` IS NULL AND `name` IN ({placeholders})"
+            );
+            let without_page_id = conn
+                .exec_iter(sql, files.to_owned())
+                .await?
+                .map_and_drop(mysql_async::from_row::<String>)
+                .await?;
+            if without_page_id.is_empty() {
+                continue;
+            }
+            let page_ids = self.baglama.get_file_page_ids(&without_page_id).await?;
+            let sql = "UPDATE `files` SET `page_id`=? WHERE `name`=? AND `page_id` IS NULL";
+            conn.exec_batch(sql, page_ids.iter().map(|(name, page_id)| (*page_id, name)))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Sets the wiki page ID of `pages` rows that were created without one.
+    /// Takes (`pages.id`, wiki page ID) pairs; a wiki page ID of 0 is unknown.
+    pub async fn backfill_page_ids(
+        conn: &mut mysql_async::Conn,
+        page_ids: &[(DbId, DbId)],
+    ) -> Result<()> {
+        let page_ids: Vec<(DbId, DbId)> = page_ids
+            .iter()
+            .filter(|(_, page_id)| *page_id > 0)
+            .copied()
+            .collect::<HashMap<_, _>>()
+            .into_iter()
+            .collect();
+        for chunk in page_ids.chunks(PAGES_CHUNK_SIZE) {
+            let placeholders = Baglama2::sql_placeholders(chunk.len());
+            let sql = format!(
+                "SELECT `id` FROM `pages` WHERE `page_id` IS NULL AND `id` IN ({placeholders})"
+            );
+            let ids: Vec<DbId> = chunk.iter().map(|(id, _)| *id).collect();
+            let without_page_id: HashSet<DbId> = conn
+                .exec_iter(sql, ids)
+                .await?
+                .map_and_drop(from_row::<DbId>)
+                .await?
+                .into_iter()
+                .collect();
+            let sql = "UPDATE `pages` SET `page_id`=? WHERE `id`=? AND `page_id` IS NULL";
+            conn.exec_batch(
+                sql,
+                chunk
+                    .iter()
+                    .filter(|(id, _)| without_page_id.contains(id))
+                    .map(|(id, page_id)| (*page_id, *id)),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Renames `files` rows whose Commons page has been moved to a new name,
+    /// so the file keeps its ID instead of being counted as a new file.
+    async fn rename_moved_files(&self, page_ids: &HashMap<String, DbId>) -> Result<()> {
+        if page_ids.is_empty() {
+            return Ok(());
+        }
+        let page_id2name: HashMap<DbId, &String> =
+            page_ids.iter().map(|(name, id)| (*id, name)).collect();
+        let ids: Vec<DbId> = page_id2name.keys().copied().collect();
+        let mut conn = self.baglama2().get_tooldb_conn().await?;
+        for chunk in ids.chunks(MATCH_EXISTING_FILES_CHUNK_SIZE) {
+            let placeholders = Self::get_placeholders("?,", chunk.len())?;
+            let sql = format!(
+                "SELECT `id`,`name`,`page_id` FROM `files` WHERE `page_id` IN ({placeholders})"
+            );
+            let existing = conn
+                .exec_iter(sql, chunk.to_owned())
+                .await?
+                .map_and_drop(File::from_row_opt)
+                .await?;
+            for file in existing.into_iter().filter_map(|f| f.ok()) {
+                let (Some(id), Some(page_id)) = (file.id, file.page_id) else {
+                    continue;
+                };
+                let new_name = match page_id2name.get(&page_id) {
+                    Some(new_name) if **new_name != file.name => *new_name,
+                    _ => continue,
+                };
+                info!("File {} was moved to {new_name}", file.name);
+                let sql = "UPDATE IGNORE `files` SET `name`=? WHERE `id`=?";
+                conn.exec_drop(sql, (new_name, id)).await?;
+            }
         }
         Ok(())
     }
@@ -859,6 +1267,52 @@ impl DbMySql2 {
         Ok(pages_to_create.into_iter().collect::<Vec<_>>())
     }
 
+    /// (group_status_id, pages_id) of the flagged viewdata rows of the month.
+    async fn excluded_rows(&self) -> Result<HashSet<(DbId, DbId)>> {
+        let sql = format!(
+            "SELECT DISTINCT `group_status_id`,`pages_id` FROM `{}` WHERE `excluded`=1",
+            self.table_name()
+        );
+        Ok(self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, ())
+            .await?
+            .map_and_drop(from_row::<(DbId, DbId)>)
+            .await?
+            .into_iter()
+            .collect())
+    }
+
+    /// IDs of the given pages that exist in the `pages` table.
+    async fn excluded_page_ids(&self, pages: &[Page]) -> Result<Vec<DbId>> {
+        let mut ret = vec![];
+        let mut conn = self.baglama2().get_tooldb_conn().await?;
+        for pages in pages.chunks(PAGES_CHUNK_SIZE) {
+            let placeholders =
+                vec!["(site=? AND title=? AND namespace_id=?)"; pages.len()].join(" OR ");
+            let params = pages
+                .iter()
+                .flat_map(|p| {
+                    [
+                        p.site_id.to_string(),
+                        p.title.to_owned(),
+                        p.namespace_id.to_string(),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            let sql = format!("SELECT `id` FROM `pages` WHERE {placeholders}");
+            ret.extend(
+                conn.exec_iter(sql, &params)
+                    .await?
+                    .map_and_drop(from_row::<DbId>)
+                    .await?,
+            );
+        }
+        Ok(ret)
+    }
+
     async fn insert_file_pages(
         &self,
         all_page_files: &[PageFile],
@@ -918,34 +1372,121 @@ impl DbMySql2 {
         }
     }
 
-    /// Sets the group_status to 'VIEW DATA COMPLETE' and updates the `total_views` field
+    /// Sets the group_status to 'VIEW DATA COMPLETE' and updates the `total_views`
+    /// and `usage_views` fields. View anomalies are flagged once the whole month is complete.
     async fn finalize_group_status(&self) -> Result<()> {
-        let year = self.ym.year();
-        let month = self.ym.month();
-        let table_name = self.table_name();
+        self.apply_page_exclusions().await?;
+        let (sql1, sql2) = Self::finalize_group_status_sql(self.table_name());
+        let params = (self.ym.year(), self.ym.month());
+        self.exec_with_params(&sql1, params).await?;
+        // Anomalies are checked against the whole month, so only once all its groups are complete
+        if self.month_is_complete().await? {
+            ViewAnomalies::new(&self.baglama, self.ym).run().await?;
+        }
+        // total_views is taken from the per-site statistics
+        self.add_site_statistics().await?;
+        self.exec_with_params(&sql2, params).await?;
+        Ok(())
+    }
+
+    /// Returns true if no mysql2 group of the month is still waiting for its views.
+    async fn month_is_complete(&self) -> Result<bool> {
+        let sql = "SELECT COUNT(*) FROM `group_status`
+            WHERE `year`=? AND `month`=? AND `storage`='mysql2' AND `status` IN ('STARTED','SCANNED')";
+        let count: Option<usize> = self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_first(sql, (self.ym.year(), self.ym.month()))
+            .await?;
+        Ok(count.unwrap_or_default() == 0)
+    }
+
+    /// Calculates the view totals and per-site statistics of complete groups
+    /// whose totals are missing, without changing any status.
+    pub async fn recalculate_totals(&self) -> Result<()> {
+        let (_, sql2) = Self::finalize_group_status_sql(self.table_name());
+        self.add_site_statistics().await?;
+        self.exec_with_params(&sql2, (self.ym.year(), self.ym.month()))
+            .await
+    }
 
+    /// Marks finished groups as complete, and calculates their view totals.
+    /// `total_views` counts each page once, `usage_views` once per file it uses;
+    /// both leave out excluded pages, whose views go into `excluded_views`.
+    /// Also fixes complete groups with a missing or (pre-`usage_views`) inflated total.
+    fn finalize_group_status_sql(table_name: &str) -> (String, String) {
         // table_name is generated internally (not user input) so interpolation is safe.
         // year and month are bound as parameters.
-
-        // Fix group_status.status for finished groups
-        let sql = format!(
+        let sql1 = format!(
             "UPDATE group_status
-            SET `status`='VIEW DATA COMPLETE',
-            total_views=(SELECT sum(page_views) FROM `{table_name}` WHERE group_status_id=group_status.id)
+            SET `status`='VIEW DATA COMPLETE'
             WHERE `year`=? AND `month`=?
             AND `status`='SCANNED'
             AND NOT EXISTS (SELECT * FROM `{table_name}` WHERE group_status_id=group_status.id AND page_views IS NULL)"
         );
-        self.exec_with_params(&sql, (year, month)).await?;
-
-        // Calculate total_views
         let sql2 = format!(
-            "UPDATE group_status
-            SET total_views=(SELECT COALESCE(sum(page_views),0) FROM `{table_name}` WHERE group_status_id=group_status.id)
-            WHERE `year`=? AND `month`=? AND status='VIEW DATA COMPLETE' AND total_views IS NULL"
+            "UPDATE group_status gs
+            SET gs.total_views=(SELECT COALESCE(sum(views),0) FROM `gs2site` WHERE group_status_id=gs.id),
+            gs.usage_views=(SELECT COALESCE(sum(page_views),0) FROM `{table_name}` WHERE group_status_id=gs.id AND excluded=0),
+            gs.excluded_views=(SELECT COALESCE(sum(page_views),0) FROM `{table_name}` v WHERE group_status_id=gs.id AND excluded=1
+                AND NOT EXISTS (SELECT * FROM `{table_name}` v2 WHERE v2.group_status_id=v.group_status_id AND v2.pages_id=v.pages_id AND v2.id<v.id))
+            WHERE gs.year=? AND gs.month=? AND gs.status='VIEW DATA COMPLETE' AND gs.storage='mysql2'
+            AND {TOTALS_MISSING}"
         );
-        self.exec_with_params(&sql2, (year, month)).await?;
-        Ok(())
+        (sql1, sql2)
+    }
+
+    /// Per-site pages and views for each completed group_status of the
+    /// month whose totals are missing, like `gs2site` in SQLite files.
+    /// Existing rows of those groups are deleted first, as they may be stale.
+    /// A page is counted once per group, no matter how many files it uses.
+    fn site_statistics_sql(table_name: &str) -> (String, String) {
+        let delete = format!(
+            "DELETE gs2site FROM `gs2site`,group_status gs
+            WHERE gs.id=gs2site.group_status_id
+            AND gs.year=? AND gs.month=? AND gs.status='VIEW DATA COMPLETE' AND gs.storage='mysql2'
+            AND {TOTALS_MISSING}"
+        );
+        let insert = format!(
+            "INSERT IGNORE INTO `gs2site` (`group_status_id`,`site_id`,`pages`,`views`)
+            SELECT group_status_id,site,COUNT(*),COALESCE(SUM(views),0) FROM (
+                SELECT v.group_status_id,pages.site,v.pages_id,MAX(v.page_views) AS views
+                FROM `{table_name}` v,pages,group_status gs
+                WHERE pages.id=v.pages_id AND gs.id=v.group_status_id AND v.excluded=0
+                AND gs.year=? AND gs.month=? AND gs.status='VIEW DATA COMPLETE' AND gs.storage='mysql2'
+                AND {TOTALS_MISSING}
+                GROUP BY v.group_status_id,v.pages_id
+            ) t
+            GROUP BY group_status_id,site"
+        );
+        (delete, insert)
+    }
+
+    /// (Re)builds the per-site statistics of complete groups without totals.
+    pub async fn add_site_statistics(&self) -> Result<()> {
+        let (delete, insert) = Self::site_statistics_sql(self.table_name());
+        let params = (self.ym.year(), self.ym.month());
+        self.exec_with_params(&delete, params).await?;
+        self.exec_with_params(&insert, params).await
+    }
+
+    /// Clears the totals of groups whose view data changed; they, and the
+    /// per-site statistics, are calculated again by `recalculate_totals`.
+    pub async fn reset_totals(&self, group_status_ids: &[DbId]) -> Result<()> {
+        if group_status_ids.is_empty() {
+            return Ok(());
+        }
+        let sql = Self::reset_totals_sql(group_status_ids.len());
+        self.exec_with_params(&sql, group_status_ids.to_vec()).await
+    }
+
+    /// Clears the totals of `count` group_status IDs, given as parameters.
+    pub fn reset_totals_sql(count: usize) -> String {
+        format!(
+            "UPDATE group_status SET total_views=NULL,usage_views=NULL,excluded_views=NULL WHERE id IN ({})",
+            Baglama2::sql_placeholders(count)
+        )
     }
 
     /// Used for internal testing only
@@ -1092,12 +1633,48 @@ impl DbTrait for DbMySql2 {
         Err(anyhow!("load_files_batch is not supported by DbMySql2"))
     }
 
-    async fn reset_main_page_view_count(&self) -> Result<()> {
-        // Not implemented for MySQL: per-wiki Main Page reset is not needed
-        // in the mysql2 pipeline (view counts are fetched fresh from the API).
-        Err(anyhow!(
-            "reset_main_page_view_count is not supported by DbMySql2"
+    /// Flags the viewdata rows of excluded pages; global exclusions for the
+    /// whole month, group rules for the group_status of that group. Flags of
+    /// earlier runs are cleared first, so removed exclusions take effect. The
+    /// totals of groups whose flags changed are cleared, to be calculated again.
+    async fn apply_page_exclusions(&self) -> Result<()> {
+        let table_name = self.table_name();
+        let before = self.excluded_rows().await?;
+        self.execute(&format!(
+            "UPDATE `{table_name}` SET `excluded`=0 WHERE `excluded`=1"
         ))
+        .await?;
+        let sites = self.baglama.get_sites()?;
+        let global = PageExclusions::load_global(&self.baglama).await?;
+        let page_ids = self.excluded_page_ids(&global.site_pages(&sites)).await?;
+        for chunk in page_ids.chunks(PAGES_CHUNK_SIZE) {
+            let sql = format!(
+                "UPDATE `{table_name}` SET `excluded`=1 WHERE `pages_id` IN ({})",
+                Baglama2::sql_placeholders(chunk.len())
+            );
+            self.exec_with_params(&sql, chunk.to_vec()).await?;
+        }
+        for (group_id, pages) in PageExclusions::load_group_rules(&self.baglama).await? {
+            let pages = PageExclusions::new(pages).site_pages(&sites);
+            let page_ids = self.excluded_page_ids(&pages).await?;
+            for chunk in page_ids.chunks(PAGES_CHUNK_SIZE) {
+                let sql = format!(
+                    "UPDATE `{table_name}` v,`group_status` gs SET v.excluded=1
+                    WHERE gs.id=v.group_status_id AND gs.group_id=? AND v.pages_id IN ({})",
+                    Baglama2::sql_placeholders(chunk.len())
+                );
+                let mut params = vec![group_id];
+                params.extend_from_slice(chunk);
+                self.exec_with_params(&sql, params).await?;
+            }
+        }
+        let after = self.excluded_rows().await?;
+        let changed: HashSet<DbId> = before
+            .symmetric_difference(&after)
+            .map(|(group_status_id, _)| *group_status_id)
+            .collect();
+        self.reset_totals(&changed.into_iter().collect::<Vec<_>>())
+            .await
     }
 
     // components are tested
@@ -1163,7 +1740,7 @@ impl DbTrait for DbMySql2 {
                 )
             })
             .collect();
-        let sql = "SELECT `id`,`site`,`title` FROM `views` WHERE ".to_string()
+        let sql = "SELECT `id`,`site`,`namespace_id`,`title` FROM `views` WHERE ".to_string()
             + &placeholders.join(" OR ");
         let viewid_site_id_title = self
             .baglama
@@ -1211,6 +1788,7 @@ mod tests {
         PageFile {
             page: Page::new(1, "SomePage".to_string(), 0),
             file: File::new_no_id(file_name),
+            wiki_page_id: 0,
         }
     }
 
@@ -1313,27 +1891,38 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_site_statistics_sql() {
+        let (delete, sql) = DbMySql2::site_statistics_sql("viewdata_2024_01");
+        assert_eq!(sql.matches('?').count(), 2);
+        assert!(sql.contains("FROM `viewdata_2024_01` v"));
+        assert!(sql.contains("GROUP BY v.group_status_id,v.pages_id"));
+        assert!(sql.contains("AND v.excluded=0"));
+        assert!(!sql.contains("NOT EXISTS"));
+        // Stale statistics of the same groups are removed first
+        assert_eq!(delete.matches('?').count(), 2);
+        assert!(delete.starts_with("DELETE gs2site"));
+        assert!(delete.contains(TOTALS_MISSING) && sql.contains(TOTALS_MISSING));
+    }
+
+    #[test]
+    fn test_finalize_group_status_sql_totals() {
+        let (sql1, sql2) = DbMySql2::finalize_group_status_sql("viewdata_2024_01");
+        assert!(!sql1.contains("total_views"));
+        assert!(sql2.contains("total_views=(SELECT COALESCE(sum(views),0) FROM `gs2site`"));
+        assert!(sql2.contains("usage_views=(SELECT COALESCE(sum(page_views),0) FROM `viewdata_2024_01` WHERE group_status_id=gs.id AND excluded=0)"));
+        assert!(sql2.contains(TOTALS_MISSING));
+        assert!(sql2.contains("excluded_views=(SELECT"));
+    }
+
     /// finalize_group_status must bind year and month as parameters.
     /// table_name is generated internally and interpolated as an identifier
     /// (MySQL does not allow table names as bind parameters), so that part
     /// remains in the format string — but year/month must not.
     #[test]
     fn test_finalize_group_status_sql_is_parameterized() {
-        // Simulate what finalize_group_status produces for a known table_name.
         let table_name = "viewdata_2024_01";
-        let sql1 = format!(
-            "UPDATE group_status
-            SET `status`='VIEW DATA COMPLETE',
-            total_views=(SELECT sum(page_views) FROM `{table_name}` WHERE group_status_id=group_status.id)
-            WHERE `year`=? AND `month`=?
-            AND `status`='SCANNED'
-            AND NOT EXISTS (SELECT * FROM `{table_name}` WHERE group_status_id=group_status.id AND page_views IS NULL)"
-        );
-        let sql2 = format!(
-            "UPDATE group_status
-            SET total_views=(SELECT COALESCE(sum(page_views),0) FROM `{table_name}` WHERE group_status_id=group_status.id)
-            WHERE `year`=? AND `month`=? AND status='VIEW DATA COMPLETE' AND total_views IS NULL"
-        );
+        let (sql1, sql2) = DbMySql2::finalize_group_status_sql(table_name);
 
         assert_eq!(
             sql1.matches('?').count(),
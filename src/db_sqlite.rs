@@ -1,4 +1,5 @@
 use crate::db_trait::{DbTrait, FilePart, ViewIdSiteIdTitle};
+use crate::page_exclusions::PageExclusions;
 use crate::{Baglama2, DbId, GroupDate, GroupId, Site, ViewCount, YearMonth};
 use anyhow::{anyhow, Result};
 
@@ -9,6 +10,15 @@ use std::sync::{Arc, Mutex};
 
 const SQLITE_DATA_TMP_PATH: &str = "/tmp";
 
+/// Completes the group_status. `total_views` counts each page (row in `views`)
+/// once; `usage_views` counts it once per file it uses. Both leave out
+/// excluded pages, whose views go into `excluded_views`.
+const SUMMARY_VIEWS_SQL: &str = "UPDATE `group_status` SET `status`='VIEW DATA COMPLETE',
+    `total_views`=(SELECT COALESCE(SUM(`views`),0) FROM `views` WHERE `excluded`=0 AND `id` IN (SELECT `view_id` FROM `group2view`)),
+    `usage_views`=(SELECT COALESCE(SUM(views.views),0) FROM `group2view`,`views` WHERE views.id=group2view.view_id AND views.excluded=0),
+    `excluded_views`=(SELECT COALESCE(SUM(`views`),0) FROM `views` WHERE `excluded`=1 AND `id` IN (SELECT `view_id` FROM `group2view`))
+    WHERE `id`=?1";
+
 #[derive(Debug, Clone)]
 pub struct DbSqlite {
     path_final: String,
@@ -113,7 +123,7 @@ impl DbSqlite {
         self.conn().execute("DELETE FROM `group_status`", ())?;
         let group_status = self.baglama.get_group_status(group_id, ym).await?;
         if let Some(gs) = group_status {
-            let sql = "INSERT INTO `group_status` (id,group_id,year,month,status,total_views,file,sqlite3) VALUES (?,?,?,?,?,?,?,?)" ;
+            let sql = "INSERT INTO `group_status` (id,group_id,year,month,status,total_views,usage_views,excluded_views,file,sqlite3) VALUES (?,?,?,?,?,?,?,?,?,?)" ;
             self.conn().execute(
                 sql,
                 rusqlite::params![
@@ -123,6 +133,8 @@ impl DbSqlite {
                     gs.month,
                     gs.status,
                     gs.total_views,
+                    gs.usage_views,
+                    gs.excluded_views,
                     gs.file,
                     gs.sqlite3
                 ],
@@ -246,10 +258,19 @@ impl DbTrait for DbSqlite {
         &self.ym
     }
 
-    async fn reset_main_page_view_count(&self) -> Result<()> {
-        // TODO for all wikis?
-        let sql = "UPDATE views SET views=0 WHERE title='Main_Page'";
-        self.conn().execute(sql, ())?;
+    async fn apply_page_exclusions(&self) -> Result<()> {
+        let mut exclusions = PageExclusions::load_global(&self.baglama).await?;
+        exclusions
+            .extend(PageExclusions::load_for_group(&self.baglama, self.group_id.get()).await?);
+        let sites = self.load_sites()?;
+        let conn = self.conn();
+        conn.execute("UPDATE `views` SET `excluded`=0", ())?;
+        for page in exclusions.site_pages(&sites) {
+            conn.execute(
+                "UPDATE `views` SET `excluded`=1 WHERE `site`=?1 AND `namespace_id`=?2 AND `title`=?3",
+                rusqlite::params![page.site_id as isize, page.namespace_id, page.title],
+            )?;
+        }
         Ok(())
     }
 
@@ -257,8 +278,11 @@ impl DbTrait for DbSqlite {
         self.conn()
             .execute("CREATE INDEX `views_site` ON `views` (site)", ())?;
         self.conn().execute("DELETE FROM `gs2site`", ())?;
-        self.conn().execute("INSERT INTO `gs2site` SELECT sites.id,?1,sites.id,COUNT(DISTINCT page_id),SUM(views) FROM `views`,`sites` WHERE views.site=sites.id GROUP BY sites.id",rusqlite::params![group_status_id as isize])?;
-        self.conn().execute("UPDATE group_status SET status='VIEW DATA COMPLETE',total_views=(SELECT sum(views) FROM gs2site) WHERE id=?1",rusqlite::params![group_status_id as isize])?;
+        self.conn().execute("INSERT INTO `gs2site` SELECT sites.id,?1,sites.id,COUNT(DISTINCT page_id),SUM(views) FROM `views`,`sites` WHERE views.site=sites.id AND views.excluded=0 GROUP BY sites.id",rusqlite::params![group_status_id as isize])?;
+        self.conn().execute(
+            SUMMARY_VIEWS_SQL,
+            rusqlite::params![group_status_id as isize],
+        )?;
         Ok(())
     }
 
@@ -297,7 +321,7 @@ impl DbTrait for DbSqlite {
         self.seed_file_groups(&self.group_id).await?;
         self.set_group_status(&self.group_id, &self.ym).await?;
         self.conn().execute(
-            "UPDATE `group_status` SET `status`='',`total_views`=null,`file`=null,`sqlite3`=null",
+            "UPDATE `group_status` SET `status`='',`total_views`=null,`usage_views`=null,`excluded_views`=null,`file`=null,`sqlite3`=null",
             (),
         )?;
         Ok(())
@@ -310,9 +334,14 @@ impl DbTrait for DbSqlite {
             .collect();
         let placeholders: Vec<String> = parts
             .iter()
-            .map(|part| format!("(`site`={} AND `title`=?)", part.site_id))
+            .map(|part| {
+                format!(
+                    "(`site`={} AND `namespace_id`={} AND `title`=?)",
+                    part.site_id, part.namespace_id
+                )
+            })
             .collect();
-        let sql = "SELECT id,site,title FROM `views` WHERE ".to_string()
+        let sql = "SELECT id,site,namespace_id,title FROM `views` WHERE ".to_string()
             + &placeholders.join(" OR ").to_string();
         let viewid_site_id_title: Vec<ViewIdSiteIdTitle> = self
             .conn()
@@ -339,3 +368,38 @@ impl DbTrait for DbSqlite {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_views_sql() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&std::fs::read_to_string("baglama.sqlite3_schema").unwrap())
+            .unwrap();
+        // Page 1 uses two files of the group
+        conn.execute_batch(
+            "INSERT INTO group_status (id,group_id,year,month) VALUES (3,1,2024,5);
+            INSERT INTO views (id,site,title,month,year,done,namespace_id,page_id,views,excluded) VALUES
+                (1,1,'Berlin',5,2024,1,0,11,100,0),(2,2,'Berlin',5,2024,1,0,12,10,0),
+                (3,2,'Main_Page',5,2024,1,0,1,5000,1);
+            INSERT INTO group2view (group_status_id,view_id,image) VALUES
+                (3,1,'A.jpg'),(3,1,'B.jpg'),(3,2,'B.jpg'),(3,3,'A.jpg');",
+        )
+        .unwrap();
+        conn.execute(SUMMARY_VIEWS_SQL, rusqlite::params![3])
+            .unwrap();
+        let (status, total_views, usage_views, excluded_views): (String, i64, i64, i64) = conn
+            .query_row(
+                "SELECT status,total_views,usage_views,excluded_views FROM group_status WHERE id=3",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(status, "VIEW DATA COMPLETE");
+        assert_eq!(total_views, 110);
+        assert_eq!(usage_views, 210);
+        assert_eq!(excluded_views, 5000);
+    }
+}
@@ -8,16 +8,24 @@ use std::sync::Arc;
 pub struct FilePart {
     pub id: Option<DbId>,
     pub site_id: DbId,
+    pub namespace_id: i32,
     pub page_title: String,
     pub page_id: DbId,
     pub file: String,
 }
 
 impl FilePart {
-    pub fn new(site_id: DbId, page_title: String, page_id: DbId, file: String) -> Self {
+    pub fn new(
+        site_id: DbId,
+        namespace_id: i32,
+        page_title: String,
+        page_id: DbId,
+        file: String,
+    ) -> Self {
         Self {
             id: None,
             site_id,
+            namespace_id,
             page_title,
             page_id,
             file,
@@ -29,14 +37,16 @@ impl FilePart {
 pub struct ViewIdSiteIdTitle {
     pub view_id: DbId,
     pub site_id: DbId,
+    pub namespace_id: i32,
     pub title: String,
 }
 
 impl ViewIdSiteIdTitle {
-    pub fn new(view_id: DbId, site_id: DbId, title: String) -> Self {
+    pub fn new(view_id: DbId, site_id: DbId, namespace_id: i32, title: String) -> Self {
         Self {
             view_id,
             site_id,
+            namespace_id,
             title,
         }
     }
@@ -47,7 +57,8 @@ impl ViewIdSiteIdTitle {
         Ok(Self {
             view_id: view_id as DbId,
             site_id: site_id as DbId,
-            title: row.get(2)?,
+            namespace_id: row.get(2)?,
+            title: row.get(3)?,
         })
     }
 }
@@ -58,7 +69,7 @@ impl FromRow for ViewIdSiteIdTitle {
         Self: Sized,
     {
         let title: Vec<u8> = row
-            .get(2)
+            .get(3)
             .ok_or_else(|| mysql_async::FromRowError(row.to_owned()))?;
         let title =
             String::from_utf8(title).map_err(|_| mysql_async::FromRowError(row.to_owned()))?;
@@ -67,6 +78,8 @@ impl FromRow for ViewIdSiteIdTitle {
                 .ok_or_else(|| mysql_async::FromRowError(row.to_owned()))?,
             row.get(1)
                 .ok_or_else(|| mysql_async::FromRowError(row.to_owned()))?,
+            row.get(2)
+                .ok_or_else(|| mysql_async::FromRowError(row.to_owned()))?,
             title,
         ))
     }
@@ -85,7 +98,7 @@ pub trait DbTrait {
     fn delete_views(&self) -> Result<()>;
     fn delete_group2view(&self) -> Result<()>;
     async fn load_files_batch(&self, offset: usize, batch_size: usize) -> Result<Vec<String>>;
-    async fn reset_main_page_view_count(&self) -> Result<()>;
+    async fn apply_page_exclusions(&self) -> Result<()>;
     async fn add_summary_statistics(&self, group_status_id: DbId) -> Result<()>;
     async fn update_view_count(&self, view_id: DbId, view_count: i64) -> Result<()>;
     async fn view_done(&self, view_id: DbId, done: u8) -> Result<()>;
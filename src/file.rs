@@ -4,17 +4,24 @@ use mysql_async::prelude::*;
 pub struct File {
     pub id: Option<DbId>,
     pub name: String,
+    /// The Commons page ID, which survives file moves
+    pub page_id: Option<DbId>,
 }
 
 impl File {
     pub fn new(id: Option<DbId>, name: String) -> Self {
-        File { id, name }
+        File {
+            id,
+            name,
+            page_id: None,
+        }
     }
 
     pub fn new_no_id(name: &str) -> Self {
         File {
             id: None,
             name: name.to_owned(),
+            page_id: None,
         }
     }
 }
@@ -30,10 +37,12 @@ impl FromRow for File {
         )
         .ok_or_else(|| mysql_async::FromRowError(row.clone()))?;
 
-        Ok(Self::new(
+        let mut ret = Self::new(
             row.get("id")
                 .ok_or_else(|| mysql_async::FromRowError(row.to_owned()))?,
             title,
-        ))
+        );
+        ret.page_id = row.get::<Option<DbId>, _>("page_id").flatten();
+        Ok(ret)
     }
 }
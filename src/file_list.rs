@@ -0,0 +1,262 @@
+//! Explicit file lists for groups, stored in the `group_files` tool DB table.
+//!
+//! Lists are imported from plain text (one file per line), or CSV/TSV (file
+//! name in the first column, optional header row); the format is given by the
+//! file extension or `--format=`. Names are normalised the way
+//! Commons does: `File:`/`Image:` prefix removed, underscores instead of
+//! spaces, and an uppercase first letter.
+
+use crate::{group_source::GroupSource, Baglama2, DbId};
+use anyhow::{anyhow, Result};
+use mysql_async::{from_row, prelude::*};
+use std::collections::HashSet;
+
+const VALIDATE_CHUNK_SIZE: usize = 1000;
+const INSERT_CHUNK_SIZE: usize = 1000;
+const HEADER_NAMES: &[&str] = &["file", "filename", "file name", "title", "name", "image"];
+
+#[derive(Debug, Clone, Default)]
+pub struct FileListImport {
+    pub stored: Vec<String>,
+    pub missing: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListFormat {
+    Text,
+    Csv,
+    Tsv,
+}
+
+impl ListFormat {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "text" | "txt" => Ok(Self::Text),
+            "csv" => Ok(Self::Csv),
+            "tsv" | "tab" => Ok(Self::Tsv),
+            other => Err(anyhow!("Unknown file list format '{other}'")),
+        }
+    }
+
+    /// The format for a file extension; anything but `.csv` and `.tsv` is text.
+    pub fn from_path(path: &str) -> Self {
+        std::path::Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| Self::parse(ext).ok())
+            .unwrap_or(Self::Text)
+    }
+
+    fn delimiter(&self) -> Option<u8> {
+        match self {
+            Self::Text => None,
+            Self::Csv => Some(b','),
+            Self::Tsv => Some(b'\t'),
+        }
+    }
+}
+
+pub struct FileList;
+
+impl FileList {
+    /// Parses a file list into unique, normalised file names.
+    pub fn parse(text: &str, format: ListFormat) -> Result<Vec<String>> {
+        let names: Vec<String> = match format.delimiter() {
+            Some(delimiter) => {
+                let mut reader = csv::ReaderBuilder::new()
+                    .delimiter(delimiter)
+                    .has_headers(false)
+                    .flexible(true)
+                    .from_reader(text.as_bytes());
+                let mut names = vec![];
+                for record in reader.records() {
+                    names.push(record?.get(0).unwrap_or_default().to_string());
+                }
+                names
+            }
+            None => text.lines().map(|line| line.to_string()).collect(),
+        };
+        let mut seen = HashSet::new();
+        Ok(names
+            .iter()
+            .enumerate()
+            .filter_map(|(num, name)| {
+                if num == 0 && Self::is_header(name) {
+                    return None;
+                }
+                Self::normalize(name)
+            })
+            .filter(|file| seen.insert(file.to_owned()))
+            .collect())
+    }
+
+    fn is_header(name: &str) -> bool {
+        HEADER_NAMES.contains(&name.trim().to_lowercase().as_str())
+    }
+
+    pub fn normalize(name: &str) -> Option<String> {
+        let name = name.trim();
+        let name = ["File:", "Image:"]
+            .iter()
+            .find_map(|prefix| {
+                name.get(..prefix.len())
+                    .filter(|start| start.eq_ignore_ascii_case(prefix))
+                    .map(|_| &name[prefix.len()..])
+            })
+            .unwrap_or(name);
+        let name = name
+            .split([' ', '_'])
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("_");
+        let mut chars = name.chars();
+        let first = chars.next()?;
+        Some(first.to_uppercase().chain(chars).collect())
+    }
+
+    /// Splits the files into those that exist on Commons, and those that do not.
+    /// Files should have their redirects resolved first, or moved files will
+    /// be reported as missing.
+    pub async fn validate(
+        baglama: &Baglama2,
+        files: &[String],
+    ) -> Result<(Vec<String>, Vec<String>)> {
+        let mut existing = HashSet::new();
+        let mut conn = baglama.get_commons_conn().await?;
+        for chunk in files.chunks(VALIDATE_CHUNK_SIZE) {
+            let placeholders = vec!["?"; chunk.len()].join(",");
+            let sql = format!(
+                "SELECT FROM_BASE64(TO_BASE64(img_name)) FROM image WHERE img_name IN ({placeholders})"
+            );
+            let found = conn
+                .exec_iter(sql, chunk.to_vec())
+                .await?
+                .map_and_drop(from_row::<String>)
+                .await?;
+            existing.extend(found);
+        }
+        Ok(files
+            .iter()
+            .cloned()
+            .partition(|file| existing.contains(file)))
+    }
+
+    /// Replaces the file list of a group with the existing files from the
+    /// given text, and adds the list to the sources of the group.
+    pub async fn import(
+        baglama: &Baglama2,
+        group_id: DbId,
+        text: &str,
+        format: ListFormat,
+    ) -> Result<FileListImport> {
+        let files = Self::parse(text, format)?;
+        let files = baglama.resolve_file_redirects(files).await?;
+        let (stored, missing) = Self::validate(baglama, &files).await?;
+        let mut conn = baglama.get_tooldb_conn().await?;
+        conn.exec_drop("DELETE FROM `group_files` WHERE `group_id`=?", (group_id,))
+            .await?;
+        for chunk in stored.chunks(INSERT_CHUNK_SIZE) {
+            let placeholders = vec!["(?,?)"; chunk.len()].join(",");
+            let sql = format!(
+                "INSERT IGNORE INTO `group_files` (`group_id`,`file`) VALUES {placeholders}"
+            );
+            let params: Vec<mysql_async::Value> = chunk
+                .iter()
+                .flat_map(|file| vec![group_id.into(), file.to_owned().into()])
+                .collect();
+            conn.exec_drop(sql, params).await?;
+        }
+        GroupSource::add_for_group(baglama, group_id, &GroupSource::FileList).await?;
+        Ok(FileListImport { stored, missing })
+    }
+
+    /// Returns the stored file list of a group.
+    pub async fn load(baglama: &Baglama2, group_id: DbId) -> Result<Vec<String>> {
+        let sql = "SELECT FROM_BASE64(TO_BASE64(`file`)) FROM `group_files` WHERE `group_id`=?";
+        let files = baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, (group_id,))
+            .await?
+            .map_and_drop(from_row::<String>)
+            .await?;
+        Ok(files)
+    }
+
+    /// Called from `DbMySql2::ensure_table_exists`.
+    pub async fn ensure_table_exists(baglama: &Baglama2) -> Result<()> {
+        let sql = "CREATE TABLE IF NOT EXISTS `group_files` (
+              `id` int(11) unsigned NOT NULL AUTO_INCREMENT,
+              `group_id` int(11) unsigned NOT NULL,
+              `file` varbinary(255) NOT NULL,
+              PRIMARY KEY (`id`),
+              UNIQUE KEY `group_file` (`group_id`,`file`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4";
+        baglama.get_tooldb_conn().await?.exec_drop(sql, ()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            FileList::normalize(" File:some  painting.jpg "),
+            Some("Some_painting.jpg".to_string())
+        );
+        assert_eq!(
+            FileList::normalize("image:Ölbild_1.png"),
+            Some("Ölbild_1.png".to_string())
+        );
+        assert_eq!(
+            FileList::normalize("Filet mignon.jpg"),
+            Some("Filet_mignon.jpg".to_string())
+        );
+        assert_eq!(FileList::normalize("  "), None);
+        assert_eq!(FileList::normalize("File:"), None);
+    }
+
+    #[test]
+    fn test_parse_text() {
+        let text = "File:A b.jpg\n\nA_b.jpg\nc.png\nD, view.jpg\n";
+        assert_eq!(
+            FileList::parse(text, ListFormat::Text).unwrap(),
+            vec![
+                "A_b.jpg".to_string(),
+                "C.png".to_string(),
+                "D,_view.jpg".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let text = "filename,creator\n\"Portrait, 1890.jpg\",Someone\nB.jpg,Other\nA.jpg,Some Museum Inc.\n\"Say \"\"Hi\"\".jpg\"\n";
+        assert_eq!(
+            FileList::parse(text, ListFormat::Csv).unwrap(),
+            vec![
+                "Portrait,_1890.jpg".to_string(),
+                "B.jpg".to_string(),
+                "A.jpg".to_string(),
+                "Say_\"Hi\".jpg".to_string(),
+            ]
+        );
+        let text = "file\tcreator\nC.tif\tx, y.\n";
+        assert_eq!(
+            FileList::parse(text, ListFormat::Tsv).unwrap(),
+            vec!["C.tif".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_list_format() {
+        assert_eq!(ListFormat::from_path("/tmp/list.CSV"), ListFormat::Csv);
+        assert_eq!(ListFormat::from_path("list.tsv"), ListFormat::Tsv);
+        assert_eq!(ListFormat::from_path("list.txt"), ListFormat::Text);
+        assert_eq!(ListFormat::from_path("list"), ListFormat::Text);
+        assert!(ListFormat::parse("xls").is_err());
+    }
+}
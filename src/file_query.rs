@@ -0,0 +1,394 @@
+//! Files for a group from a SPARQL query, e.g. against Structured Data on
+//! Commons ("collection (P195) = our museum").
+//!
+//! The query result may contain MediaInfo entities (`.../entity/M123`),
+//! `Special:FilePath` URLs, or plain `File:` titles; all are resolved to file
+//! names. Results are cached per group, query and month in the
+//! `group_query_files` tool DB table, and each query run is recorded in
+//! `group_query_runs`, so a month is only queried once, even if it returned
+//! no files.
+//!
+//! The query provider is chosen via the config: `sparql_fixture` (a local JSON
+//! file in SPARQL results format, for tests) takes precedence over
+//! `sparql_endpoint` (default: the Commons query service). `sparql_cookie` is
+//! sent along to endpoints requiring a login.
+
+use crate::{Baglama2, DbId, YearMonth};
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use mysql_async::{from_row, prelude::*};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+const DEFAULT_SPARQL_ENDPOINT: &str = "https://commons-query.wikimedia.org/sparql";
+const SPARQL_USER_AGENT: &str = "BaGLAMa2 (https://glamtools.toolforge.org/baglama2/)";
+const PAGE_ID_CHUNK_SIZE: usize = 1000;
+const CACHE_INSERT_CHUNK_SIZE: usize = 1000;
+/// Characters to encode in a form value; the unreserved ones are kept.
+const FORM_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryProvider {
+    Sparql {
+        endpoint: String,
+        cookie: Option<String>,
+    },
+    Fixture(PathBuf),
+}
+
+impl QueryProvider {
+    pub fn from_config(config: &Value) -> Self {
+        if let Some(path) = config["sparql_fixture"].as_str() {
+            return Self::Fixture(PathBuf::from(path));
+        }
+        Self::Sparql {
+            endpoint: config["sparql_endpoint"]
+                .as_str()
+                .unwrap_or(DEFAULT_SPARQL_ENDPOINT)
+                .to_string(),
+            cookie: config["sparql_cookie"].as_str().map(|s| s.to_string()),
+        }
+    }
+
+    /// Runs the query, and returns the result in SPARQL JSON results format.
+    pub async fn run(&self, query: &str) -> Result<Value> {
+        match self {
+            Self::Sparql { endpoint, cookie } => {
+                let mut request = reqwest::Client::builder()
+                    .user_agent(SPARQL_USER_AGENT)
+                    .build()?
+                    .post(endpoint)
+                    .header("Accept", "application/sparql-results+json")
+                    .header("Content-Type", "application/x-www-form-urlencoded")
+                    .body(format!("query={}", utf8_percent_encode(query, FORM_VALUE)));
+                if let Some(cookie) = cookie {
+                    request = request.header("Cookie", cookie);
+                }
+                let response = request.send().await?;
+                if !response.status().is_success() {
+                    return Err(anyhow!(
+                        "SPARQL query failed: HTTP {} from {endpoint}",
+                        response.status()
+                    ));
+                }
+                Ok(serde_json::from_str(&response.text().await?)?)
+            }
+            Self::Fixture(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| anyhow!("Could not read SPARQL fixture {path:?}: {e}"))?;
+                Ok(serde_json::from_str(&text)?)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryResultFile {
+    Title(String),
+    MediaInfo(DbId),
+}
+
+impl QueryResultFile {
+    /// Parses a single result value; anything that is not a file is ignored.
+    pub fn from_value(value: &str) -> Option<Self> {
+        if let Some((_, name)) = value.split_once("/Special:FilePath/") {
+            let name = percent_decode_str(name).decode_utf8_lossy();
+            return Some(Self::Title(name.replace(' ', "_")));
+        }
+        if let Some((_, entity)) = value.rsplit_once("/entity/") {
+            return entity
+                .strip_prefix('M')?
+                .parse::<DbId>()
+                .ok()
+                .map(Self::MediaInfo);
+        }
+        if value.contains("://") {
+            return None;
+        }
+        let name = value
+            .strip_prefix("File:")
+            .unwrap_or(value)
+            .trim()
+            .replace(' ', "_");
+        if name.is_empty() {
+            None
+        } else {
+            Some(Self::Title(name))
+        }
+    }
+
+    /// Parses SPARQL JSON results, using the first result variable.
+    pub fn from_sparql_results(json: &Value) -> Result<Vec<Self>> {
+        let var = json["head"]["vars"][0]
+            .as_str()
+            .ok_or_else(|| anyhow!("SPARQL result has no variables"))?;
+        let bindings = json["results"]["bindings"]
+            .as_array()
+            .ok_or_else(|| anyhow!("SPARQL result has no bindings"))?;
+        Ok(bindings
+            .iter()
+            .filter_map(|binding| binding[var]["value"].as_str())
+            .filter_map(Self::from_value)
+            .collect())
+    }
+}
+
+pub struct FileQuery<'a> {
+    baglama: &'a Baglama2,
+    provider: QueryProvider,
+}
+
+impl<'a> FileQuery<'a> {
+    pub fn new(baglama: &'a Baglama2) -> Self {
+        Self {
+            baglama,
+            provider: QueryProvider::from_config(baglama.config()),
+        }
+    }
+
+    /// Returns the files for a group query in a month, running the query only
+    /// if it was not run for that month yet.
+    pub async fn get_files(
+        &self,
+        group_id: DbId,
+        ym: &YearMonth,
+        query: &str,
+    ) -> Result<Vec<String>> {
+        let query_hash = Self::query_hash(query);
+        let cached = self.get_cached_files(group_id, &query_hash, ym).await?;
+        // Cached files without a run are from before runs were recorded
+        if !cached.is_empty() || self.was_run(group_id, &query_hash, ym).await? {
+            return Ok(cached);
+        }
+        let json = self.provider.run(query).await?;
+        let results = QueryResultFile::from_sparql_results(&json)?;
+        let files = self.resolve_files(results).await?;
+        info!(
+            "Query for group {group_id} returned {} files for {ym}",
+            files.len()
+        );
+        self.store_cached_files(group_id, &query_hash, ym, &files)
+            .await?;
+        Ok(files)
+    }
+
+    /// Identifies a query in the cache, as a group can have several.
+    fn query_hash(query: &str) -> String {
+        format!("{:x}", Sha256::digest(query.trim().as_bytes()))
+    }
+
+    /// Resolves MediaInfo IDs to file names, and de-duplicates.
+    async fn resolve_files(&self, results: Vec<QueryResultFile>) -> Result<Vec<String>> {
+        let mut files = vec![];
+        let mut page_ids = vec![];
+        for result in results {
+            match result {
+                QueryResultFile::Title(title) => files.push(title),
+                QueryResultFile::MediaInfo(page_id) => page_ids.push(page_id),
+            }
+        }
+        for chunk in page_ids.chunks(PAGE_ID_CHUNK_SIZE) {
+            let placeholders = vec!["?"; chunk.len()].join(",");
+            let sql = format!("SELECT FROM_BASE64(TO_BASE64(page_title)) FROM page WHERE page_namespace=6 AND page_id IN ({placeholders})");
+            let titles = self
+                .baglama
+                .get_commons_conn()
+                .await?
+                .exec_iter(sql, chunk.to_vec())
+                .await?
+                .map_and_drop(from_row::<String>)
+                .await?;
+            if titles.len() < chunk.len() {
+                warn!(
+                    "{} MediaInfo entities could not be resolved to files",
+                    chunk.len() - titles.len()
+                );
+            }
+            files.extend(titles);
+        }
+        let mut seen = HashSet::new();
+        files.retain(|file| seen.insert(file.to_owned()));
+        Ok(files)
+    }
+
+    async fn get_cached_files(
+        &self,
+        group_id: DbId,
+        query_hash: &str,
+        ym: &YearMonth,
+    ) -> Result<Vec<String>> {
+        let sql = "SELECT FROM_BASE64(TO_BASE64(`file`)) FROM `group_query_files` WHERE `group_id`=? AND `query_hash`=? AND `year`=? AND `month`=?";
+        let files = self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, (group_id, query_hash, ym.year(), ym.month()))
+            .await?
+            .map_and_drop(from_row::<String>)
+            .await?;
+        Ok(files)
+    }
+
+    async fn was_run(&self, group_id: DbId, query_hash: &str, ym: &YearMonth) -> Result<bool> {
+        let sql = "SELECT COUNT(*) FROM `group_query_runs` WHERE `group_id`=? AND `query_hash`=? AND `year`=? AND `month`=?";
+        let count: Option<usize> = self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_first(sql, (group_id, query_hash, ym.year(), ym.month()))
+            .await?;
+        Ok(count.unwrap_or_default() > 0)
+    }
+
+    /// Stores the files, and records the run once they are all stored.
+    async fn store_cached_files(
+        &self,
+        group_id: DbId,
+        query_hash: &str,
+        ym: &YearMonth,
+        files: &[String],
+    ) -> Result<()> {
+        let mut conn = self.baglama.get_tooldb_conn().await?;
+        for chunk in files.chunks(CACHE_INSERT_CHUNK_SIZE) {
+            let placeholders = vec!["(?,?,?,?,?)"; chunk.len()].join(",");
+            let sql = format!("INSERT IGNORE INTO `group_query_files` (`group_id`,`query_hash`,`year`,`month`,`file`) VALUES {placeholders}");
+            let params: Vec<mysql_async::Value> = chunk
+                .iter()
+                .flat_map(|file| {
+                    vec![
+                        group_id.into(),
+                        query_hash.into(),
+                        ym.year().into(),
+                        ym.month().into(),
+                        file.to_owned().into(),
+                    ]
+                })
+                .collect();
+            conn.exec_drop(sql, params).await?;
+        }
+        let sql = "INSERT IGNORE INTO `group_query_runs` (`group_id`,`query_hash`,`year`,`month`,`files`) VALUES (?,?,?,?,?)";
+        conn.exec_drop(
+            sql,
+            (group_id, query_hash, ym.year(), ym.month(), files.len()),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Called from `DbMySql2::ensure_table_exists`, which also migrates older tables.
+    pub async fn ensure_table_exists(baglama: &Baglama2) -> Result<()> {
+        let sql = "CREATE TABLE IF NOT EXISTS `group_query_files` (
+              `id` int(11) unsigned NOT NULL AUTO_INCREMENT,
+              `group_id` int(11) unsigned NOT NULL,
+              `query_hash` char(64) NOT NULL DEFAULT '',
+              `year` int(11) NOT NULL,
+              `month` int(11) NOT NULL,
+              `file` varbinary(255) NOT NULL,
+              PRIMARY KEY (`id`),
+              UNIQUE KEY `group_query_month_file` (`group_id`,`query_hash`,`year`,`month`,`file`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4";
+        let mut conn = baglama.get_tooldb_conn().await?;
+        conn.exec_drop(sql, ()).await?;
+        let sql = "CREATE TABLE IF NOT EXISTS `group_query_runs` (
+              `group_id` int(11) unsigned NOT NULL,
+              `query_hash` char(64) NOT NULL,
+              `year` int(11) NOT NULL,
+              `month` int(11) NOT NULL,
+              `files` int(11) unsigned NOT NULL,
+              `run_at` timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+              PRIMARY KEY (`group_id`,`query_hash`,`year`,`month`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=ascii";
+        conn.exec_drop(sql, ()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sparql_result() -> Value {
+        json!({
+            "head": {"vars": ["file"]},
+            "results": {"bindings": [
+                {"file": {"type": "uri", "value": "https://commons.wikimedia.org/entity/M12345"}},
+                {"file": {"type": "uri", "value": "http://commons.wikimedia.org/wiki/Special:FilePath/Mona%20Lisa%20%28detail%29.jpg"}},
+                {"file": {"type": "literal", "value": "File:Some painting.png"}},
+                {"file": {"type": "uri", "value": "http://www.wikidata.org/entity/Q42"}},
+            ]}
+        })
+    }
+
+    #[test]
+    fn test_from_sparql_results() {
+        let files = QueryResultFile::from_sparql_results(&sparql_result()).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                QueryResultFile::MediaInfo(12345),
+                QueryResultFile::Title("Mona_Lisa_(detail).jpg".to_string()),
+                QueryResultFile::Title("Some_painting.png".to_string()),
+            ]
+        );
+        assert!(QueryResultFile::from_sparql_results(&json!({})).is_err());
+    }
+
+    #[test]
+    fn test_percent_encoding() {
+        assert_eq!(
+            QueryResultFile::from_value("https://x/wiki/Special:FilePath/A%20b%C3%A4%2"),
+            Some(QueryResultFile::Title("A_bä%2".to_string()))
+        );
+        assert_eq!(
+            utf8_percent_encode("SELECT ?file { ?file wdt:P195 wd:Q1 }", FORM_VALUE).to_string(),
+            "SELECT%20%3Ffile%20%7B%20%3Ffile%20wdt%3AP195%20wd%3AQ1%20%7D"
+        );
+    }
+
+    #[test]
+    fn test_query_hash() {
+        let hash = FileQuery::query_hash("SELECT ?file { ?file wdt:P195 wd:Q1 }");
+        assert_eq!(hash.len(), 64);
+        assert_eq!(
+            hash,
+            FileQuery::query_hash(" SELECT ?file { ?file wdt:P195 wd:Q1 }\n")
+        );
+        assert_ne!(
+            hash,
+            FileQuery::query_hash("SELECT ?file { ?file wdt:P195 wd:Q2 }")
+        );
+    }
+
+    #[test]
+    fn test_provider_from_config() {
+        assert_eq!(
+            QueryProvider::from_config(&json!({"sparql_fixture": "/tmp/x.json"})),
+            QueryProvider::Fixture(PathBuf::from("/tmp/x.json"))
+        );
+        assert_eq!(
+            QueryProvider::from_config(&json!({})),
+            QueryProvider::Sparql {
+                endpoint: DEFAULT_SPARQL_ENDPOINT.to_string(),
+                cookie: None
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fixture_provider() {
+        let path = std::env::temp_dir().join("baglama2_test_sparql_fixture.json");
+        std::fs::write(&path, sparql_result().to_string()).unwrap();
+        let provider = QueryProvider::Fixture(path.clone());
+        let json = provider.run("ignored").await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(json, sparql_result());
+    }
+}
@@ -0,0 +1,226 @@
+//! Number of global usages per file and month, from `globalimagelinks`.
+//!
+//! Used by incremental page collection: a file of a group whose usages did
+//! not change since the previous month gets last month's pages copied,
+//! instead of loading its usages again. Besides the count, a checksum over
+//! the using pages catches usages that were replaced by others. Files of
+//! groups are still loaded in full with `--full`, which should be scheduled
+//! now and then, as incremental months build on each other.
+
+use crate::{global_image_links::GlobalImageLinks, Baglama2, DbId, YearMonth};
+use anyhow::Result;
+use mysql_async::{from_row, prelude::*, Conn};
+use std::collections::{HashMap, HashSet};
+
+const CHUNK_SIZE: usize = 5000;
+
+/// The usages of a file: their number, and the sum of the CRC32 of each
+/// using page, as `wiki:page_id:namespace_id:title`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsageCount {
+    pub usages: usize,
+    pub checksum: u64,
+}
+
+impl UsageCount {
+    pub fn new(usages: usize, checksum: u64) -> Self {
+        Self { usages, checksum }
+    }
+
+    /// The count of a single usage; must match `GlobalImageLinks::counts`.
+    pub fn of(gil: &GlobalImageLinks) -> Self {
+        let key = format!(
+            "{}:{}:{}:{}",
+            gil.wiki, gil.page, gil.page_namespace_id, gil.page_title
+        );
+        Self::new(1, crc32fast::hash(key.as_bytes()) as u64)
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.usages += other.usages;
+        self.checksum = self.checksum.wrapping_add(other.checksum);
+    }
+}
+
+pub struct FileUsageCounts;
+
+impl FileUsageCounts {
+    /// Usage counts per file, adding the usages of redirects (old name to
+    /// new name) to their target.
+    pub fn resolve<'a>(
+        counts: impl IntoIterator<Item = (&'a String, UsageCount)>,
+        redirects: &HashMap<String, String>,
+    ) -> HashMap<String, UsageCount> {
+        let mut ret: HashMap<String, UsageCount> = HashMap::new();
+        for (file, count) in counts {
+            let file = redirects.get(file).unwrap_or(file);
+            ret.entry(file.to_owned()).or_default().add(&count);
+        }
+        ret
+    }
+
+    /// The files (with ID) whose usage count and checksum are the same as last
+    /// month, and that had pages in the group last month. Files without any
+    /// usages have a count of 0.
+    pub fn unchanged_files(
+        files: &[String],
+        counts: &HashMap<String, UsageCount>,
+        file2id: &HashMap<String, DbId>,
+        previous_counts: &HashMap<DbId, UsageCount>,
+        previous_files: &HashSet<DbId>,
+    ) -> Vec<(String, DbId)> {
+        files
+            .iter()
+            .filter_map(|file| {
+                let id = *file2id.get(file)?;
+                let count = counts.get(file).copied().unwrap_or_default();
+                if previous_files.contains(&id) && previous_counts.get(&id) == Some(&count) {
+                    Some((file.to_owned(), id))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Usage counts of a month; counts saved without a checksum are left out.
+    pub async fn load(
+        conn: &mut Conn,
+        ym: &YearMonth,
+        ids: &[DbId],
+    ) -> Result<HashMap<DbId, UsageCount>> {
+        let mut ret = HashMap::new();
+        for chunk in ids.chunks(CHUNK_SIZE) {
+            let placeholders = Baglama2::sql_placeholders(chunk.len());
+            let sql = format!(
+                "SELECT `files_id`,`usages`,`checksum` FROM `file_usage_counts` WHERE `year`=? AND `month`=? AND `checksum` IS NOT NULL AND `files_id` IN ({placeholders})"
+            );
+            let mut params: Vec<mysql_async::Value> = vec![ym.year().into(), ym.month().into()];
+            params.extend(chunk.iter().map(|id| (*id).into()));
+            ret.extend(
+                conn.exec_iter(sql, params)
+                    .await?
+                    .map_and_drop(from_row::<(DbId, usize, u64)>)
+                    .await?
+                    .into_iter()
+                    .map(|(id, usages, checksum)| (id, UsageCount::new(usages, checksum))),
+            );
+        }
+        Ok(ret)
+    }
+
+    pub async fn save(
+        conn: &mut Conn,
+        ym: &YearMonth,
+        counts: &[(DbId, UsageCount)],
+    ) -> Result<()> {
+        for chunk in counts.chunks(CHUNK_SIZE) {
+            let placeholders = vec!["(?,?,?,?,?)"; chunk.len()].join(",");
+            let sql = format!(
+                "REPLACE INTO `file_usage_counts` (`year`,`month`,`files_id`,`usages`,`checksum`) VALUES {placeholders}"
+            );
+            let params: Vec<mysql_async::Value> = chunk
+                .iter()
+                .flat_map(|(id, count)| {
+                    [
+                        ym.year().into(),
+                        ym.month().into(),
+                        (*id).into(),
+                        count.usages.into(),
+                        count.checksum.into(),
+                    ]
+                })
+                .collect();
+            conn.exec_drop(sql, params).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let counts = [
+            ("A.jpg".to_string(), UsageCount::new(3, 30)),
+            ("Old_A.jpg".to_string(), UsageCount::new(2, 20)),
+            ("B.jpg".to_string(), UsageCount::new(1, 10)),
+        ];
+        let redirects = [("Old_A.jpg".to_string(), "A.jpg".to_string())]
+            .into_iter()
+            .collect();
+        let ret = FileUsageCounts::resolve(counts.iter().map(|(f, c)| (f, *c)), &redirects);
+        assert_eq!(ret.len(), 2);
+        assert_eq!(ret["A.jpg"], UsageCount::new(5, 50));
+        assert_eq!(ret["B.jpg"], UsageCount::new(1, 10));
+    }
+
+    #[test]
+    fn test_usage_count_of() {
+        let gil = GlobalImageLinks {
+            wiki: "enwiki".to_string(),
+            page: 12345,
+            page_namespace_id: 0,
+            page_title: "Foo_Bar".to_string(),
+            to: "A.jpg".to_string(),
+        };
+        // MySQL: SELECT CRC32('enwiki:12345:0:Foo_Bar')
+        let expected = crc32fast::hash(b"enwiki:12345:0:Foo_Bar") as u64;
+        assert_eq!(UsageCount::of(&gil), UsageCount::new(1, expected));
+    }
+
+    #[test]
+    fn test_unchanged_files() {
+        let files: Vec<String> = ["A.jpg", "B.jpg", "C.jpg", "D.jpg", "E.jpg"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let counts = [
+            ("A.jpg".to_string(), UsageCount::new(5, 50)),
+            ("B.jpg".to_string(), UsageCount::new(2, 20)),
+            ("D.jpg".to_string(), UsageCount::new(1, 11)),
+        ]
+        .into_iter()
+        .collect();
+        // E.jpg is new, it has no ID
+        let file2id = [("A.jpg", 1), ("B.jpg", 2), ("C.jpg", 3), ("D.jpg", 4)]
+            .into_iter()
+            .map(|(f, id)| (f.to_string(), id))
+            .collect();
+        // B.jpg has one usage more than last month; D.jpg has the same number
+        // of usages, but on other pages
+        let previous_counts = [
+            (1, UsageCount::new(5, 50)),
+            (2, UsageCount::new(1, 10)),
+            (3, UsageCount::default()),
+            (4, UsageCount::new(1, 10)),
+        ]
+        .into_iter()
+        .collect();
+        let previous_files = [1, 2, 3, 4].into_iter().collect();
+        assert_eq!(
+            FileUsageCounts::unchanged_files(
+                &files,
+                &counts,
+                &file2id,
+                &previous_counts,
+                &previous_files
+            ),
+            vec![("A.jpg".to_string(), 1), ("C.jpg".to_string(), 3)]
+        );
+        // Files that were not in the group last month are loaded again
+        let previous_files = [3].into_iter().collect();
+        assert_eq!(
+            FileUsageCounts::unchanged_files(
+                &files,
+                &counts,
+                &file2id,
+                &previous_counts,
+                &previous_files
+            ),
+            vec![("C.jpg".to_string(), 3)]
+        );
+    }
+}
@@ -1,7 +1,8 @@
-use crate::Baglama2;
+use crate::{file_usage_counts::UsageCount, Baglama2};
 use anyhow::Result;
 use mysql_async::from_row;
 use mysql_async::prelude::*;
+use std::collections::HashMap;
 
 #[derive(Debug, Clone)]
 pub struct GlobalImageLinks {
@@ -20,7 +21,36 @@ impl GlobalImageLinks {
         }
         let placeholders = Baglama2::sql_placeholders(files.len());
         let sql = format!("SELECT gil_wiki,gil_page,gil_page_namespace_id,gil_page_namespace,FROM_BASE64(TO_BASE64(gil_page_title)),FROM_BASE64(TO_BASE64(gil_to)) FROM `globalimagelinks` WHERE `gil_to` IN ({})",&placeholders);
+        Self::query(&sql, files, baglama, "GlobalImageLinks::load").await
+    }
+
+    /// Usage count and checksum per file; files without usages are left out.
+    /// Much cheaper than loading the usages themselves.
+    /// The checksum must match `UsageCount::of`.
+    pub async fn counts(
+        files: &[String],
+        baglama: &Baglama2,
+    ) -> Result<HashMap<String, UsageCount>> {
+        if files.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let placeholders = Baglama2::sql_placeholders(files.len());
+        let sql = format!("SELECT FROM_BASE64(TO_BASE64(gil_to)),COUNT(*),SUM(CRC32(CONCAT_WS(':',gil_wiki,gil_page,gil_page_namespace_id,gil_page_title))) FROM `globalimagelinks` WHERE `gil_to` IN ({placeholders}) GROUP BY gil_to");
+        let rows: Vec<(String, usize, u64)> =
+            Self::query(&sql, files, baglama, "GlobalImageLinks::counts").await?;
+        Ok(rows
+            .into_iter()
+            .map(|(file, usages, checksum)| (file, UsageCount::new(usages, checksum)))
+            .collect())
+    }
 
+    /// Runs a query on the Commons replica, retrying on errors.
+    async fn query<T: FromRow + Send + 'static>(
+        sql: &str,
+        files: &[String],
+        baglama: &Baglama2,
+        label: &str,
+    ) -> Result<Vec<T>> {
         let max_attempts = 5;
         let mut last_error: Option<String> = None;
         for attempt in 0..max_attempts {
@@ -34,7 +64,7 @@ impl GlobalImageLinks {
                     continue;
                 }
             };
-            let res = match mysql_commons_conn.exec_iter(&sql, files.to_owned()).await {
+            let res = match mysql_commons_conn.exec_iter(sql, files.to_owned()).await {
                 Ok(res) => res,
                 Err(e) => {
                     last_error = Some(format!("Query error: {e}"));
@@ -42,7 +72,7 @@ impl GlobalImageLinks {
                     continue;
                 }
             };
-            match res.map_and_drop(from_row::<GlobalImageLinks>).await {
+            match res.map_and_drop(from_row::<T>).await {
                 Ok(ret) => return Ok(ret),
                 Err(e) => {
                     last_error = Some(format!("Mapping error: {e}"));
@@ -52,7 +82,7 @@ impl GlobalImageLinks {
             }
         }
         Err(anyhow::anyhow!(
-            "GlobalImageLinks::load failed after {max_attempts} attempts. Last error: {}",
+            "{label} failed after {max_attempts} attempts. Last error: {}",
             last_error.unwrap_or_else(|| "Unknown".to_string())
         ))
     }
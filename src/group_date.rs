@@ -7,6 +7,8 @@ use crate::db_mysql2::DbMySql2 as DatabaseType;
 use crate::db_trait::DbTrait;
 use crate::db_trait::FilePart;
 use crate::global_image_links::GlobalImageLinks;
+use crate::group_exclusions::GroupExclusions;
+use crate::group_source::GroupSource;
 use crate::GroupId;
 use crate::Site;
 use crate::ViewCount;
@@ -100,18 +102,6 @@ impl GroupDate {
         self.sites.get(site_id)
     }
 
-    async fn get_files_from_user_name(&self, user_name: &str) -> Result<Vec<String>> {
-        self.baglama.get_files_from_user_name(user_name).await
-    }
-
-    async fn get_files_from_commons_category_tree(
-        &self,
-        category: &str,
-        depth: isize,
-    ) -> Result<Vec<String>> {
-        self.baglama.get_pages_in_category(category, depth, 6).await
-    }
-
     pub async fn add_files(&self, db: &DatabaseType) -> Result<()> {
         let group = self
             .baglama
@@ -120,19 +110,10 @@ impl GroupDate {
             .ok_or_else(|| anyhow!("Could not find group {} in MySQL database", self.group_id))?;
         debug!("{group:?}");
         db.delete_all_files().await?;
+        let exclusions = GroupExclusions::load(&self.baglama, self.group_id.get()).await?;
 
-        // Get files in category tree from Commons
-        debug!(
-            "Getting files from {}, depth {}",
-            group.category(),
-            group.depth()
-        );
-        let files = if group.is_user_name() {
-            self.get_files_from_user_name(group.category()).await?
-        } else {
-            self.get_files_from_commons_category_tree(group.category(), group.depth())
-                .await?
-        };
+        let files =
+            GroupSource::files_for_group(&self.baglama, &group, &self.ym, &exclusions).await?;
         if files.len() < 5 {
             warn!(
                 "{} / {} has {} files",
@@ -210,7 +191,13 @@ impl GroupDate {
                 let sql_value =
                     format!("({site_id},?,{month},{year},{done},{namespace_id},{page_id},{views})");
                 sql_values.push(sql_value);
-                let part = FilePart::new(site_id, title.to_owned(), page_id, gil.to.to_owned());
+                let part = FilePart::new(
+                    site_id,
+                    namespace_id,
+                    title.to_owned(),
+                    page_id,
+                    gil.to.to_owned(),
+                );
                 parts.push(part);
             }
             debug!(
@@ -276,10 +263,6 @@ impl GroupDate {
         let first_day = self.ym.first_day()?;
         let last_day = self.ym.last_day()?;
 
-        // Hide Main Page from view count
-        db.reset_main_page_view_count().await?;
-        debug!("main page view count reset");
-
         let batch_size = ADD_VIEW_COUNTS_BATCH_SIZE;
         let mut found = true;
         while found {
@@ -304,6 +287,8 @@ impl GroupDate {
             }
         }
 
+        debug!("add_view_counts: applying page exclusions");
+        db.apply_page_exclusions().await?;
         debug!("add_view_counts: adding summary statistics");
         self.add_summary_statistics(db).await?;
         Ok(())
@@ -342,16 +327,16 @@ impl GroupDate {
         let viewid_site_id_title = db.get_viewid_site_id_title(&parts).await?;
         debug!("add_views_batch_for_files: viewid_site_id_title={viewid_site_id_title:?}");
 
-        let siteid_title_viewid: HashMap<(usize, String), usize> = viewid_site_id_title
+        let siteid_title_viewid: HashMap<(usize, i32, String), usize> = viewid_site_id_title
             .into_iter()
-            .map(|x| ((x.site_id, x.title.to_owned()), x.view_id))
+            .map(|x| ((x.site_id, x.namespace_id, x.title.to_owned()), x.view_id))
             .collect();
         debug!("D: {siteid_title_viewid:?}");
         let mut values = vec![];
         let mut images = vec![];
         for part in &parts {
-            let view_id = match siteid_title_viewid.get(&(part.site_id, part.page_title.to_owned()))
-            {
+            let key = (part.site_id, part.namespace_id, part.page_title.to_owned());
+            let view_id = match siteid_title_viewid.get(&key) {
                 Some(id) => id,
                 None => {
                     debug!("{}/{} not found, odd", part.site_id, part.page_title);
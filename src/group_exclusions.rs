@@ -0,0 +1,365 @@
+//! Per-group exclusion rules, stored in the `group_exclusions` tool DB table.
+//!
+//! An excluded category is skipped, together with its subtree, when walking
+//! a group's category tree. Excluded file patterns are matched against file
+//! names (with underscores); `*` matches any run of characters, `?` a single
+//! character. Excluded pages (see `page_exclusions`) keep their views out of
+//! the group totals.
+
+use crate::{page_exclusions::ExcludedPage, Baglama2, DbId};
+use anyhow::{anyhow, Result};
+use mysql_async::{from_row, prelude::*};
+use regex::Regex;
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExclusionType {
+    Category,
+    FilePattern,
+    Page,
+}
+
+impl ExclusionType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExclusionType::Category => "category",
+            ExclusionType::FilePattern => "file",
+            ExclusionType::Page => "page",
+        }
+    }
+}
+
+impl TryFrom<&str> for ExclusionType {
+    type Error = &'static str;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "category" => Ok(ExclusionType::Category),
+            "file" => Ok(ExclusionType::FilePattern),
+            "page" => Ok(ExclusionType::Page),
+            _ => Err("Invalid exclusion type!"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GroupExclusions {
+    categories: HashSet<String>,
+    file_patterns: Vec<Regex>,
+}
+
+impl GroupExclusions {
+    pub fn new(categories: &[String], file_patterns: &[String]) -> Result<Self> {
+        Ok(Self {
+            categories: categories
+                .iter()
+                .map(|category| Self::normalize_category(category))
+                .collect(),
+            file_patterns: file_patterns
+                .iter()
+                .map(|pattern| Self::pattern_to_regex(pattern))
+                .collect::<Result<Vec<_>>>()?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.categories.is_empty() && self.file_patterns.is_empty()
+    }
+
+    pub fn excludes_category(&self, category: &str) -> bool {
+        !self.categories.is_empty()
+            && self
+                .categories
+                .contains(&Self::normalize_category(category))
+    }
+
+    pub fn excludes_file(&self, file: &str) -> bool {
+        if self.file_patterns.is_empty() {
+            return false;
+        }
+        let file = Self::normalize_file(file);
+        self.file_patterns.iter().any(|re| re.is_match(&file))
+    }
+
+    /// Removes excluded files from the list.
+    pub fn filter_files(&self, files: Vec<String>) -> Vec<String> {
+        if self.file_patterns.is_empty() {
+            return files;
+        }
+        files
+            .into_iter()
+            .filter(|file| !self.excludes_file(file))
+            .collect()
+    }
+
+    /// A category as in the category tree: without namespace prefix, with
+    /// underscores, and an uppercase first letter.
+    fn normalize_category(category: &str) -> String {
+        Self::normalize_title(category, &["Category:"])
+    }
+
+    /// A file name (or pattern) as on Commons, like `FileList::normalize`.
+    fn normalize_file(file: &str) -> String {
+        Self::normalize_title(file, &["File:", "Image:"])
+    }
+
+    fn normalize_title(title: &str, prefixes: &[&str]) -> String {
+        let title = title.trim();
+        let title = prefixes
+            .iter()
+            .find_map(|prefix| {
+                title
+                    .get(..prefix.len())
+                    .filter(|start| start.eq_ignore_ascii_case(prefix))
+                    .map(|_| &title[prefix.len()..])
+            })
+            .unwrap_or(title);
+        let title = title.trim().replace(' ', "_");
+        let mut chars = title.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => title,
+        }
+    }
+
+    /// The value of a rule as it is stored.
+    fn normalize_value(exclusion_type: ExclusionType, value: &str) -> String {
+        match exclusion_type {
+            ExclusionType::Category => Self::normalize_category(value),
+            ExclusionType::FilePattern => Self::normalize_file(value),
+            ExclusionType::Page => value.trim().replace(' ', "_"),
+        }
+    }
+
+    fn pattern_to_regex(pattern: &str) -> Result<Regex> {
+        let pattern = Self::normalize_file(pattern);
+        let mut re = "^".to_string();
+        for c in pattern.chars() {
+            match c {
+                '*' => re += ".*",
+                '?' => re.push('.'),
+                c => re += &regex::escape(&c.to_string()),
+            }
+        }
+        re.push('$');
+        Regex::new(&re).map_err(|e| anyhow!("Bad file pattern '{pattern}': {e}"))
+    }
+
+    /// Loads the exclusion rules for a group.
+    pub async fn load(baglama: &Baglama2, group_id: DbId) -> Result<Self> {
+        let rows = Self::list(baglama, group_id).await?;
+        let of_type = |t: ExclusionType| -> Vec<String> {
+            rows.iter()
+                .filter(|(rt, _)| *rt == t)
+                .map(|(_, value)| value.to_owned())
+                .collect()
+        };
+        Self::new(
+            &of_type(ExclusionType::Category),
+            &of_type(ExclusionType::FilePattern),
+        )
+    }
+
+    /// Lists the raw exclusion rules for a group.
+    pub async fn list(baglama: &Baglama2, group_id: DbId) -> Result<Vec<(ExclusionType, String)>> {
+        let sql = "SELECT `type`,FROM_BASE64(TO_BASE64(`value`)) FROM `group_exclusions` WHERE `group_id`=?";
+        let rows = baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, (group_id,))
+            .await?
+            .map_and_drop(from_row::<(String, String)>)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|(t, value)| Some((ExclusionType::try_from(t.as_str()).ok()?, value)))
+            .collect())
+    }
+
+    /// Lists the raw exclusion rules of one type, for all groups.
+    pub async fn list_type(
+        baglama: &Baglama2,
+        exclusion_type: ExclusionType,
+    ) -> Result<Vec<(DbId, String)>> {
+        let sql = "SELECT `group_id`,FROM_BASE64(TO_BASE64(`value`)) FROM `group_exclusions` WHERE `type`=?";
+        let rows = baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, (exclusion_type.as_str(),))
+            .await?
+            .map_and_drop(from_row::<(DbId, String)>)
+            .await?;
+        Ok(rows)
+    }
+
+    pub async fn add(
+        baglama: &Baglama2,
+        group_id: DbId,
+        exclusion_type: ExclusionType,
+        value: &str,
+    ) -> Result<()> {
+        match exclusion_type {
+            ExclusionType::FilePattern => {
+                Self::pattern_to_regex(value)?;
+            }
+            ExclusionType::Page => {
+                ExcludedPage::parse(value)?;
+            }
+            ExclusionType::Category => {}
+        }
+        let sql =
+            "INSERT IGNORE INTO `group_exclusions` (`group_id`,`type`,`value`) VALUES (?,?,?)";
+        baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_drop(
+                sql,
+                (
+                    group_id,
+                    exclusion_type.as_str(),
+                    Self::normalize_value(exclusion_type, value),
+                ),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Removes a rule; rules stored before values were normalized are matched as given.
+    pub async fn remove(
+        baglama: &Baglama2,
+        group_id: DbId,
+        exclusion_type: ExclusionType,
+        value: &str,
+    ) -> Result<()> {
+        let sql =
+            "DELETE FROM `group_exclusions` WHERE `group_id`=? AND `type`=? AND `value` IN (?,?)";
+        baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_drop(
+                sql,
+                (
+                    group_id,
+                    exclusion_type.as_str(),
+                    Self::normalize_value(exclusion_type, value),
+                    value.trim().replace(' ', "_"),
+                ),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Called from `DbMySql2::ensure_table_exists`.
+    pub async fn ensure_table_exists(baglama: &Baglama2) -> Result<()> {
+        let sql = "CREATE TABLE IF NOT EXISTS `group_exclusions` (
+              `id` int(11) unsigned NOT NULL AUTO_INCREMENT,
+              `group_id` int(11) unsigned NOT NULL,
+              `type` varchar(16) NOT NULL,
+              `value` varbinary(255) NOT NULL,
+              PRIMARY KEY (`id`),
+              UNIQUE KEY `group_type_value` (`group_id`,`type`,`value`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4";
+        baglama.get_tooldb_conn().await?.exec_drop(sql, ()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_excludes_category() {
+        let ex = GroupExclusions::new(&["Maintenance categories".to_string()], &[]).unwrap();
+        assert!(ex.excludes_category("Maintenance_categories"));
+        assert!(ex.excludes_category("Maintenance categories"));
+        assert!(!ex.excludes_category("Maintenance"));
+        assert!(!GroupExclusions::default().excludes_category("Anything"));
+    }
+
+    #[test]
+    fn test_category_prefix_and_case() {
+        let ex = GroupExclusions::new(
+            &[
+                "Category:Maintenance".to_string(),
+                "hidden cats".to_string(),
+            ],
+            &[],
+        )
+        .unwrap();
+        assert!(ex.excludes_category("Maintenance"));
+        assert!(ex.excludes_category("Hidden_cats"));
+        assert!(!ex.excludes_category("Category:Other"));
+        let ex = GroupExclusions::new(&[" category: maintenance ".to_string()], &[]).unwrap();
+        assert!(ex.excludes_category("Maintenance"));
+    }
+
+    #[test]
+    fn test_file_pattern_prefix_and_case() {
+        let patterns = vec!["File:*.pdf".to_string(), "logo of ?.svg".to_string()];
+        let ex = GroupExclusions::new(&[], &patterns).unwrap();
+        assert!(ex.excludes_file("Some document.pdf"));
+        assert!(ex.excludes_file("File:Report.pdf"));
+        assert!(ex.excludes_file("Logo_of_X.svg"));
+        assert!(!ex.excludes_file("Photo.jpg"));
+    }
+
+    #[test]
+    fn test_normalize_value() {
+        assert_eq!(
+            GroupExclusions::normalize_value(ExclusionType::Category, "Category:maintenance cats"),
+            "Maintenance_cats"
+        );
+        assert_eq!(
+            GroupExclusions::normalize_value(ExclusionType::FilePattern, "image:*.pdf"),
+            "*.pdf"
+        );
+        // Page rules start with the server, which must not be changed
+        assert_eq!(
+            GroupExclusions::normalize_value(ExclusionType::Page, "de.wikipedia.org:4:Haupt seite"),
+            "de.wikipedia.org:4:Haupt_seite"
+        );
+    }
+
+    #[test]
+    fn test_file_patterns() {
+        let patterns = vec!["*.pdf".to_string(), "Logo of ?.svg".to_string()];
+        let ex = GroupExclusions::new(&[], &patterns).unwrap();
+        assert!(ex.excludes_file("Some document.pdf"));
+        assert!(ex.excludes_file("Logo_of_X.svg"));
+        assert!(!ex.excludes_file("Logo_of_XY.svg"));
+        assert!(!ex.excludes_file("Photo.jpg"));
+        // Regex metacharacters in patterns are taken literally
+        let ex = GroupExclusions::new(&[], &["File (1).jpg".to_string()]).unwrap();
+        assert!(ex.excludes_file("File_(1).jpg"));
+        assert!(!ex.excludes_file("File_1.jpg"));
+    }
+
+    #[test]
+    fn test_filter_files() {
+        let ex = GroupExclusions::new(&[], &["*_sibling_*".to_string()]).unwrap();
+        let files = vec![
+            "A.jpg".to_string(),
+            "From_sibling_museum.jpg".to_string(),
+            "B.jpg".to_string(),
+        ];
+        assert_eq!(
+            ex.filter_files(files),
+            vec!["A.jpg".to_string(), "B.jpg".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_exclusion_type() {
+        assert_eq!(
+            ExclusionType::try_from("category"),
+            Ok(ExclusionType::Category)
+        );
+        assert_eq!(
+            ExclusionType::try_from("file"),
+            Ok(ExclusionType::FilePattern)
+        );
+        assert_eq!(ExclusionType::try_from("page"), Ok(ExclusionType::Page));
+        assert!(ExclusionType::try_from("user").is_err());
+    }
+}
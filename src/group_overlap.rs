@@ -0,0 +1,202 @@
+//! Overlap between groups in a month: files and pages they share, and the
+//! views of the union of their pages versus the sum of their views.
+//!
+//! Works on the `files_id`/`pages_id` columns of the mysql2 viewdata table.
+
+use crate::{
+    db_mysql2::DbMySql2, row_group_status::StorageType, Baglama2, DbId, GroupId, YearMonth,
+};
+use anyhow::{anyhow, Result};
+use mysql_async::{from_row, prelude::*};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+/// (files_id, pages_id, page_views) rows of one group.
+pub type GroupUsages = Vec<(DbId, DbId, u64)>;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GroupSets {
+    pub group_id: DbId,
+    pub files: HashSet<DbId>,
+    pub pages: HashMap<DbId, u64>,
+}
+
+impl GroupSets {
+    pub fn new(group_id: DbId, usages: &GroupUsages) -> Self {
+        let mut ret = Self {
+            group_id,
+            ..Default::default()
+        };
+        for (files_id, pages_id, views) in usages {
+            ret.files.insert(*files_id);
+            ret.pages.insert(*pages_id, *views);
+        }
+        ret
+    }
+
+    pub fn views(&self) -> u64 {
+        self.pages.values().sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PairOverlap {
+    pub group_ids: (DbId, DbId),
+    pub shared_files: usize,
+    pub shared_pages: usize,
+    pub shared_views: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupOverlap {
+    pub ym: YearMonth,
+    pub groups: Vec<GroupSets>,
+    pub pairs: Vec<PairOverlap>,
+    pub union_files: usize,
+    pub union_pages: usize,
+    /// Views of all distinct pages across the groups.
+    pub union_views: u64,
+    /// Sum of the views of each group, counting shared pages repeatedly.
+    pub sum_views: u64,
+    /// Files and pages in more than one group.
+    pub shared_files: usize,
+    pub shared_pages: usize,
+}
+
+impl GroupOverlap {
+    pub fn compute(ym: YearMonth, groups: Vec<GroupSets>) -> Self {
+        let mut pairs = vec![];
+        for (i, a) in groups.iter().enumerate() {
+            for b in groups.iter().skip(i + 1) {
+                let shared_pages: Vec<&DbId> =
+                    a.pages.keys().filter(|p| b.pages.contains_key(p)).collect();
+                pairs.push(PairOverlap {
+                    group_ids: (a.group_id, b.group_id),
+                    shared_files: a.files.intersection(&b.files).count(),
+                    shared_pages: shared_pages.len(),
+                    shared_views: shared_pages.iter().map(|p| a.pages[p]).sum(),
+                });
+            }
+        }
+
+        let mut file_groups: HashMap<DbId, usize> = HashMap::new();
+        let mut page_groups: HashMap<DbId, (usize, u64)> = HashMap::new();
+        for group in &groups {
+            for files_id in &group.files {
+                *file_groups.entry(*files_id).or_default() += 1;
+            }
+            for (pages_id, views) in &group.pages {
+                let entry = page_groups.entry(*pages_id).or_insert((0, *views));
+                entry.0 += 1;
+            }
+        }
+        Self {
+            ym,
+            pairs,
+            union_files: file_groups.len(),
+            union_pages: page_groups.len(),
+            union_views: page_groups.values().map(|(_, views)| views).sum(),
+            sum_views: groups.iter().map(|g| g.views()).sum(),
+            shared_files: file_groups.values().filter(|n| **n > 1).count(),
+            shared_pages: page_groups.values().filter(|(n, _)| *n > 1).count(),
+            groups,
+        }
+    }
+
+    pub async fn load(baglama: &Baglama2, group_ids: &[GroupId], ym: &YearMonth) -> Result<Self> {
+        let table = DbMySql2::viewdata_table(ym);
+        let mut conn = baglama.get_tooldb_conn().await?;
+        let mut groups = vec![];
+        for group_id in group_ids {
+            let gs = baglama
+                .get_group_status(group_id, ym)
+                .await?
+                .ok_or_else(|| anyhow!("No status for group {group_id} in {ym}"))?;
+            if gs.storage != StorageType::Mysql2 {
+                return Err(anyhow!(
+                    "Group {group_id} uses storage {:?} in {ym}, not mysql2",
+                    gs.storage
+                ));
+            }
+            let sql = format!("SELECT files_id,pages_id,COALESCE(page_views,0) FROM `{table}` WHERE group_status_id=? AND excluded=0");
+            let usages: GroupUsages = conn
+                .exec_iter(sql, (gs.id,))
+                .await?
+                .map_and_drop(from_row::<(DbId, DbId, u64)>)
+                .await?;
+            groups.push(GroupSets::new(group_id.get(), &usages));
+        }
+        Ok(Self::compute(*ym, groups))
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "month": self.ym.to_string(),
+            "groups": self.groups.iter().map(|g| json!({
+                "group_id": g.group_id,
+                "files": g.files.len(),
+                "pages": g.pages.len(),
+                "views": g.views(),
+            })).collect::<Vec<_>>(),
+            "pairs": self.pairs.iter().map(|p| json!({
+                "group_ids": [p.group_ids.0, p.group_ids.1],
+                "shared_files": p.shared_files,
+                "shared_pages": p.shared_pages,
+                "shared_views": p.shared_views,
+            })).collect::<Vec<_>>(),
+            "union_files": self.union_files,
+            "union_pages": self.union_pages,
+            "union_views": self.union_views,
+            "sum_views": self.sum_views,
+            "double_counted_views": self.sum_views - self.union_views,
+            "shared_files": self.shared_files,
+            "shared_pages": self.shared_pages,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute() {
+        // Group 1 contains group 2; group 3 shares one page with group 1 via another file
+        let groups = vec![
+            GroupSets::new(1, &vec![(10, 100, 50), (11, 101, 20), (12, 102, 5)]),
+            GroupSets::new(2, &vec![(10, 100, 50), (11, 101, 20)]),
+            GroupSets::new(3, &vec![(13, 102, 5), (14, 103, 1)]),
+        ];
+        let overlap = GroupOverlap::compute(YearMonth::new(2024, 1).unwrap(), groups);
+        assert_eq!(overlap.union_files, 5);
+        assert_eq!(overlap.union_pages, 4);
+        assert_eq!(overlap.union_views, 76);
+        assert_eq!(overlap.sum_views, 75 + 70 + 6);
+        assert_eq!(overlap.shared_files, 2);
+        assert_eq!(overlap.shared_pages, 3);
+        assert_eq!(
+            overlap.pairs,
+            vec![
+                PairOverlap {
+                    group_ids: (1, 2),
+                    shared_files: 2,
+                    shared_pages: 2,
+                    shared_views: 70,
+                },
+                PairOverlap {
+                    group_ids: (1, 3),
+                    shared_files: 0,
+                    shared_pages: 1,
+                    shared_views: 5,
+                },
+                PairOverlap {
+                    group_ids: (2, 3),
+                    shared_files: 0,
+                    shared_pages: 0,
+                    shared_views: 0,
+                },
+            ]
+        );
+        assert_eq!(overlap.to_json()["double_counted_views"], 75);
+    }
+}
@@ -0,0 +1,354 @@
+//! Top files and pages of a group for a month. For mysql2, these are queried
+//! directly from the viewdata table; other storage types go through a
+//! [`ResultsReader`].
+
+use crate::{
+    db_mysql2::DbMySql2,
+    results_reader::{GroupResults, ResultsReader, StorageReader},
+    row_group_status::StorageType,
+    Baglama2, DbId, GroupId, YearMonth,
+};
+use anyhow::{anyhow, Result};
+use mysql_async::{from_row, prelude::*};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+pub const DEFAULT_TOP_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopFile {
+    pub name: String,
+    pub views: u64,
+    pub pages: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopPage {
+    pub server: String,
+    pub namespace_id: i32,
+    pub title: String,
+    pub views: u64,
+    pub files: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupReport {
+    pub group_id: DbId,
+    pub ym: YearMonth,
+    pub total_views: u64,
+    pub distinct_files: usize,
+    pub distinct_pages: usize,
+    pub distinct_wikis: usize,
+    pub top_files: Vec<TopFile>,
+    pub top_pages: Vec<TopPage>,
+}
+
+impl GroupReport {
+    pub async fn generate(
+        baglama: &Baglama2,
+        group_id: GroupId,
+        ym: &YearMonth,
+        limit: usize,
+    ) -> Result<Self> {
+        let group_status = baglama
+            .get_group_status(&group_id, ym)
+            .await?
+            .ok_or_else(|| anyhow!("No status for group {group_id} in {ym}"))?;
+        if group_status.storage != StorageType::Mysql2 {
+            let results = StorageReader::new(baglama).read(&group_status).await?;
+            return Ok(Self::from_results(&results, limit));
+        }
+        let gs_id = group_status.id;
+        let table = DbMySql2::viewdata_table(ym);
+        let mut conn = baglama.get_tooldb_conn().await?;
+
+        // A page has the same view count in every row, one row per file it uses
+        let sql = format!(
+            "SELECT COALESCE(SUM(views),0),COUNT(*),COUNT(DISTINCT site) FROM (
+                SELECT MAX(page_views) AS views,pages.site AS site
+                FROM `{table}`,pages
+                WHERE group_status_id=? AND pages.id=pages_id AND excluded=0
+                GROUP BY pages_id) t"
+        );
+        let (total_views, distinct_pages, distinct_wikis) = conn
+            .exec_iter(sql, (gs_id,))
+            .await?
+            .map_and_drop(from_row::<(u64, usize, usize)>)
+            .await?
+            .first()
+            .copied()
+            .unwrap_or_default();
+        let sql = format!(
+            "SELECT COUNT(DISTINCT files_id) FROM `{table}` WHERE group_status_id=? AND excluded=0"
+        );
+        let distinct_files = conn
+            .exec_first::<usize, _, _>(sql, (gs_id,))
+            .await?
+            .unwrap_or_default();
+
+        let sql = format!(
+            "SELECT FROM_BASE64(TO_BASE64(files.name)),COALESCE(SUM(page_views),0),COUNT(DISTINCT pages_id)
+            FROM `{table}`,files
+            WHERE group_status_id=? AND files.id=files_id AND excluded=0
+            GROUP BY files_id
+            ORDER BY 2 DESC,1
+            LIMIT ?"
+        );
+        let top_files = conn
+            .exec_iter(sql, (gs_id, limit))
+            .await?
+            .map_and_drop(from_row::<(String, u64, usize)>)
+            .await?
+            .into_iter()
+            .map(|(name, views, pages)| TopFile { name, views, pages })
+            .collect();
+
+        let sql = format!(
+            "SELECT sites.server,pages.namespace_id,FROM_BASE64(TO_BASE64(pages.title)),COALESCE(MAX(page_views),0),COUNT(DISTINCT files_id)
+            FROM `{table}`,pages,sites
+            WHERE group_status_id=? AND pages.id=pages_id AND sites.id=pages.site AND excluded=0
+            GROUP BY pages_id
+            ORDER BY 4 DESC,1,3
+            LIMIT ?"
+        );
+        let top_pages = conn
+            .exec_iter(sql, (gs_id, limit))
+            .await?
+            .map_and_drop(from_row::<(String, i32, String, u64, usize)>)
+            .await?
+            .into_iter()
+            .map(|(server, namespace_id, title, views, files)| TopPage {
+                server,
+                namespace_id,
+                title,
+                views,
+                files,
+            })
+            .collect();
+
+        Ok(Self {
+            group_id: group_id.get(),
+            ym: ym.to_owned(),
+            total_views,
+            distinct_files,
+            distinct_pages,
+            distinct_wikis,
+            top_files,
+            top_pages,
+        })
+    }
+
+    /// Builds the report from results read by a [`ResultsReader`].
+    /// Like the mysql2 report, it leaves out excluded pages.
+    pub fn from_results(results: &GroupResults, limit: usize) -> Self {
+        // Files only used on excluded pages have no pages left
+        let mut top_files: Vec<TopFile> = results
+            .file_views()
+            .into_iter()
+            .filter(|(_, _, pages)| *pages > 0)
+            .map(|(name, views, pages)| TopFile { name, views, pages })
+            .collect();
+        let distinct_files = top_files.len();
+        top_files.sort_by(|a, b| b.views.cmp(&a.views).then_with(|| a.name.cmp(&b.name)));
+        top_files.truncate(limit);
+
+        let file_counts = results.page_file_counts();
+        let mut top_pages: Vec<TopPage> = results
+            .pages
+            .iter()
+            .zip(file_counts)
+            .filter(|((_, views), _)| !views.excluded)
+            .map(|((page, views), files)| TopPage {
+                server: page.server.to_owned(),
+                namespace_id: page.namespace_id,
+                title: page.title.to_owned(),
+                views: views.views.unwrap_or_default(),
+                files,
+            })
+            .collect();
+        top_pages.sort_by(|a, b| {
+            b.views
+                .cmp(&a.views)
+                .then_with(|| a.server.cmp(&b.server))
+                .then_with(|| a.title.cmp(&b.title))
+        });
+        let distinct_pages = top_pages.len();
+        top_pages.truncate(limit);
+
+        let distinct_wikis = results
+            .pages
+            .iter()
+            .filter(|(_, views)| !views.excluded)
+            .map(|(page, _)| &page.server)
+            .collect::<HashSet<_>>()
+            .len();
+        Self {
+            group_id: results.group_id,
+            ym: results.ym,
+            total_views: results.total_views(),
+            distinct_files,
+            distinct_pages,
+            distinct_wikis,
+            top_files,
+            top_pages,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "group_id": self.group_id,
+            "month": self.ym.to_string(),
+            "total_views": self.total_views,
+            "distinct_files": self.distinct_files,
+            "distinct_pages": self.distinct_pages,
+            "distinct_wikis": self.distinct_wikis,
+            "top_files": self.top_files.iter().map(|f| json!({
+                "file": f.name,
+                "views": f.views,
+                "pages": f.pages,
+            })).collect::<Vec<_>>(),
+            "top_pages": self.top_pages.iter().map(|p| json!({
+                "server": p.server,
+                "namespace_id": p.namespace_id,
+                "title": p.title,
+                "views": p.views,
+                "files": p.files,
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    pub fn top_files_csv(&self) -> String {
+        let mut ret = "rank,file,views,pages\n".to_string();
+        for (num, f) in self.top_files.iter().enumerate() {
+            ret += &format!(
+                "{},{},{},{}\n",
+                num + 1,
+                csv_field(&f.name),
+                f.views,
+                f.pages
+            );
+        }
+        ret
+    }
+
+    pub fn top_pages_csv(&self) -> String {
+        let mut ret = "rank,server,namespace_id,title,views,files\n".to_string();
+        for (num, p) in self.top_pages.iter().enumerate() {
+            ret += &format!(
+                "{},{},{},{},{},{}\n",
+                num + 1,
+                csv_field(&p.server),
+                p.namespace_id,
+                csv_field(&p.title),
+                p.views,
+                p.files
+            );
+        }
+        ret
+    }
+}
+
+/// Quotes a CSV field if necessary.
+pub fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> GroupReport {
+        GroupReport {
+            group_id: 7,
+            ym: YearMonth::new(2024, 3).unwrap(),
+            total_views: 1500,
+            distinct_files: 1,
+            distinct_pages: 2,
+            distinct_wikis: 1,
+            top_files: vec![TopFile {
+                name: "Portrait,_1890.jpg".to_string(),
+                views: 1500,
+                pages: 2,
+            }],
+            top_pages: vec![TopPage {
+                server: "en.wikipedia.org".to_string(),
+                namespace_id: 0,
+                title: "Painter".to_string(),
+                views: 1000,
+                files: 1,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn test_csv() {
+        let report = report();
+        assert_eq!(
+            report.top_files_csv(),
+            "rank,file,views,pages\n1,\"Portrait,_1890.jpg\",1500,2\n"
+        );
+        assert_eq!(
+            report.top_pages_csv(),
+            "rank,server,namespace_id,title,views,files\n1,en.wikipedia.org,0,Painter,1000,1\n"
+        );
+    }
+
+    #[test]
+    fn test_from_results() {
+        use crate::results_reader::ResultPage;
+        use crate::row_group_status::RowGroupStatus;
+        let gs = RowGroupStatus {
+            id: 1,
+            group_id: 7,
+            year: 2024,
+            month: 3,
+            status: "VIEW DATA COMPLETE".to_string(),
+            total_views: None,
+            usage_views: None,
+            excluded_views: None,
+            file: None,
+            sqlite3: None,
+            storage: StorageType::Sqlite3,
+        };
+        let page = |server: &str, title: &str| ResultPage {
+            server: server.to_string(),
+            namespace_id: 0,
+            title: title.to_string(),
+        };
+        let mut results = GroupResults::new(&gs).unwrap();
+        results.add_usages(vec![
+            ("A.jpg".to_string(), page("en.wikipedia.org", "X"), Some(5)),
+            ("B.jpg".to_string(), page("en.wikipedia.org", "X"), Some(5)),
+            ("B.jpg".to_string(), page("de.wikipedia.org", "Y"), Some(50)),
+        ]);
+        let report = GroupReport::from_results(&results, 1);
+        assert_eq!(report.total_views, 55);
+        assert_eq!(report.distinct_files, 2);
+        assert_eq!(report.distinct_pages, 2);
+        assert_eq!(report.distinct_wikis, 2);
+        assert_eq!(report.top_files.len(), 1);
+        assert_eq!(report.top_files[0].name, "B.jpg");
+        assert_eq!(report.top_files[0].views, 55);
+        assert_eq!(report.top_pages[0].title, "Y");
+        assert_eq!(report.top_pages[0].files, 1);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let j = report().to_json();
+        assert_eq!(j["month"], "2024-03");
+        assert_eq!(j["top_files"][0]["file"], "Portrait,_1890.jpg");
+        assert_eq!(j["top_pages"][0]["views"], 1000);
+    }
+}
@@ -0,0 +1,360 @@
+//! Where the files of a group come from.
+//!
+//! By default, this is the category tree or the uploader given in the `groups`
+//! table. A group can instead have one or more sources in the `group_sources`
+//! tool DB table, starting with the one from `groups` when the first is added: categories with individual depths, user names, SPARQL
+//! queries (see [`crate::file_query`]), or an explicit file list (see
+//! [`crate::file_list`]). The files of all sources are merged.
+
+use crate::{
+    category_tree::CategoryTree, file_list::FileList, file_query::FileQuery,
+    group_exclusions::GroupExclusions, row_group::RowGroup, Baglama2, DbId, YearMonth,
+};
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use mysql_async::{from_row, prelude::*};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GroupSource {
+    Category { category: String, depth: isize },
+    UserName(String),
+    Query(String),
+    FileList,
+}
+
+impl GroupSource {
+    pub fn source_type(&self) -> &'static str {
+        match self {
+            GroupSource::Category { .. } => "category",
+            GroupSource::UserName(_) => "user",
+            GroupSource::Query(_) => "query",
+            GroupSource::FileList => "file_list",
+        }
+    }
+
+    pub fn from_group(group: &RowGroup) -> Self {
+        if group.is_user_name() {
+            GroupSource::UserName(group.category().to_owned())
+        } else {
+            GroupSource::Category {
+                category: group.category().to_owned(),
+                depth: group.depth(),
+            }
+        }
+    }
+
+    fn from_row(source_type: &str, value: String, depth: isize) -> Result<Self> {
+        match source_type {
+            "category" => Ok(GroupSource::Category {
+                category: value,
+                depth,
+            }),
+            "user" => Ok(GroupSource::UserName(value)),
+            "query" => Ok(GroupSource::Query(value)),
+            "file_list" => Ok(GroupSource::FileList),
+            other => Err(anyhow!("Unknown group source type '{other}'")),
+        }
+    }
+
+    /// A short description of the source, for logs and statistics.
+    pub fn label(&self) -> String {
+        match self {
+            GroupSource::Category { category, depth } => format!("{category} ({depth})"),
+            GroupSource::UserName(user_name) => user_name.to_owned(),
+            GroupSource::Query(query) => query.split_whitespace().collect::<Vec<_>>().join(" "),
+            GroupSource::FileList => "file list".to_string(),
+        }
+    }
+
+    fn value_and_depth(&self) -> (String, isize) {
+        match self {
+            GroupSource::Category { category, depth } => (category.to_owned(), *depth),
+            GroupSource::UserName(user_name) => (user_name.to_owned(), 0),
+            GroupSource::Query(query) => (query.to_owned(), 0),
+            GroupSource::FileList => (String::new(), 0),
+        }
+    }
+
+    /// Returns the sources for a group; entries in `group_sources` take
+    /// precedence over the category/user name in `groups`. Adding the first
+    /// entry also copies the `groups` source, so it is not lost.
+    pub async fn for_group(baglama: &Baglama2, group: &RowGroup) -> Result<Vec<Self>> {
+        let sources = Self::list(baglama, group.id()).await?;
+        if sources.is_empty() {
+            Ok(vec![Self::from_group(group)])
+        } else {
+            Ok(sources)
+        }
+    }
+
+    /// The files of all sources of a group in a month, merged, with redirects
+    /// resolved and exclusions applied. The category trees of all category
+    /// sources are merged into one, and stored for the month.
+    pub async fn files_for_group(
+        baglama: &Baglama2,
+        group: &RowGroup,
+        ym: &YearMonth,
+        exclusions: &GroupExclusions,
+    ) -> Result<Vec<String>> {
+        let group_id = group.id();
+        let mut tree: Option<CategoryTree> = None;
+        let mut file_counts = vec![];
+        let mut files_per_source = vec![];
+        for source in Self::for_group(baglama, group).await? {
+            let files = match &source {
+                GroupSource::Category { category, depth } => {
+                    let root = category.replace(' ', "_");
+                    let source_tree = baglama
+                        .get_category_tree(std::slice::from_ref(&root), *depth, exclusions)
+                        .await?;
+                    let files = baglama
+                        .get_pages_in_categories(&source_tree.categories(), 6)
+                        .await?;
+                    match &mut tree {
+                        Some(tree) => tree.merge(source_tree),
+                        None => tree = Some(source_tree),
+                    }
+                    files
+                }
+                GroupSource::UserName(user_name) => {
+                    baglama
+                        .get_files_from_group_user(group_id, user_name)
+                        .await?
+                }
+                GroupSource::Query(query) => {
+                    FileQuery::new(baglama)
+                        .get_files(group_id, ym, query)
+                        .await?
+                }
+                GroupSource::FileList => FileList::load(baglama, group_id).await?,
+            };
+            info!(
+                "Group {group_id}: {} files from {}",
+                files.len(),
+                source.label()
+            );
+            file_counts.push((source, files.len()));
+            files_per_source.push(files);
+        }
+        if let Some(mut tree) = tree {
+            Self::store_category_tree(baglama, ym, group_id, &mut tree).await;
+        }
+        if let Err(e) = Self::record_stats(baglama, group_id, ym, &file_counts).await {
+            warn!("Could not record source statistics for group {group_id}: {e}");
+        }
+        let files = Self::union_files(files_per_source);
+        let files = baglama.resolve_file_redirects(files).await?;
+        Ok(exclusions.filter_files(files))
+    }
+
+    /// Stores the category tree of a group for the month, so it can be
+    /// compared with the one from the previous month.
+    async fn store_category_tree(
+        baglama: &Baglama2,
+        ym: &YearMonth,
+        group_id: DbId,
+        tree: &mut CategoryTree,
+    ) {
+        match baglama.add_file_counts(tree).await {
+            Ok(()) => {
+                if let Err(e) =
+                    CategoryTree::path(baglama, ym, group_id).and_then(|path| tree.save(&path))
+                {
+                    warn!("Could not store category tree for group {group_id}: {e}");
+                }
+            }
+            Err(e) => warn!("Could not get file counts for group {group_id}: {e}"),
+        }
+    }
+
+    /// Lists the sources stored for a group in `group_sources`.
+    pub async fn list(baglama: &Baglama2, group_id: DbId) -> Result<Vec<Self>> {
+        let sql = "SELECT `type`,FROM_BASE64(TO_BASE64(`value`)),`depth` FROM `group_sources` WHERE `group_id`=? ORDER BY `id`";
+        let rows = baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, (group_id,))
+            .await?
+            .map_and_drop(from_row::<(String, String, isize)>)
+            .await?;
+        rows.into_iter()
+            .map(|(source_type, value, depth)| Self::from_row(&source_type, value, depth))
+            .collect()
+    }
+
+    /// IDs of the groups that have entries in `group_sources`.
+    pub async fn groups_with_sources(baglama: &Baglama2) -> Result<HashSet<DbId>> {
+        let rows = baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter("SELECT DISTINCT `group_id` FROM `group_sources`", ())
+            .await?
+            .map_and_drop(from_row::<DbId>)
+            .await?;
+        Ok(rows.into_iter().collect())
+    }
+
+    /// Adds a source to a group. An existing source of the same type and value
+    /// is replaced, so this can be used to change the depth of a category.
+    /// The first source added to a group is added next to its `groups` source.
+    pub async fn add_for_group(baglama: &Baglama2, group_id: DbId, source: &Self) -> Result<()> {
+        if Self::list(baglama, group_id).await?.is_empty() {
+            let group = baglama
+                .get_group(&group_id.try_into()?)
+                .await?
+                .ok_or_else(|| anyhow!("No such group: {group_id}"))?;
+            if !group.category().trim().is_empty() {
+                Self::insert_for_group(baglama, group_id, &Self::from_group(&group)).await?;
+            }
+        }
+        Self::remove_for_group(baglama, group_id, source).await?;
+        Self::insert_for_group(baglama, group_id, source).await
+    }
+
+    async fn insert_for_group(baglama: &Baglama2, group_id: DbId, source: &Self) -> Result<()> {
+        let (value, depth) = source.value_and_depth();
+        baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_drop(
+                "INSERT INTO `group_sources` (`group_id`,`type`,`value`,`depth`) VALUES (?,?,?,?)",
+                (group_id, source.source_type(), value, depth),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Removes a source from a group; the depth of categories is ignored.
+    pub async fn remove_for_group(baglama: &Baglama2, group_id: DbId, source: &Self) -> Result<()> {
+        let (value, _depth) = source.value_and_depth();
+        baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_drop(
+                "DELETE FROM `group_sources` WHERE `group_id`=? AND `type`=? AND `value`=?",
+                (group_id, source.source_type(), value),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Merges the file lists of several sources, keeping the first occurrence
+    /// of each file.
+    pub fn union_files(files_per_source: Vec<Vec<String>>) -> Vec<String> {
+        let mut seen = HashSet::new();
+        files_per_source
+            .into_iter()
+            .flatten()
+            .filter(|file| seen.insert(file.to_owned()))
+            .collect()
+    }
+
+    /// Records how many files each source of a group contributed in a month,
+    /// before de-duplication and exclusions.
+    pub async fn record_stats(
+        baglama: &Baglama2,
+        group_id: DbId,
+        ym: &YearMonth,
+        file_counts: &[(Self, usize)],
+    ) -> Result<()> {
+        let mut conn = baglama.get_tooldb_conn().await?;
+        let sql = "REPLACE INTO `group_source_stats` (`group_id`,`year`,`month`,`type`,`source`,`files`) VALUES (?,?,?,?,?,?)";
+        for (source, files) in file_counts {
+            let mut label = source.label();
+            while label.len() > 255 {
+                label.pop();
+            }
+            conn.exec_drop(
+                sql,
+                (
+                    group_id,
+                    ym.year(),
+                    ym.month(),
+                    source.source_type(),
+                    label,
+                    files,
+                ),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Called from `DbMySql2::ensure_table_exists`.
+    pub async fn ensure_table_exists(baglama: &Baglama2) -> Result<()> {
+        let sql = "CREATE TABLE IF NOT EXISTS `group_sources` (
+              `id` int(11) unsigned NOT NULL AUTO_INCREMENT,
+              `group_id` int(11) unsigned NOT NULL,
+              `type` varchar(16) NOT NULL,
+              `value` mediumblob NOT NULL,
+              `depth` int(11) NOT NULL DEFAULT 0,
+              PRIMARY KEY (`id`),
+              KEY `group_id` (`group_id`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4";
+        let mut conn = baglama.get_tooldb_conn().await?;
+        conn.exec_drop(sql, ()).await?;
+        let sql = "CREATE TABLE IF NOT EXISTS `group_source_stats` (
+              `id` int(11) unsigned NOT NULL AUTO_INCREMENT,
+              `group_id` int(11) unsigned NOT NULL,
+              `year` int(11) NOT NULL,
+              `month` int(11) NOT NULL,
+              `type` varchar(16) NOT NULL,
+              `source` varbinary(255) NOT NULL,
+              `files` int(11) unsigned NOT NULL,
+              PRIMARY KEY (`id`),
+              UNIQUE KEY `group_month_source` (`group_id`,`year`,`month`,`type`,`source`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4";
+        conn.exec_drop(sql, ()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_row() {
+        assert_eq!(
+            GroupSource::from_row("category", "Foo".to_string(), 3).unwrap(),
+            GroupSource::Category {
+                category: "Foo".to_string(),
+                depth: 3
+            }
+        );
+        assert_eq!(
+            GroupSource::from_row("query", "SELECT ?file {}".to_string(), 0).unwrap(),
+            GroupSource::Query("SELECT ?file {}".to_string())
+        );
+        assert!(GroupSource::from_row("nonsense", String::new(), 0).is_err());
+    }
+
+    #[test]
+    fn test_union_files() {
+        let files = GroupSource::union_files(vec![
+            vec!["A.jpg".to_string(), "B.jpg".to_string()],
+            vec![],
+            vec!["C.jpg".to_string(), "A.jpg".to_string()],
+        ]);
+        assert_eq!(
+            files,
+            vec![
+                "A.jpg".to_string(),
+                "B.jpg".to_string(),
+                "C.jpg".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_label() {
+        let query = GroupSource::Query("SELECT ?file\n  WHERE { }".to_string());
+        assert_eq!(query.label(), "SELECT ?file WHERE { }");
+        let category = GroupSource::Category {
+            category: "Foo".to_string(),
+            depth: 2,
+        };
+        assert_eq!(category.label(), "Foo (2)");
+    }
+}
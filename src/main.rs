@@ -1,4 +1,23 @@
+use crate::api::ApiBackend;
+use crate::category_check::{CategoryCheck, CategoryCheckOptions};
+use crate::category_tree::CategoryTree;
 use crate::db_mysql2::DbMySql2;
+use crate::file_list::{FileList, ListFormat};
+use crate::group_exclusions::{ExclusionType, GroupExclusions};
+use crate::group_overlap::GroupOverlap;
+use crate::group_report::{GroupReport, DEFAULT_TOP_LIMIT};
+use crate::group_source::GroupSource;
+use crate::month_summary::MonthSummary;
+use crate::month_verify::MonthVerify;
+use crate::page_exclusions::PageExclusions;
+use crate::pageviews::cross_check::{self, CrossCheck};
+use crate::parquet_export::ParquetExport;
+use crate::release::Release;
+use crate::sqlite_export::SqliteExport;
+use crate::sqlite_migration::SqliteMigration;
+use crate::trends::Trends;
+use crate::view_anomalies::ViewAnomalies;
+use crate::wiki_report::WikiReport;
 use anyhow::Result;
 use baglama2::*;
 use chrono::{DateTime, Datelike, Months, Utc};
@@ -14,20 +33,42 @@ pub use year_month::YearMonth;
 
 pub type DbId = usize;
 
+pub mod api;
 pub mod baglama2;
+pub mod category_check;
+pub mod category_tree;
+pub mod charts;
 pub mod db_mysql2;
 pub mod db_sqlite;
 pub mod db_trait;
 pub mod file;
+pub mod file_list;
+pub mod file_query;
+pub mod file_usage_counts;
 pub mod global_image_links;
 pub mod group_date;
+pub mod group_exclusions;
+pub mod group_overlap;
+pub mod group_report;
+pub mod group_source;
+pub mod month_summary;
+pub mod month_verify;
 pub mod month_views;
 pub mod page;
+pub mod page_exclusions;
 pub mod pageviews;
+pub mod parquet_export;
+pub mod release;
+pub mod results_reader;
 pub mod row_group;
 pub mod row_group_status;
 pub mod site;
+pub mod sqlite_export;
+pub mod sqlite_migration;
+pub mod trends;
+pub mod view_anomalies;
 pub mod view_count;
+pub mod wiki_report;
 pub mod year_month;
 
 pub type GroupId = NonZero<DbId>;
@@ -138,8 +179,11 @@ async fn process_all_groups(
     Ok(())
 }
 
-async fn process_mysql2(ym: YearMonth, baglama: Arc<Baglama2>) -> Result<()> {
-    let db = DbMySql2::new(ym, baglama.clone()).await?;
+async fn process_mysql2(ym: YearMonth, baglama: Arc<Baglama2>, full_scan: bool) -> Result<()> {
+    let mut db = DbMySql2::new(ym, baglama.clone()).await?;
+    if full_scan {
+        db = db.full_scan();
+    }
     db.ensure_table_exists().await?;
     db.start_missing_groups().await?;
     db.add_pages().await?;
@@ -153,6 +197,90 @@ async fn process_mysql2_views(ym: YearMonth, baglama: Arc<Baglama2>) -> Result<(
     Ok(())
 }
 
+/// Prints the category tree of a group for a month (building and storing it
+/// if necessary), and how it changed since the previous month. The trees of
+/// several category sources are merged.
+async fn category_tree_report(
+    group_id: GroupId,
+    ym: YearMonth,
+    write_dot: bool,
+    baglama: &Baglama2,
+) -> Result<()> {
+    let group = baglama
+        .get_group(&group_id)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("No such group: {group_id}"))?;
+    let path = CategoryTree::path(baglama, &ym, group_id.get())?;
+    let tree = match CategoryTree::load(&path) {
+        Ok(tree) => tree,
+        Err(_) => {
+            let exclusions = GroupExclusions::load(baglama, group_id.get()).await?;
+            let mut tree: Option<CategoryTree> = None;
+            for source in GroupSource::for_group(baglama, &group).await? {
+                if let GroupSource::Category { category, depth } = source {
+                    let root = category.replace(' ', "_");
+                    let source_tree = baglama
+                        .get_category_tree(std::slice::from_ref(&root), depth, &exclusions)
+                        .await?;
+                    match &mut tree {
+                        Some(tree) => tree.merge(source_tree),
+                        None => tree = Some(source_tree),
+                    }
+                }
+            }
+            let mut tree =
+                tree.ok_or_else(|| anyhow::anyhow!("Group {group_id} has no category source"))?;
+            baglama.add_file_counts(&mut tree).await?;
+            tree.save(&path)?;
+            tree
+        }
+    };
+    if write_dot {
+        let dot_path = path.replace(".json", ".dot");
+        std::fs::write(&dot_path, tree.to_dot())?;
+        info!("DOT written to {dot_path}");
+    }
+    let previous = ym
+        .previous()
+        .and_then(|prev| CategoryTree::path(baglama, &prev, group_id.get()))
+        .and_then(|prev_path| CategoryTree::load(&prev_path));
+    let diff = previous.map(|prev| tree.diff(&prev).to_json()).ok();
+    let report = serde_json::json!({
+        "group_id": group_id.get(),
+        "category": group.category(),
+        "month": ym.to_string(),
+        "tree": tree.to_json(),
+        "diff_to_previous_month": diff,
+    });
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+/// Parses `category <name> [depth]`, `user <name>`, `query <path to SPARQL
+/// file>`, or `file_list`.
+fn group_source_from_args(args: &[String]) -> Result<GroupSource> {
+    let value = || {
+        args.get(1)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Source value expected"))
+    };
+    match args.first().map(|s| s.as_str()) {
+        Some("category") => Ok(GroupSource::Category {
+            category: value()?,
+            depth: args
+                .get(2)
+                .map(|s| s.parse::<isize>().expect("bad depth"))
+                .unwrap_or(0),
+        }),
+        Some("user") => Ok(GroupSource::UserName(value()?)),
+        Some("query") => Ok(GroupSource::Query(std::fs::read_to_string(value()?)?)),
+        Some("file_list") => Ok(GroupSource::FileList),
+        other => Err(anyhow::anyhow!(
+            "Source type (category, user, query, file_list) expected, not {other:?}"
+        )),
+    }
+}
+
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<()> {
     log::set_max_level(LevelFilter::Trace);
@@ -160,7 +288,6 @@ async fn main() -> Result<()> {
         .map(|s| s.into_string().expect("Bad argv"))
         .collect();
     let baglama = Arc::new(Baglama2::new().await?);
-    baglama.deactivate_nonexistent_categories().await?;
     match argv.get(1).map(|s| s.as_str()) {
         Some("mysql2") => {
             let year = year(argv.get(2));
@@ -169,6 +296,8 @@ async fn main() -> Result<()> {
             process_mysql2(
                 YearMonth::new(year, month).expect("bad year/month"),
                 baglama.clone(),
+                // Incremental by default; schedule a `--full` run regularly
+                argv.iter().any(|a| a == "--full"),
             )
             .await?;
         }
@@ -182,6 +311,302 @@ async fn main() -> Result<()> {
             )
             .await?;
         }
+        Some("cross_check") => {
+            let ym = YearMonth::new(year(argv.get(2)), month(argv.get(3))).expect("bad year/month");
+            let sample_size = argv
+                .iter()
+                .find_map(|a| a.strip_prefix("--sample="))
+                .map(|s| s.parse::<usize>().expect("bad sample size"))
+                .unwrap_or(cross_check::DEFAULT_SAMPLE_SIZE);
+            let tolerance = argv
+                .iter()
+                .find_map(|a| a.strip_prefix("--tolerance="))
+                .map(|s| s.parse::<f64>().expect("bad tolerance"))
+                .unwrap_or(cross_check::DEFAULT_TOLERANCE);
+            let report = CrossCheck::new(&baglama, ym, sample_size, tolerance)
+                .run()
+                .await?;
+            println!("{}", serde_json::to_string_pretty(&report.to_json())?);
+        }
+        Some("check_categories") => {
+            let options = CategoryCheckOptions::from_args(&argv);
+            CategoryCheck::new(&baglama, options).run().await?;
+        }
+        Some("category_tree") => {
+            let group_id: GroupId = argv
+                .get(2)
+                .map(|s| s.parse::<DbId>().expect("bad group ID"))
+                .expect("Group ID expected")
+                .try_into()?;
+            let ym = YearMonth::new(year(argv.get(3)), month(argv.get(4))).expect("bad year/month");
+            category_tree_report(group_id, ym, argv.iter().any(|a| a == "--dot"), &baglama).await?;
+        }
+        Some("top") => {
+            let group_id: GroupId = argv
+                .get(2)
+                .map(|s| s.parse::<DbId>().expect("bad group ID"))
+                .expect("Group ID expected")
+                .try_into()?;
+            let ym = YearMonth::new(year(argv.get(3)), month(argv.get(4))).expect("bad year/month");
+            let limit = argv
+                .iter()
+                .find_map(|a| a.strip_prefix("--limit="))
+                .map(|s| s.parse::<usize>().expect("bad limit"))
+                .unwrap_or(DEFAULT_TOP_LIMIT);
+            let report = GroupReport::generate(&baglama, group_id, &ym, limit).await?;
+            match argv.iter().find_map(|a| a.strip_prefix("--csv=")) {
+                Some(dir) => {
+                    let prefix = format!("{dir}/group_{group_id}_{ym}");
+                    std::fs::write(format!("{prefix}_files.csv"), report.top_files_csv())?;
+                    std::fs::write(format!("{prefix}_pages.csv"), report.top_pages_csv())?;
+                    info!("CSV written to {prefix}_files.csv and {prefix}_pages.csv");
+                }
+                None => println!("{}", serde_json::to_string_pretty(&report.to_json())?),
+            }
+        }
+        Some("trends") => {
+            let from =
+                YearMonth::new(year(argv.get(2)), month(argv.get(3))).expect("bad year/month");
+            let to = YearMonth::new(year(argv.get(4)), month(argv.get(5))).expect("bad year/month");
+            let group_id = argv
+                .get(6)
+                .filter(|s| !s.starts_with("--"))
+                .map(|s| s.parse::<DbId>().expect("bad group ID"));
+            let trends = Trends::load(&baglama, group_id, &from, &to).await?;
+            if argv.iter().any(|a| a == "--csv") {
+                print!("{}", Trends::to_csv(&trends));
+            } else {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&Trends::to_json(&trends))?
+                );
+            }
+        }
+        Some("export_sqlite") => {
+            let group_id: GroupId = argv
+                .get(2)
+                .map(|s| s.parse::<DbId>().expect("bad group ID"))
+                .expect("Group ID expected")
+                .try_into()?;
+            let ym = YearMonth::new(year(argv.get(3)), month(argv.get(4))).expect("bad year/month");
+            let path = argv.get(5).expect("Output file expected");
+            SqliteExport::export(&baglama, group_id, &ym, path).await?;
+            info!("Group {group_id} for {ym} exported to {path}");
+        }
+        Some("export_parquet") => {
+            let ym = YearMonth::new(year(argv.get(2)), month(argv.get(3))).expect("bad year/month");
+            let dir = argv.get(4).expect("Output directory expected");
+            let files = ParquetExport::new(&baglama, ym, dir).run().await?;
+            info!("{files} Parquet files written to {dir}");
+        }
+        Some("release") => {
+            let ym = YearMonth::new(year(argv.get(2)), month(argv.get(3))).expect("bad year/month");
+            let dir = argv.get(4).expect("Output directory expected");
+            let release_dir = Release::new(&baglama, ym, dir).run().await?;
+            info!("Release written to {}", release_dir.display());
+        }
+        Some("private") => {
+            let group_id: GroupId = argv
+                .get(2)
+                .map(|s| s.parse::<DbId>().expect("bad group ID"))
+                .expect("Group ID expected")
+                .try_into()?;
+            let private = argv.get(3).map(|s| s.as_str()) != Some("0");
+            Release::set_private(&baglama, group_id, private).await?;
+        }
+        Some("serve") => {
+            let port = argv
+                .iter()
+                .find_map(|a| a.strip_prefix("--port="))
+                .map(|s| s.parse::<u16>().expect("bad port"))
+                .unwrap_or(8000);
+            let api = match argv.iter().find_map(|a| a.strip_prefix("--sqlite=")) {
+                Some(path) => ApiBackend::sqlite(path)?,
+                None => ApiBackend::mysql(baglama.clone()).await?,
+            };
+            info!("Serving API on port {port}");
+            api::serve(api, port).await?;
+        }
+        Some("render") => {
+            let group_id: GroupId = argv
+                .get(2)
+                .map(|s| s.parse::<DbId>().expect("bad group ID"))
+                .expect("Group ID expected")
+                .try_into()?;
+            let ym = YearMonth::new(year(argv.get(3)), month(argv.get(4))).expect("bad year/month");
+            let dir = argv.get(5).expect("Output directory expected");
+            let report = WikiReport::load(&baglama, group_id, &ym, DEFAULT_TOP_LIMIT).await?;
+            let wikitext = report.render_wikitext(&WikiReport::wikitext_template(Some(&baglama))?);
+            let html = report.render_html(&WikiReport::html_template(Some(&baglama))?);
+            let prefix = format!("{dir}/group_{group_id}_{ym}");
+            std::fs::write(format!("{prefix}.wiki"), &wikitext)?;
+            std::fs::write(format!("{prefix}.html"), &html)?;
+            info!("Written to {prefix}.wiki and {prefix}.html");
+            if let Some(title) = argv.iter().find_map(|a| a.strip_prefix("--publish=")) {
+                if argv.iter().any(|a| a == "--dry-run") {
+                    info!("Dry run: would publish {prefix}.wiki to commonswiki:{title}");
+                } else {
+                    let summary = format!("BaGLAMa statistics for {ym}");
+                    baglama
+                        .edit_page("commonswiki", title, &wikitext, &summary)
+                        .await?;
+                    info!("Published to commonswiki:{title}");
+                }
+            }
+        }
+        Some("charts") => {
+            let group_id: GroupId = argv
+                .get(2)
+                .map(|s| s.parse::<DbId>().expect("bad group ID"))
+                .expect("Group ID expected")
+                .try_into()?;
+            let from =
+                YearMonth::new(year(argv.get(3)), month(argv.get(4))).expect("bad year/month");
+            let to = YearMonth::new(year(argv.get(5)), month(argv.get(6))).expect("bad year/month");
+            let dir = argv.get(7).expect("Output directory expected");
+            let prefix = format!("{dir}/group_{group_id}");
+            let trends = Trends::load(&baglama, Some(group_id.get()), &from, &to).await?;
+            if let Some(trend) = trends.first() {
+                std::fs::write(format!("{prefix}_views.svg"), charts::views_chart(trend))?;
+            }
+            let months = charts::month_range(&from, &to);
+            let rows = charts::load_wiki_views(&baglama, group_id.get(), &from, &to).await?;
+            std::fs::write(
+                format!("{prefix}_wikis.svg"),
+                charts::wikis_chart(group_id.get(), &months, &rows),
+            )?;
+            let report = GroupReport::generate(&baglama, group_id, &to, 20).await?;
+            let title = format!("Group {group_id}: top files, {to}");
+            std::fs::write(
+                format!("{prefix}_top_files_{to}.svg"),
+                charts::top_files_chart(&title, &report.top_files, 20),
+            )?;
+            info!("Charts written to {dir}");
+        }
+        Some("anomalies") => {
+            let ym = YearMonth::new(year(argv.get(2)), month(argv.get(3))).expect("bad year/month");
+            // Report only; flagging and capping happen when the month is finalized
+            let anomalies = ViewAnomalies::new(&baglama, ym).detect().await?;
+            info!("{} view anomalies found in {ym}", anomalies.len());
+            let json: Vec<_> = anomalies.iter().map(|a| a.to_json()).collect();
+            println!("{}", serde_json::to_string_pretty(&json)?);
+        }
+        Some("summary") => {
+            let ym = YearMonth::new(year(argv.get(2)), month(argv.get(3))).expect("bad year/month");
+            let summary = MonthSummary::load(&baglama, &ym).await?;
+            println!("{}", serde_json::to_string_pretty(&summary.to_json())?);
+        }
+        Some("verify") => {
+            let ym = YearMonth::new(year(argv.get(2)), month(argv.get(3))).expect("bad year/month");
+            let fix = argv.iter().any(|a| a == "--fix");
+            let report = MonthVerify::new(baglama.clone(), ym).run(fix).await?;
+            info!("{} integrity findings in {ym}", report.findings.len());
+            println!("{}", serde_json::to_string_pretty(&report.to_json())?);
+        }
+        Some("overlap") => {
+            let ym = YearMonth::new(year(argv.get(2)), month(argv.get(3))).expect("bad year/month");
+            let group_ids: Vec<GroupId> = argv
+                .iter()
+                .skip(4)
+                .map(|s| {
+                    s.parse::<DbId>()
+                        .expect("bad group ID")
+                        .try_into()
+                        .expect("group ID is 0")
+                })
+                .collect();
+            if group_ids.len() < 2 {
+                return Err(anyhow::anyhow!("At least two group IDs expected"));
+            }
+            let overlap = GroupOverlap::load(&baglama, &group_ids, &ym).await?;
+            println!("{}", serde_json::to_string_pretty(&overlap.to_json())?);
+        }
+        Some("migrate_sqlite") => {
+            let ym = argv.get(2).map(|_| {
+                YearMonth::new(year(argv.get(2)), month(argv.get(3))).expect("bad year/month")
+            });
+            let summary = SqliteMigration::new(baglama.clone())?.run(ym).await?;
+            info!(
+                "Migrated {} SQLite files, {} failed",
+                summary.migrated, summary.failed
+            );
+        }
+        Some("exclusions") => {
+            let group_id = argv
+                .get(2)
+                .map(|s| s.parse::<DbId>().expect("bad group ID"))
+                .expect("Group ID expected");
+            for (exclusion_type, value) in GroupExclusions::list(&baglama, group_id).await? {
+                println!("{}\t{value}", exclusion_type.as_str());
+            }
+        }
+        Some(action @ ("exclude" | "unexclude")) => {
+            let group_id = argv
+                .get(2)
+                .map(|s| s.parse::<DbId>().expect("bad group ID"))
+                .expect("Group ID expected");
+            let exclusion_type = argv
+                .get(3)
+                .map(|s| {
+                    ExclusionType::try_from(s.as_str()).expect("category, file or page expected")
+                })
+                .expect("Exclusion type (category, file or page) expected");
+            let value = argv.get(4).expect(
+                "Category, file pattern or page (<server>:<namespace_id>:<title>) expected",
+            );
+            if action == "exclude" {
+                GroupExclusions::add(&baglama, group_id, exclusion_type, value).await?;
+            } else {
+                GroupExclusions::remove(&baglama, group_id, exclusion_type, value).await?;
+            }
+        }
+        Some("main_pages") => {
+            let refresh = argv.iter().any(|arg| arg == "--refresh");
+            let resolved = PageExclusions::update_main_pages(&baglama, refresh).await?;
+            info!("Resolved main pages of {resolved} sites");
+        }
+        Some("sources") => {
+            let group_id = argv
+                .get(2)
+                .map(|s| s.parse::<DbId>().expect("bad group ID"))
+                .expect("Group ID expected");
+            for source in GroupSource::list(&baglama, group_id).await? {
+                println!("{}\t{}", source.source_type(), source.label());
+            }
+        }
+        Some(action @ ("add_source" | "remove_source")) => {
+            let group_id = argv
+                .get(2)
+                .map(|s| s.parse::<DbId>().expect("bad group ID"))
+                .expect("Group ID expected");
+            let source = group_source_from_args(&argv[3..])?;
+            if action == "add_source" {
+                GroupSource::add_for_group(&baglama, group_id, &source).await?;
+            } else {
+                GroupSource::remove_for_group(&baglama, group_id, &source).await?;
+            }
+        }
+        Some("import_file_list") => {
+            let group_id = argv
+                .get(2)
+                .map(|s| s.parse::<DbId>().expect("bad group ID"))
+                .expect("Group ID expected");
+            let path = argv.get(3).expect("Path to file list expected");
+            let format = match argv.iter().find_map(|a| a.strip_prefix("--format=")) {
+                Some(format) => ListFormat::parse(format)?,
+                None => ListFormat::from_path(path),
+            };
+            let text = std::fs::read_to_string(path)?;
+            let import = FileList::import(&baglama, group_id, &text, format).await?;
+            info!(
+                "Stored {} files for group {group_id}, {} missing on Commons",
+                import.stored.len(),
+                import.missing.len()
+            );
+            for file in &import.missing {
+                println!("MISSING\t{file}");
+            }
+        }
         Some("_run") => {
             let group_id = argv
                 .get(2)
@@ -0,0 +1,112 @@
+//! Summary of a month for review before a release: group status counts,
+//! view totals, the pageview source, and the flagged view anomalies.
+
+use crate::{
+    db_mysql2::DbMySql2,
+    view_anomalies::{Anomaly, ViewAnomalies},
+    Baglama2, YearMonth,
+};
+use anyhow::Result;
+use mysql_async::{from_row, prelude::*};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MonthTotals {
+    pub total_views: u64,
+    pub usage_views: u64,
+    pub excluded_views: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonthSummary {
+    pub ym: YearMonth,
+    /// Number of groups per status.
+    pub statuses: BTreeMap<String, usize>,
+    pub totals: MonthTotals,
+    pub pageview_source: Option<String>,
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl MonthSummary {
+    pub async fn load(baglama: &Baglama2, ym: &YearMonth) -> Result<Self> {
+        let mut conn = baglama.get_tooldb_conn().await?;
+        let sql = "SELECT `status`,COUNT(*) FROM `group_status` WHERE `year`=? AND `month`=? GROUP BY `status`";
+        let statuses = conn
+            .exec_iter(sql, (ym.year(), ym.month()))
+            .await?
+            .map_and_drop(from_row::<(String, usize)>)
+            .await?
+            .into_iter()
+            .collect();
+        let sql = "SELECT COALESCE(SUM(total_views),0),COALESCE(SUM(usage_views),0),COALESCE(SUM(excluded_views),0)
+            FROM `group_status` WHERE `year`=? AND `month`=? AND `status`='VIEW DATA COMPLETE'";
+        let (total_views, usage_views, excluded_views) = conn
+            .exec_first::<(u64, u64, u64), _, _>(sql, (ym.year(), ym.month()))
+            .await?
+            .unwrap_or_default();
+        Ok(Self {
+            ym: *ym,
+            statuses,
+            totals: MonthTotals {
+                total_views,
+                usage_views,
+                excluded_views,
+            },
+            pageview_source: DbMySql2::get_pageview_source(baglama, ym).await?,
+            anomalies: ViewAnomalies::load(baglama, ym).await?,
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "month": self.ym.to_string(),
+            "statuses": self.statuses,
+            "total_views": self.totals.total_views,
+            "usage_views": self.totals.usage_views,
+            "excluded_views": self.totals.excluded_views,
+            "pageview_source": self.pageview_source,
+            "anomalies": self.anomalies.iter().map(|a| a.to_json()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::view_anomalies::AnomalyKind;
+
+    #[test]
+    fn test_to_json() {
+        let summary = MonthSummary {
+            ym: YearMonth::new(2024, 6).unwrap(),
+            statuses: [
+                ("VIEW DATA COMPLETE".to_string(), 3),
+                ("SCANNED".to_string(), 1),
+            ]
+            .into_iter()
+            .collect(),
+            totals: MonthTotals {
+                total_views: 1000,
+                usage_views: 1500,
+                excluded_views: 20,
+            },
+            pageview_source: Some("dump".to_string()),
+            anomalies: vec![Anomaly {
+                pages_id: 5,
+                group_status_id: None,
+                kind: AnomalyKind::History,
+                views: 900,
+                reference: 10,
+                capped_views: Some(50),
+                page: None,
+            }],
+        };
+        let json = summary.to_json();
+        assert_eq!(json["month"], "2024-06");
+        assert_eq!(json["statuses"]["SCANNED"], 1);
+        assert_eq!(json["excluded_views"], 20);
+        assert_eq!(json["anomalies"][0]["kind"], "history");
+        assert_eq!(json["anomalies"][0]["capped_views"], 50);
+    }
+}
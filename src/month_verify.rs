@@ -0,0 +1,401 @@
+//! Integrity checks for the mysql2 data of a month.
+//!
+//! Each finding comes with a suggested repair. With `--fix`, the repairs that
+//! are safe to run unattended are applied; duplicate pages are only reported.
+
+use crate::{db_mysql2::DbMySql2, Baglama2, DbId, YearMonth};
+use anyhow::Result;
+use log::info;
+use mysql_async::{from_row, prelude::*};
+use serde_json::{json, Value};
+use std::{collections::BTreeSet, sync::Arc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CheckKind {
+    /// Complete group with viewdata rows that have no views.
+    IncompleteViews,
+    /// Viewdata rows pointing at a missing file or page.
+    OrphanRows,
+    /// Complete group whose `total_views` differs from its viewdata.
+    TotalMismatch,
+    /// Active group without a group_status row for the month.
+    MissingGroup,
+    /// Pages on the same site with the same title, in different namespaces.
+    DuplicatePages,
+}
+
+impl CheckKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::IncompleteViews => "incomplete_views",
+            Self::OrphanRows => "orphan_rows",
+            Self::TotalMismatch => "total_mismatch",
+            Self::MissingGroup => "missing_group",
+            Self::DuplicatePages => "duplicate_pages",
+        }
+    }
+
+    pub fn repair(&self) -> &'static str {
+        match self {
+            Self::IncompleteViews => {
+                "reset the group to SCANNED and clear its totals, then run the views step again"
+            }
+            Self::OrphanRows => "delete the rows, then recalculate the group totals",
+            Self::TotalMismatch => {
+                "clear the group totals and per-site statistics, then recalculate them"
+            }
+            Self::MissingGroup => "start the group for the month, then run the scan again",
+            Self::DuplicatePages => {
+                "check the namespace of the pages by hand; merge them if they are the same page"
+            }
+        }
+    }
+
+    /// Whether `--fix` applies the repair.
+    pub fn is_fixable(&self) -> bool {
+        *self != Self::DuplicatePages
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub kind: CheckKind,
+    pub group_status_id: Option<DbId>,
+    pub group_id: Option<DbId>,
+    pub details: String,
+}
+
+impl Finding {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "check": self.kind.as_str(),
+            "group_status_id": self.group_status_id,
+            "group_id": self.group_id,
+            "details": self.details,
+            "repair": self.kind.repair(),
+            "fixable": self.kind.is_fixable(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyReport {
+    pub ym: YearMonth,
+    pub findings: Vec<Finding>,
+    pub fixed: bool,
+}
+
+impl VerifyReport {
+    /// The group_status IDs of the findings of a kind.
+    pub fn group_status_ids(&self, kind: CheckKind) -> Vec<DbId> {
+        self.findings
+            .iter()
+            .filter(|f| f.kind == kind)
+            .filter_map(|f| f.group_status_id)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut counts = serde_json::Map::new();
+        for finding in &self.findings {
+            let count = counts
+                .entry(finding.kind.as_str())
+                .or_insert_with(|| json!(0));
+            *count = json!(count.as_u64().unwrap_or_default() + 1);
+        }
+        json!({
+            "month": self.ym.to_string(),
+            "fixed": self.fixed,
+            "counts": counts,
+            "findings": self.findings.iter().map(|f| f.to_json()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+pub struct MonthVerify {
+    baglama: Arc<Baglama2>,
+    ym: YearMonth,
+}
+
+impl MonthVerify {
+    pub fn new(baglama: Arc<Baglama2>, ym: YearMonth) -> Self {
+        Self { baglama, ym }
+    }
+
+    /// Runs all checks, and applies the fixable repairs if `fix` is set.
+    pub async fn run(&self, fix: bool) -> Result<VerifyReport> {
+        let mut findings = self.check_missing_groups().await?;
+        if self.viewdata_table_exists().await? {
+            findings.append(&mut self.check_incomplete_views().await?);
+            findings.append(&mut self.check_orphan_rows().await?);
+            findings.append(&mut self.check_total_mismatch().await?);
+            findings.append(&mut self.check_duplicate_pages().await?);
+        }
+        let report = VerifyReport {
+            ym: self.ym,
+            findings,
+            fixed: fix,
+        };
+        if fix {
+            self.fix(&report).await?;
+        }
+        Ok(report)
+    }
+
+    async fn fix(&self, report: &VerifyReport) -> Result<()> {
+        let db = DbMySql2::new(self.ym, self.baglama.clone()).await?;
+        let table = DbMySql2::viewdata_table(&self.ym);
+        let mut conn = self.baglama.get_tooldb_conn().await?;
+
+        if report
+            .findings
+            .iter()
+            .any(|f| f.kind == CheckKind::MissingGroup)
+        {
+            db.start_missing_groups().await?;
+        }
+
+        let orphans = report.group_status_ids(CheckKind::OrphanRows);
+        if !orphans.is_empty() {
+            let sql = format!(
+                "DELETE v FROM `{table}` v
+                LEFT JOIN files ON files.id=v.files_id
+                LEFT JOIN pages ON pages.id=v.pages_id
+                WHERE files.id IS NULL OR pages.id IS NULL"
+            );
+            conn.exec_drop(sql, ()).await?;
+        }
+
+        let incomplete = report.group_status_ids(CheckKind::IncompleteViews);
+        if !incomplete.is_empty() {
+            let sql = format!(
+                "UPDATE group_status SET `status`='SCANNED' WHERE id IN ({})",
+                Baglama2::sql_placeholders(incomplete.len())
+            );
+            conn.exec_drop(sql, incomplete.clone()).await?;
+        }
+
+        let mut reset: BTreeSet<DbId> = incomplete.into_iter().collect();
+        reset.extend(orphans);
+        reset.extend(report.group_status_ids(CheckKind::TotalMismatch));
+        if !reset.is_empty() {
+            let reset: Vec<DbId> = reset.into_iter().collect();
+            let placeholders = Baglama2::sql_placeholders(reset.len());
+            let sql = format!("DELETE FROM `gs2site` WHERE group_status_id IN ({placeholders})");
+            conn.exec_drop(sql, reset.clone()).await?;
+            let sql = format!(
+                "UPDATE group_status SET total_views=NULL,usage_views=NULL,excluded_views=NULL WHERE id IN ({placeholders})"
+            );
+            conn.exec_drop(sql, reset.clone()).await?;
+            info!(
+                "Reset the totals of {} group(s) in {}",
+                reset.len(),
+                self.ym
+            );
+        }
+        // Only groups that are still complete get new totals
+        db.recalculate_totals().await?;
+        Ok(())
+    }
+
+    async fn viewdata_table_exists(&self) -> Result<bool> {
+        let sql = "SELECT COUNT(*) FROM information_schema.tables WHERE table_schema=DATABASE() AND table_name=?";
+        let count: Option<usize> = self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_first(sql, (DbMySql2::viewdata_table(&self.ym),))
+            .await?;
+        Ok(count.unwrap_or_default() > 0)
+    }
+
+    async fn check_missing_groups(&self) -> Result<Vec<Finding>> {
+        let sql = "SELECT id FROM groups WHERE is_active=1
+            AND NOT EXISTS (SELECT * FROM group_status WHERE group_id=groups.id AND year=? AND month=?)";
+        let rows = self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, (self.ym.year(), self.ym.month()))
+            .await?
+            .map_and_drop(from_row::<DbId>)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|group_id| Finding {
+                kind: CheckKind::MissingGroup,
+                group_status_id: None,
+                group_id: Some(group_id),
+                details: format!("active group has no status for {}", self.ym),
+            })
+            .collect())
+    }
+
+    async fn check_incomplete_views(&self) -> Result<Vec<Finding>> {
+        let table = DbMySql2::viewdata_table(&self.ym);
+        let sql = format!(
+            "SELECT gs.id,gs.group_id,COUNT(*) FROM group_status gs,`{table}` v
+            WHERE v.group_status_id=gs.id AND v.page_views IS NULL
+            AND gs.year=? AND gs.month=? AND gs.status='VIEW DATA COMPLETE' AND gs.storage='mysql2'
+            GROUP BY gs.id,gs.group_id"
+        );
+        let rows = self.group_rows(&sql).await?;
+        Ok(rows
+            .into_iter()
+            .map(|(group_status_id, group_id, count)| Finding {
+                kind: CheckKind::IncompleteViews,
+                group_status_id: Some(group_status_id),
+                group_id: Some(group_id),
+                details: format!("{count} row(s) without views"),
+            })
+            .collect())
+    }
+
+    async fn check_orphan_rows(&self) -> Result<Vec<Finding>> {
+        let table = DbMySql2::viewdata_table(&self.ym);
+        let sql = format!(
+            "SELECT gs.id,gs.group_id,COUNT(*) FROM `{table}` v
+            INNER JOIN group_status gs ON gs.id=v.group_status_id
+            LEFT JOIN files ON files.id=v.files_id
+            LEFT JOIN pages ON pages.id=v.pages_id
+            WHERE (files.id IS NULL OR pages.id IS NULL) AND gs.year=? AND gs.month=?
+            GROUP BY gs.id,gs.group_id"
+        );
+        let rows = self.group_rows(&sql).await?;
+        Ok(rows
+            .into_iter()
+            .map(|(group_status_id, group_id, count)| Finding {
+                kind: CheckKind::OrphanRows,
+                group_status_id: Some(group_status_id),
+                group_id: Some(group_id),
+                details: format!("{count} row(s) with a missing file or page"),
+            })
+            .collect())
+    }
+
+    /// `total_views` counts each non-excluded page once, with its highest view count.
+    async fn check_total_mismatch(&self) -> Result<Vec<Finding>> {
+        let table = DbMySql2::viewdata_table(&self.ym);
+        let sql = format!(
+            "SELECT gs.id,gs.group_id,gs.total_views,COALESCE(t.views,0) FROM group_status gs
+            LEFT JOIN (
+                SELECT group_status_id,SUM(views) AS views FROM (
+                    SELECT group_status_id,MAX(page_views) AS views FROM `{table}`
+                    WHERE excluded=0 GROUP BY group_status_id,pages_id
+                ) p GROUP BY group_status_id
+            ) t ON t.group_status_id=gs.id
+            WHERE gs.year=? AND gs.month=? AND gs.status='VIEW DATA COMPLETE' AND gs.storage='mysql2'
+            AND NOT (gs.total_views <=> COALESCE(t.views,0))"
+        );
+        let rows = self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, (self.ym.year(), self.ym.month()))
+            .await?
+            .map_and_drop(from_row::<(DbId, DbId, Option<u64>, u64)>)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(group_status_id, group_id, total_views, views)| Finding {
+                kind: CheckKind::TotalMismatch,
+                group_status_id: Some(group_status_id),
+                group_id: Some(group_id),
+                details: match total_views {
+                    Some(total_views) => {
+                        format!("total_views is {total_views}, viewdata has {views}")
+                    }
+                    None => format!("total_views is missing, viewdata has {views}"),
+                },
+            })
+            .collect())
+    }
+
+    async fn check_duplicate_pages(&self) -> Result<Vec<Finding>> {
+        let table = DbMySql2::viewdata_table(&self.ym);
+        let sql = format!(
+            "SELECT COALESCE(sites.server,''),FROM_BASE64(TO_BASE64(pages.title)),GROUP_CONCAT(pages.id ORDER BY pages.id),GROUP_CONCAT(pages.namespace_id ORDER BY pages.id)
+            FROM pages,sites
+            WHERE sites.id=pages.site AND pages.id IN (SELECT DISTINCT pages_id FROM `{table}`)
+            GROUP BY pages.site,pages.title
+            HAVING COUNT(DISTINCT pages.namespace_id)>1"
+        );
+        let rows = self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, ())
+            .await?
+            .map_and_drop(from_row::<(String, String, String, String)>)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(server, title, ids, namespaces)| Finding {
+                kind: CheckKind::DuplicatePages,
+                group_status_id: None,
+                group_id: None,
+                details: format!("{server}: '{title}' as pages {ids} in namespaces {namespaces}"),
+            })
+            .collect())
+    }
+
+    /// Rows of (group_status_id, group_id, count) for the month.
+    async fn group_rows(&self, sql: &str) -> Result<Vec<(DbId, DbId, usize)>> {
+        let rows = self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, (self.ym.year(), self.ym.month()))
+            .await?
+            .map_and_drop(from_row::<(DbId, DbId, usize)>)
+            .await?;
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finding(kind: CheckKind, group_status_id: Option<DbId>) -> Finding {
+        Finding {
+            kind,
+            group_status_id,
+            group_id: Some(1),
+            details: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let report = VerifyReport {
+            ym: YearMonth::new(2024, 3).unwrap(),
+            findings: vec![
+                finding(CheckKind::TotalMismatch, Some(7)),
+                finding(CheckKind::TotalMismatch, Some(3)),
+                finding(CheckKind::OrphanRows, Some(7)),
+                finding(CheckKind::DuplicatePages, None),
+            ],
+            fixed: false,
+        };
+        assert_eq!(
+            report.group_status_ids(CheckKind::TotalMismatch),
+            vec![3, 7]
+        );
+        assert!(report
+            .group_status_ids(CheckKind::IncompleteViews)
+            .is_empty());
+        let json = report.to_json();
+        assert_eq!(json["month"], "2024-03");
+        assert_eq!(json["counts"]["total_mismatch"], 2);
+        assert_eq!(json["counts"]["orphan_rows"], 1);
+        assert_eq!(json["findings"][3]["check"], "duplicate_pages");
+        assert_eq!(json["findings"][3]["fixable"], false);
+        assert!(json["findings"][0]["repair"]
+            .as_str()
+            .unwrap()
+            .contains("recalculate"));
+    }
+}
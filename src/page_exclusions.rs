@@ -0,0 +1,328 @@
+//! Pages whose views are left out of group totals: the main page of each
+//! wiki, pages listed in the `page_exclusions` config key, and per-group
+//! `page` rules in `group_exclusions`.
+//!
+//! Pages are written as `<server>:<namespace_id>:<title>`, for example
+//! `de.wikipedia.org:4:Hauptseite`. Excluded rows are flagged, not deleted;
+//! their views are reported as `excluded_views` in `group_status`. Main pages
+//! are resolved from the site info of each wiki, and cached in the
+//! `main_pages` tool DB table.
+
+use crate::{
+    group_exclusions::{ExclusionType, GroupExclusions},
+    page::Page,
+    Baglama2, DbId, Site,
+};
+use anyhow::{anyhow, Result};
+use log::warn;
+use mysql_async::{from_row, prelude::*};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ExcludedPage {
+    pub server: String,
+    pub namespace_id: i32,
+    pub title: String,
+}
+
+impl ExcludedPage {
+    pub fn new(server: &str, namespace_id: i32, title: &str) -> Self {
+        Self {
+            server: server.trim().to_string(),
+            namespace_id,
+            title: title.trim().replace(' ', "_"),
+        }
+    }
+
+    /// Parses `<server>:<namespace_id>:<title>`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(3, ':');
+        match (
+            parts.next(),
+            parts.next().and_then(|ns| ns.trim().parse::<i32>().ok()),
+            parts.next(),
+        ) {
+            (Some(server), Some(namespace_id), Some(title))
+                if !server.trim().is_empty() && !title.trim().is_empty() =>
+            {
+                Ok(Self::new(server, namespace_id, title))
+            }
+            _ => Err(anyhow!(
+                "Bad page '{s}': <server>:<namespace_id>:<title> expected"
+            )),
+        }
+    }
+
+    /// Splits a prefixed title into namespace and title, using the
+    /// `namespaces` site info of the wiki.
+    pub fn from_prefixed(server: &str, prefixed: &str, namespaces: &Value) -> Self {
+        let prefixed = prefixed.replace('_', " ");
+        if let (Some((prefix, title)), Some(namespaces)) =
+            (prefixed.split_once(':'), namespaces.as_object())
+        {
+            let namespace_id = namespaces
+                .values()
+                .filter(|ns| ns["id"].as_i64() != Some(0))
+                .find(|ns| {
+                    [ns["*"].as_str(), ns["canonical"].as_str()]
+                        .iter()
+                        .flatten()
+                        .any(|name| *name == prefix)
+                })
+                .and_then(|ns| ns["id"].as_i64());
+            if let Some(namespace_id) = namespace_id {
+                return Self::new(server, namespace_id as i32, title);
+            }
+        }
+        Self::new(server, 0, &prefixed)
+    }
+}
+
+impl fmt::Display for ExcludedPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.server, self.namespace_id, self.title)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PageExclusions {
+    pages: HashSet<ExcludedPage>,
+}
+
+impl PageExclusions {
+    pub fn new(pages: Vec<ExcludedPage>) -> Self {
+        Self {
+            pages: pages.into_iter().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    pub fn extend(&mut self, pages: Vec<ExcludedPage>) {
+        self.pages.extend(pages);
+    }
+
+    pub fn excludes(&self, page: &ExcludedPage) -> bool {
+        self.pages.contains(page)
+    }
+
+    /// The excluded pages on known sites, as tool DB pages (without ID).
+    pub fn site_pages(&self, sites: &[Site]) -> Vec<Page> {
+        let server2id: HashMap<&str, DbId> = sites
+            .iter()
+            .filter_map(|site| Some((site.server().as_deref()?, site.id())))
+            .collect();
+        let mut ret: Vec<Page> = self
+            .pages
+            .iter()
+            .filter_map(|page| {
+                let site_id = server2id.get(page.server.as_str())?;
+                Some(Page::new(
+                    *site_id,
+                    page.title.to_owned(),
+                    page.namespace_id,
+                ))
+            })
+            .collect();
+        ret.sort();
+        ret
+    }
+
+    /// Main pages, and pages from the config; these apply to all groups.
+    pub async fn load_global(baglama: &Baglama2) -> Result<Self> {
+        let mut ret = Self::new(Self::main_pages(baglama).await?);
+        ret.extend(Self::from_config(baglama.config())?);
+        Ok(ret)
+    }
+
+    pub fn from_config(config: &Value) -> Result<Vec<ExcludedPage>> {
+        match config["page_exclusions"].as_array() {
+            Some(pages) => pages
+                .iter()
+                .map(|page| {
+                    ExcludedPage::parse(
+                        page.as_str()
+                            .ok_or_else(|| anyhow!("page_exclusions: string expected"))?,
+                    )
+                })
+                .collect(),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// The `page` rules of a group.
+    pub async fn load_for_group(baglama: &Baglama2, group_id: DbId) -> Result<Vec<ExcludedPage>> {
+        GroupExclusions::list(baglama, group_id)
+            .await?
+            .into_iter()
+            .filter(|(exclusion_type, _)| *exclusion_type == ExclusionType::Page)
+            .map(|(_, value)| ExcludedPage::parse(&value))
+            .collect()
+    }
+
+    /// The `page` rules of all groups that have any.
+    pub async fn load_group_rules(baglama: &Baglama2) -> Result<HashMap<DbId, Vec<ExcludedPage>>> {
+        let mut ret: HashMap<DbId, Vec<ExcludedPage>> = HashMap::new();
+        for (group_id, value) in GroupExclusions::list_type(baglama, ExclusionType::Page).await? {
+            ret.entry(group_id)
+                .or_default()
+                .push(ExcludedPage::parse(&value)?);
+        }
+        Ok(ret)
+    }
+
+    /// The cached main pages, resolving those of sites not seen before.
+    pub async fn main_pages(baglama: &Baglama2) -> Result<Vec<ExcludedPage>> {
+        Self::update_main_pages(baglama, false).await?;
+        let sql = "SELECT sites.server,main_pages.namespace_id,FROM_BASE64(TO_BASE64(main_pages.title))
+            FROM `main_pages`,`sites`
+            WHERE sites.id=main_pages.site_id AND main_pages.title IS NOT NULL AND sites.server IS NOT NULL";
+        let rows = baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, ())
+            .await?
+            .map_and_drop(from_row::<(String, i32, String)>)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(server, namespace_id, title)| ExcludedPage::new(&server, namespace_id, &title))
+            .collect())
+    }
+
+    /// Resolves the main pages of sites without a cached one, or of all sites
+    /// if `refresh` is set. Sites that can not be resolved are cached without
+    /// a title, and tried again next time. Returns the number of sites resolved.
+    pub async fn update_main_pages(baglama: &Baglama2, refresh: bool) -> Result<usize> {
+        Self::ensure_table_exists(baglama).await?;
+        let mut conn = baglama.get_tooldb_conn().await?;
+        if refresh {
+            conn.exec_drop("DELETE FROM `main_pages`", ()).await?;
+        }
+        let known: HashSet<DbId> = conn
+            .exec_iter(
+                "SELECT `site_id` FROM `main_pages` WHERE `title` IS NOT NULL",
+                (),
+            )
+            .await?
+            .map_and_drop(from_row::<DbId>)
+            .await?
+            .into_iter()
+            .collect();
+        let mut resolved = 0;
+        for site in baglama.get_sites()? {
+            if known.contains(&site.id()) {
+                continue;
+            }
+            let main_page = match Self::resolve_main_page(baglama, &site).await {
+                Ok(main_page) => main_page,
+                Err(e) => {
+                    warn!("No main page for {:?}: {e}", site.server());
+                    None
+                }
+            };
+            let (namespace_id, title) = match &main_page {
+                Some(page) => (Some(page.namespace_id), Some(page.title.to_owned())),
+                None => (None, None),
+            };
+            let sql = "REPLACE INTO `main_pages` (`site_id`,`namespace_id`,`title`) VALUES (?,?,?)";
+            conn.exec_drop(sql, (site.id(), namespace_id, title))
+                .await?;
+            resolved += 1;
+        }
+        Ok(resolved)
+    }
+
+    async fn resolve_main_page(baglama: &Baglama2, site: &Site) -> Result<Option<ExcludedPage>> {
+        let (Some(server), Some(wiki)) = (site.server(), site.giu_code()) else {
+            return Ok(None);
+        };
+        let site_info = baglama.site_info(wiki).await?;
+        Ok(site_info["general"]["mainpage"].as_str().map(|main_page| {
+            ExcludedPage::from_prefixed(server, main_page, &site_info["namespaces"])
+        }))
+    }
+
+    async fn ensure_table_exists(baglama: &Baglama2) -> Result<()> {
+        let sql = "CREATE TABLE IF NOT EXISTS `main_pages` (
+              `site_id` int(11) unsigned NOT NULL,
+              `namespace_id` int(11) DEFAULT NULL,
+              `title` varbinary(255) DEFAULT NULL,
+              PRIMARY KEY (`site_id`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4";
+        baglama.get_tooldb_conn().await?.exec_drop(sql, ()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        let page = ExcludedPage::parse("de.wikipedia.org:4:Haupt seite").unwrap();
+        assert_eq!(
+            page,
+            ExcludedPage::new("de.wikipedia.org", 4, "Haupt_seite")
+        );
+        assert_eq!(page.to_string(), "de.wikipedia.org:4:Haupt_seite");
+        // Colons in titles are kept
+        let page = ExcludedPage::parse("en.wikipedia.org:0:Star Wars: Episode I").unwrap();
+        assert_eq!(page.title, "Star_Wars:_Episode_I");
+        assert!(ExcludedPage::parse("de.wikipedia.org:Hauptseite").is_err());
+        assert!(ExcludedPage::parse("de.wikipedia.org:x:Hauptseite").is_err());
+        assert!(ExcludedPage::parse(":0:Hauptseite").is_err());
+    }
+
+    #[test]
+    fn test_from_prefixed() {
+        let namespaces = json!({
+            "0": {"id": 0, "*": ""},
+            "4": {"id": 4, "*": "Wikipedia", "canonical": "Project"},
+            "100": {"id": 100, "*": "Portal", "canonical": "Portal"},
+        });
+        assert_eq!(
+            ExcludedPage::from_prefixed("de.wikipedia.org", "Wikipedia:Hauptseite", &namespaces),
+            ExcludedPage::new("de.wikipedia.org", 4, "Hauptseite")
+        );
+        assert_eq!(
+            ExcludedPage::from_prefixed(
+                "fr.wikipedia.org",
+                "Wikipédia:Accueil principal",
+                &namespaces
+            ),
+            ExcludedPage::new("fr.wikipedia.org", 0, "Wikipédia:Accueil_principal")
+        );
+        assert_eq!(
+            ExcludedPage::from_prefixed("en.wikipedia.org", "Main Page", &namespaces),
+            ExcludedPage::new("en.wikipedia.org", 0, "Main_Page")
+        );
+    }
+
+    #[test]
+    fn test_site_pages() {
+        let sites = vec![
+            Site::new(1, Some("de.wikipedia.org".to_string())),
+            Site::new(2, Some("en.wikipedia.org".to_string())),
+        ];
+        let config =
+            json!({"page_exclusions": ["en.wikipedia.org:0:Main_Page", "xx.example.org:0:Foo"]});
+        let mut exclusions = PageExclusions::new(PageExclusions::from_config(&config).unwrap());
+        exclusions.extend(vec![ExcludedPage::new("de.wikipedia.org", 4, "Hauptseite")]);
+        assert!(exclusions.excludes(&ExcludedPage::new("en.wikipedia.org", 0, "Main Page")));
+        assert_eq!(
+            exclusions.site_pages(&sites),
+            vec![
+                Page::new(1, "Hauptseite".to_string(), 4),
+                Page::new(2, "Main_Page".to_string(), 0),
+            ]
+        );
+        assert!(PageExclusions::from_config(&json!({"page_exclusions": [1]})).is_err());
+    }
+}
@@ -34,11 +34,7 @@ where
     F: Fn(HashMap<usize, u64>) -> Fut,
     Fut: std::future::Future<Output = Result<()>>,
 {
-    let pv = Pageviews::new(
-        PageviewsGranularity::Monthly,
-        PageviewsAccess::All,
-        PageviewsAgent::User,
-    );
+    let pv = monthly_user_pageviews();
     let sql = format!(
         "SELECT DISTINCT `vd`.`pages_id`,`server`,FROM_BASE64(TO_BASE64(`title`))
          FROM `{table_name}` AS `vd`,`pages`,`sites`
@@ -66,27 +62,8 @@ where
             rows[0].0
         );
 
-        let page2id = rows
-            .into_iter()
-            .filter(|(_id, _server, title)| !title.is_empty())
-            .filter_map(|(id, server, title)| {
-                let title_with_underscores = title.replace(' ', "_");
-                let project = server.strip_suffix(".org")?.to_string();
-                Some(((project, title_with_underscores), id))
-            })
-            .collect::<HashMap<(String, String), usize>>();
-        let project_pages = page2id.keys().cloned().collect::<Vec<_>>();
-
-        let results = pv
-            .get_multiple_articles(
-                &project_pages,
-                &Pageviews::month_start(ym.year(), ym.month()).unwrap(),
-                &Pageviews::month_end(ym.year(), ym.month()).unwrap(),
-                5,
-            )
-            .await;
-        let results = match results {
-            Ok(results) => results,
+        let id2views = match fetch_monthly_views(&pv, ym, rows).await {
+            Ok(id2views) => id2views,
             Err(e) => {
                 error!("Error getting pageviews: {}. Waiting 5 seconds.", e);
                 tokio::time::sleep(Duration::from_secs(5)).await;
@@ -94,20 +71,58 @@ where
             }
         };
 
-        let mut id2views: HashMap<usize, u64> = page2id.values().map(|id| (*id, 0)).collect();
-        for result in results {
-            let key = (result.project.to_owned(), result.article.to_owned());
-            match page2id.get(&key) {
-                Some(id) => {
-                    id2views.insert(*id, result.total_views());
-                }
-                None => {
-                    error!("Page not found: {:?}", key);
-                }
-            }
-        }
-
         flush_fn(id2views).await?;
     }
     Ok(())
 }
+
+/// Monthly views by users, on all access methods.
+pub fn monthly_user_pageviews() -> Pageviews {
+    Pageviews::new(
+        PageviewsGranularity::Monthly,
+        PageviewsAccess::All,
+        PageviewsAgent::User,
+    )
+}
+
+/// Fetches the views of `(id, server, title)` pages for a month, by ID.
+/// Pages without a result get 0 views.
+pub async fn fetch_monthly_views(
+    pv: &Pageviews,
+    ym: &YearMonth,
+    pages: Vec<(usize, String, String)>,
+) -> Result<HashMap<usize, u64>> {
+    let page2id = pages
+        .into_iter()
+        .filter(|(_id, _server, title)| !title.is_empty())
+        .filter_map(|(id, server, title)| {
+            let title_with_underscores = title.replace(' ', "_");
+            let project = server.strip_suffix(".org")?.to_string();
+            Some(((project, title_with_underscores), id))
+        })
+        .collect::<HashMap<(String, String), usize>>();
+    let project_pages = page2id.keys().cloned().collect::<Vec<_>>();
+
+    let results = pv
+        .get_multiple_articles(
+            &project_pages,
+            &Pageviews::month_start(ym.year(), ym.month()).unwrap(),
+            &Pageviews::month_end(ym.year(), ym.month()).unwrap(),
+            5,
+        )
+        .await?;
+
+    let mut id2views: HashMap<usize, u64> = page2id.values().map(|id| (*id, 0)).collect();
+    for result in results {
+        let key = (result.project.to_owned(), result.article.to_owned());
+        match page2id.get(&key) {
+            Some(id) => {
+                id2views.insert(*id, result.total_views());
+            }
+            None => {
+                error!("Page not found: {:?}", key);
+            }
+        }
+    }
+    Ok(id2views)
+}
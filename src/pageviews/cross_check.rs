@@ -0,0 +1,268 @@
+//! Cross-check of stored page views against the per-article REST API.
+//!
+//! Takes a random sample of pages from the viewdata of a finished month,
+//! fetches their views via [`api_fallback`](super::api_fallback), and reports
+//! the mismatch rate per wiki and namespace. A title normalisation or
+//! namespace bug in the dump matcher shows up as a wiki or namespace with a
+//! high mismatch rate.
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use mysql_async::{from_row, prelude::*};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+use super::api_fallback;
+use crate::{db_mysql2::DbMySql2, Baglama2, DbId, YearMonth};
+
+pub const DEFAULT_SAMPLE_SIZE: usize = 1000;
+/// Relative difference up to which stored and API views are considered equal.
+pub const DEFAULT_TOLERANCE: f64 = 0.01;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrossCheckSample {
+    pub pages_id: DbId,
+    pub server: String,
+    pub namespace_id: i32,
+    pub title: String,
+    pub stored_views: u64,
+    pub api_views: u64,
+}
+
+impl CrossCheckSample {
+    pub fn is_mismatch(&self, tolerance: f64) -> bool {
+        let diff = self.stored_views.abs_diff(self.api_views);
+        diff > 0 && diff as f64 > tolerance * self.stored_views.max(self.api_views) as f64
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "pages_id": self.pages_id,
+            "server": self.server,
+            "namespace_id": self.namespace_id,
+            "title": self.title,
+            "stored_views": self.stored_views,
+            "api_views": self.api_views,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MismatchStats {
+    pub sampled: usize,
+    pub mismatches: usize,
+}
+
+impl MismatchStats {
+    pub fn rate(&self) -> f64 {
+        if self.sampled == 0 {
+            0.0
+        } else {
+            self.mismatches as f64 / self.sampled as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrossCheckReport {
+    pub ym: YearMonth,
+    pub pageview_source: Option<String>,
+    pub tolerance: f64,
+    pub samples: Vec<CrossCheckSample>,
+}
+
+impl CrossCheckReport {
+    /// Mismatch statistics per (server, namespace).
+    pub fn stats(&self) -> BTreeMap<(String, i32), MismatchStats> {
+        let mut ret: BTreeMap<(String, i32), MismatchStats> = BTreeMap::new();
+        for sample in &self.samples {
+            let stats = ret
+                .entry((sample.server.to_owned(), sample.namespace_id))
+                .or_default();
+            stats.sampled += 1;
+            if sample.is_mismatch(self.tolerance) {
+                stats.mismatches += 1;
+            }
+        }
+        ret
+    }
+
+    pub fn total(&self) -> MismatchStats {
+        MismatchStats {
+            sampled: self.samples.len(),
+            mismatches: self
+                .samples
+                .iter()
+                .filter(|s| s.is_mismatch(self.tolerance))
+                .count(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let total = self.total();
+        json!({
+            "month": self.ym.to_string(),
+            "pageview_source": self.pageview_source,
+            "tolerance": self.tolerance,
+            "sampled": total.sampled,
+            "mismatches": total.mismatches,
+            "mismatch_rate": total.rate(),
+            "by_wiki_namespace": self.stats().iter().map(|((server, namespace_id), stats)| json!({
+                "server": server,
+                "namespace_id": namespace_id,
+                "sampled": stats.sampled,
+                "mismatches": stats.mismatches,
+                "mismatch_rate": stats.rate(),
+            })).collect::<Vec<_>>(),
+            "mismatched_pages": self.samples.iter()
+                .filter(|s| s.is_mismatch(self.tolerance))
+                .map(|s| s.to_json())
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+pub struct CrossCheck<'a> {
+    baglama: &'a Baglama2,
+    ym: YearMonth,
+    sample_size: usize,
+    tolerance: f64,
+}
+
+impl<'a> CrossCheck<'a> {
+    pub fn new(baglama: &'a Baglama2, ym: YearMonth, sample_size: usize, tolerance: f64) -> Self {
+        Self {
+            baglama,
+            ym,
+            sample_size,
+            tolerance,
+        }
+    }
+
+    pub async fn run(&self) -> Result<CrossCheckReport> {
+        let pageview_source = DbMySql2::get_pageview_source(self.baglama, &self.ym).await?;
+        if pageview_source.is_none() {
+            return Err(anyhow!("No views have been loaded for {}", self.ym));
+        }
+        let mut pages = self.load_sample().await?;
+
+        // The API needs the full title, with namespace prefix
+        let mut api_pages = vec![];
+        for (pages_id, server, wiki, namespace_id, title, _views) in &pages {
+            match self
+                .baglama
+                .prefix_with_namespace(title, *namespace_id, wiki)
+                .await
+            {
+                Some(full_title) => api_pages.push((*pages_id, server.to_owned(), full_title)),
+                None => warn!("No namespace {namespace_id} prefix for {wiki}, skipping {title}"),
+            }
+        }
+        let pv = api_fallback::monthly_user_pageviews();
+        let id2views = api_fallback::fetch_monthly_views(&pv, &self.ym, api_pages).await?;
+
+        pages.retain(|(pages_id, ..)| id2views.contains_key(pages_id));
+        let samples = pages
+            .into_iter()
+            .map(
+                |(pages_id, server, _wiki, namespace_id, title, stored_views)| CrossCheckSample {
+                    pages_id,
+                    server,
+                    namespace_id,
+                    title,
+                    stored_views,
+                    api_views: id2views[&pages_id],
+                },
+            )
+            .collect();
+        Ok(CrossCheckReport {
+            ym: self.ym,
+            pageview_source,
+            tolerance: self.tolerance,
+            samples,
+        })
+    }
+
+    /// Random pages with views: (pages_id, server, wiki, namespace_id, title, views).
+    async fn load_sample(&self) -> Result<Vec<(DbId, String, String, i32, String, u64)>> {
+        let table = DbMySql2::viewdata_table(&self.ym);
+        let sql = format!(
+            "SELECT pages.id,COALESCE(sites.server,''),COALESCE(sites.giu_code,''),pages.namespace_id,FROM_BASE64(TO_BASE64(pages.title)),t.views
+            FROM (
+                SELECT pages_id,MAX(page_views) AS views FROM `{table}`
+                WHERE page_views IS NOT NULL
+                GROUP BY pages_id
+                ORDER BY RAND()
+                LIMIT ?
+            ) t,pages,sites
+            WHERE pages.id=t.pages_id AND sites.id=pages.site"
+        );
+        let rows = self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, (self.sample_size,))
+            .await?
+            .map_and_drop(from_row::<(DbId, String, String, i32, String, u64)>)
+            .await?;
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(
+        server: &str,
+        namespace_id: i32,
+        stored_views: u64,
+        api_views: u64,
+    ) -> CrossCheckSample {
+        CrossCheckSample {
+            pages_id: 1,
+            server: server.to_string(),
+            namespace_id,
+            title: "X".to_string(),
+            stored_views,
+            api_views,
+        }
+    }
+
+    #[test]
+    fn test_is_mismatch() {
+        assert!(!sample("a", 0, 1000, 1005).is_mismatch(0.01));
+        assert!(sample("a", 0, 1000, 1100).is_mismatch(0.01));
+        assert!(sample("a", 0, 0, 3).is_mismatch(0.01));
+        assert!(!sample("a", 0, 0, 0).is_mismatch(0.01));
+    }
+
+    #[test]
+    fn test_stats() {
+        let report = CrossCheckReport {
+            ym: YearMonth::new(2024, 2).unwrap(),
+            pageview_source: Some("dump".to_string()),
+            tolerance: DEFAULT_TOLERANCE,
+            samples: vec![
+                sample("de.wikipedia.org", 0, 100, 100),
+                sample("de.wikipedia.org", 0, 100, 100),
+                sample("de.wikipedia.org", 4, 0, 50),
+                sample("en.wikipedia.org", 0, 10, 12),
+            ],
+        };
+        let stats = report.stats();
+        assert_eq!(
+            stats[&("de.wikipedia.org".to_string(), 0)],
+            MismatchStats {
+                sampled: 2,
+                mismatches: 0
+            }
+        );
+        assert_eq!(stats[&("de.wikipedia.org".to_string(), 4)].rate(), 1.0);
+        assert_eq!(report.total().mismatches, 2);
+        let json = report.to_json();
+        assert_eq!(json["mismatch_rate"], 0.5);
+        assert_eq!(json["by_wiki_namespace"].as_array().unwrap().len(), 3);
+        assert_eq!(json["mismatched_pages"].as_array().unwrap().len(), 2);
+    }
+}
@@ -2,6 +2,8 @@
 //!
 //! The dump reader (`dump_reader`) is designed to be self-contained with
 //! no MySQL dependency, making it easy to extract into a standalone library.
+//! `cross_check` compares stored views with the API on a sample of pages.
 
 pub mod api_fallback;
+pub mod cross_check;
 pub mod dump_reader;
@@ -0,0 +1,230 @@
+//! Exports the viewdata of a mysql2 month to Parquet, one file per group:
+//! `<dir>/group_id=<group_id>/<YYYY-MM>.parquet`.
+//!
+//! Rows are read from MySQL in batches (keyset pagination on the viewdata
+//! ID) and written as one row group per batch, so memory use is bounded by
+//! the batch size, not the table size.
+
+use crate::{db_mysql2::DbMySql2, Baglama2, DbId, YearMonth};
+use anyhow::Result;
+use arrow::array::{ArrayRef, BooleanArray, Int32Array, StringArray, UInt64Array};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use log::info;
+use mysql_async::{from_row, prelude::*};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const PARQUET_BATCH_SIZE: usize = 50_000;
+
+/// A joined viewdata row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ViewdataRow {
+    pub id: DbId,
+    pub file: String,
+    pub server: String,
+    pub namespace_id: i32,
+    pub title: String,
+    pub page_views: Option<u64>,
+    /// Excluded pages (e.g. main pages) are left out of the group totals.
+    pub excluded: bool,
+}
+
+pub struct ParquetExport<'a> {
+    baglama: &'a Baglama2,
+    ym: YearMonth,
+    dir: PathBuf,
+}
+
+impl<'a> ParquetExport<'a> {
+    pub fn new(baglama: &'a Baglama2, ym: YearMonth, dir: &str) -> Self {
+        Self {
+            baglama,
+            ym,
+            dir: PathBuf::from(dir),
+        }
+    }
+
+    pub fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("file", DataType::Utf8, false),
+            Field::new("server", DataType::Utf8, false),
+            Field::new("namespace_id", DataType::Int32, false),
+            Field::new("title", DataType::Utf8, false),
+            Field::new("page_views", DataType::UInt64, true),
+            Field::new("excluded", DataType::Boolean, false),
+        ]))
+    }
+
+    pub fn to_record_batch(rows: &[ViewdataRow]) -> Result<RecordBatch> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(
+                rows.iter().map(|r| r.file.as_str()),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                rows.iter().map(|r| r.server.as_str()),
+            )),
+            Arc::new(Int32Array::from_iter_values(
+                rows.iter().map(|r| r.namespace_id),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                rows.iter().map(|r| r.title.as_str()),
+            )),
+            Arc::new(UInt64Array::from_iter(rows.iter().map(|r| r.page_views))),
+            Arc::new(BooleanArray::from(
+                rows.iter().map(|r| r.excluded).collect::<Vec<_>>(),
+            )),
+        ];
+        Ok(RecordBatch::try_new(Self::schema(), columns)?)
+    }
+
+    pub fn group_path(dir: &Path, group_id: DbId, ym: &YearMonth) -> PathBuf {
+        dir.join(format!("group_id={group_id}"))
+            .join(format!("{ym}.parquet"))
+    }
+
+    /// Exports all complete mysql2 groups of the month. Returns the number of
+    /// files written.
+    pub async fn run(&self) -> Result<usize> {
+        let sql = "SELECT `id`,`group_id` FROM `group_status` WHERE `year`=? AND `month`=? AND `storage`='mysql2' AND `status`='VIEW DATA COMPLETE' ORDER BY `group_id`";
+        let group_status = self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, (self.ym.year(), self.ym.month()))
+            .await?
+            .map_and_drop(from_row::<(DbId, DbId)>)
+            .await?;
+        for (gs_id, group_id) in &group_status {
+            let rows = self.export_group(*gs_id, *group_id).await?;
+            info!("Group {group_id}: {rows} rows exported");
+        }
+        Ok(group_status.len())
+    }
+
+    /// Writes one group's viewdata to its Parquet file. Returns the number of rows.
+    pub async fn export_group(&self, gs_id: DbId, group_id: DbId) -> Result<usize> {
+        let path = Self::group_path(&self.dir, group_id, &self.ym);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let path_tmp = path.with_extension("parquet.tmp");
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let mut writer = ArrowWriter::try_new(
+            std::fs::File::create(&path_tmp)?,
+            Self::schema(),
+            Some(props),
+        )?;
+        let mut total = 0;
+        let mut last_id = 0;
+        loop {
+            let rows = self.load_batch(gs_id, last_id).await?;
+            let Some(last) = rows.last() else { break };
+            last_id = last.id;
+            total += rows.len();
+            writer.write(&Self::to_record_batch(&rows)?)?;
+            writer.flush()?;
+            if rows.len() < PARQUET_BATCH_SIZE {
+                break;
+            }
+        }
+        writer.close()?;
+        std::fs::rename(&path_tmp, &path)?;
+        Ok(total)
+    }
+
+    async fn load_batch(&self, gs_id: DbId, after_id: DbId) -> Result<Vec<ViewdataRow>> {
+        let table = DbMySql2::viewdata_table(&self.ym);
+        let sql = format!(
+            "SELECT v.id,FROM_BASE64(TO_BASE64(files.name)),COALESCE(sites.server,''),pages.namespace_id,FROM_BASE64(TO_BASE64(pages.title)),v.page_views,v.excluded
+            FROM `{table}` v,files,pages,sites
+            WHERE v.group_status_id=? AND v.id>? AND files.id=v.files_id AND pages.id=v.pages_id AND sites.id=pages.site
+            ORDER BY v.id
+            LIMIT ?"
+        );
+        let rows = self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, (gs_id, after_id, PARQUET_BATCH_SIZE))
+            .await?
+            .map_and_drop(from_row::<(DbId, String, String, i32, String, Option<u64>, bool)>)
+            .await?
+            .into_iter()
+            .map(
+                |(id, file, server, namespace_id, title, page_views, excluded)| ViewdataRow {
+                    id,
+                    file,
+                    server,
+                    namespace_id,
+                    title,
+                    page_views,
+                    excluded,
+                },
+            )
+            .collect();
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Array;
+
+    #[test]
+    fn test_to_record_batch() {
+        let rows = vec![
+            ViewdataRow {
+                id: 1,
+                file: "A.jpg".to_string(),
+                server: "de.wikipedia.org".to_string(),
+                namespace_id: 0,
+                title: "Berlin".to_string(),
+                page_views: Some(50),
+                excluded: false,
+            },
+            ViewdataRow {
+                id: 2,
+                file: "B.jpg".to_string(),
+                server: "en.wikipedia.org".to_string(),
+                namespace_id: 14,
+                title: "Berlin".to_string(),
+                page_views: None,
+                excluded: true,
+            },
+        ];
+        let batch = ParquetExport::to_record_batch(&rows).unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(batch.num_columns(), 6);
+        let views = batch
+            .column(4)
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        assert_eq!(views.value(0), 50);
+        assert!(views.is_null(1));
+        let excluded = batch
+            .column(5)
+            .as_any()
+            .downcast_ref::<BooleanArray>()
+            .unwrap();
+        assert!(!excluded.value(0));
+        assert!(excluded.value(1));
+        assert_eq!(excluded.null_count(), 0);
+    }
+
+    #[test]
+    fn test_group_path() {
+        let ym = YearMonth::new(2024, 3).unwrap();
+        assert_eq!(
+            ParquetExport::group_path(Path::new("/data"), 42, &ym),
+            PathBuf::from("/data/group_id=42/2024-03.parquet")
+        );
+    }
+}
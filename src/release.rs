@@ -0,0 +1,359 @@
+//! Open-data release of a finished month.
+//!
+//! Writes a versioned directory `<dir>/<YYYY-MM>/v<N>/` with bz2-compressed
+//! CSV files (per-group totals, per-site breakdowns, top files) and a
+//! `manifest.json` with checksums, row counts and the number of flagged view
+//! anomalies. Inactive groups, and groups
+//! with `groups.is_private=1`, are left out. A version is written to
+//! `v<N>.tmp` and only renamed to `v<N>` once complete.
+
+use crate::{
+    db_mysql2::DbMySql2,
+    group_report::{csv_field, GroupReport, DEFAULT_TOP_LIMIT},
+    row_group_status::{RowGroupStatus, StorageType},
+    view_anomalies::ViewAnomalies,
+    wiki_report::WikiReport,
+    Baglama2, DbId, GroupId, YearMonth,
+};
+use anyhow::{anyhow, Result};
+use bzip2::{write::BzEncoder, Compression};
+use log::info;
+use mysql_async::{from_row, prelude::*};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const RELEASE_GROUPS_SQL: &str = "SELECT `id` FROM `groups` WHERE `is_active`=1 AND `is_private`=0";
+
+/// The git commit the binary was built from, set by `build.rs`.
+pub const CODE_VERSION: &str = match option_env!("GIT_HASH") {
+    Some(hash) => hash,
+    None => "unknown",
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestFile {
+    pub name: String,
+    pub rows: usize,
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+impl ManifestFile {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "rows": self.rows,
+            "bytes": self.bytes,
+            "sha256": self.sha256,
+        })
+    }
+}
+
+pub struct Release<'a> {
+    baglama: &'a Baglama2,
+    ym: YearMonth,
+    dir: PathBuf,
+}
+
+impl<'a> Release<'a> {
+    pub fn new(baglama: &'a Baglama2, ym: YearMonth, dir: &str) -> Self {
+        Self {
+            baglama,
+            ym,
+            dir: PathBuf::from(dir),
+        }
+    }
+
+    /// Marks a group as private (excluded from releases), or public.
+    pub async fn set_private(baglama: &Baglama2, group_id: GroupId, private: bool) -> Result<()> {
+        let sql = "UPDATE `groups` SET `is_private`=? WHERE `id`=?";
+        baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_drop(sql, (private as u8, group_id.get()))
+            .await?;
+        Ok(())
+    }
+
+    /// Writes the release. Returns the release directory.
+    pub async fn run(&self) -> Result<PathBuf> {
+        let group_status = self.load_group_status().await?;
+        let month_dir = self.dir.join(self.ym.to_string());
+        let version = Self::next_version(&month_dir)?;
+        let release_dir = month_dir.join(format!("v{version}"));
+        // Written to a temporary directory first, so a failed run leaves no partial version
+        let release_dir_tmp = month_dir.join(format!("v{version}.tmp"));
+        if release_dir_tmp.exists() {
+            std::fs::remove_dir_all(&release_dir_tmp)?;
+        }
+        std::fs::create_dir_all(&release_dir_tmp)?;
+
+        let files = vec![
+            Self::write_csv(
+                &release_dir_tmp,
+                "group_totals.csv.bz2",
+                "group_id,category,total_views,usage_views,excluded_views",
+                &self.group_totals_rows(&group_status).await?,
+            )?,
+            Self::write_csv(
+                &release_dir_tmp,
+                "site_breakdown.csv.bz2",
+                "group_id,server,pages,views",
+                &self.site_breakdown_rows(&group_status).await?,
+            )?,
+            Self::write_csv(
+                &release_dir_tmp,
+                "top_files.csv.bz2",
+                "group_id,rank,file,views,pages",
+                &self.top_files_rows(&group_status).await?,
+            )?,
+        ];
+        let pageview_source = DbMySql2::get_pageview_source(self.baglama, &self.ym).await?;
+        let anomalies = ViewAnomalies::load(self.baglama, &self.ym).await?;
+        let manifest = Self::manifest(
+            &self.ym,
+            version,
+            pageview_source,
+            group_status.len(),
+            anomalies.len(),
+            &files,
+        );
+        std::fs::write(
+            release_dir_tmp.join("manifest.json"),
+            serde_json::to_string_pretty(&manifest)?,
+        )?;
+        std::fs::rename(&release_dir_tmp, &release_dir)?;
+        info!("Release {version} for {} written", self.ym);
+        Ok(release_dir)
+    }
+
+    /// All public groups need to be complete for the month.
+    async fn load_group_status(&self) -> Result<Vec<RowGroupStatus>> {
+        let sql = format!(
+            "SELECT {} FROM `group_status` WHERE `year`=? AND `month`=? AND `group_id` IN ({RELEASE_GROUPS_SQL}) ORDER BY `group_id`",
+            RowGroupStatus::sql_all()
+        );
+        let rows = self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, (self.ym.year(), self.ym.month()))
+            .await?
+            .map_and_drop(from_row::<RowGroupStatus>)
+            .await?;
+        let incomplete = rows
+            .iter()
+            .filter(|gs| gs.status != "VIEW DATA COMPLETE")
+            .count();
+        if rows.is_empty() || incomplete > 0 {
+            return Err(anyhow!(
+                "{} is not finished: {incomplete} of {} groups incomplete",
+                self.ym,
+                rows.len()
+            ));
+        }
+        Ok(rows)
+    }
+
+    async fn group_totals_rows(&self, group_status: &[RowGroupStatus]) -> Result<Vec<String>> {
+        let mut ret = vec![];
+        for gs in group_status {
+            let group_id: GroupId = gs.group_id.try_into()?;
+            let category = self
+                .baglama
+                .get_group(&group_id)
+                .await?
+                .map(|group| group.category().to_owned())
+                .unwrap_or_default();
+            ret.push(format!(
+                "{},{},{},{},{}",
+                gs.group_id,
+                csv_field(&category),
+                gs.total_views.unwrap_or_default(),
+                gs.usage_views.unwrap_or_default(),
+                gs.excluded_views.unwrap_or_default()
+            ));
+        }
+        Ok(ret)
+    }
+
+    /// Rows from `gs2site` for mysql2 group months, and from the results for
+    /// the others, as `gs2site` is only written for mysql2.
+    async fn site_breakdown_rows(&self, group_status: &[RowGroupStatus]) -> Result<Vec<String>> {
+        let (mysql2, others): (Vec<&RowGroupStatus>, Vec<&RowGroupStatus>) = group_status
+            .iter()
+            .partition(|gs| gs.storage == StorageType::Mysql2);
+        let gs_ids: Vec<DbId> = mysql2.iter().map(|gs| gs.id).collect();
+        let mut rows: Vec<(DbId, String, u64, u64)> = vec![];
+        for chunk in gs_ids.chunks(1000) {
+            let sql = format!(
+                "SELECT gs.group_id,COALESCE(sites.server,''),gs2site.pages,gs2site.views
+                FROM gs2site,group_status gs,sites
+                WHERE gs.id=gs2site.group_status_id AND sites.id=gs2site.site_id AND gs.id IN ({})",
+                Baglama2::sql_placeholders(chunk.len())
+            );
+            rows.extend(
+                self.baglama
+                    .get_tooldb_conn()
+                    .await?
+                    .exec_iter(sql, chunk.to_vec())
+                    .await?
+                    .map_and_drop(from_row::<(DbId, String, u64, u64)>)
+                    .await?,
+            );
+        }
+        for gs in others {
+            let sites = WikiReport::load_sites(self.baglama, gs).await?;
+            rows.extend(
+                sites
+                    .into_iter()
+                    .map(|site| (gs.group_id, site.server, site.pages, site.views)),
+            );
+        }
+        rows.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+        Ok(rows
+            .into_iter()
+            .map(|(group_id, server, pages, views)| {
+                format!("{group_id},{},{pages},{views}", csv_field(&server))
+            })
+            .collect())
+    }
+
+    async fn top_files_rows(&self, group_status: &[RowGroupStatus]) -> Result<Vec<String>> {
+        let mut ret = vec![];
+        for gs in group_status {
+            let group_id: GroupId = gs.group_id.try_into()?;
+            let report =
+                GroupReport::generate(self.baglama, group_id, &self.ym, DEFAULT_TOP_LIMIT).await?;
+            ret.extend(report.top_files.iter().enumerate().map(|(num, f)| {
+                format!(
+                    "{group_id},{},{},{},{}",
+                    num + 1,
+                    csv_field(&f.name),
+                    f.views,
+                    f.pages
+                )
+            }));
+        }
+        Ok(ret)
+    }
+
+    /// The first unused `v<N>` in the month directory.
+    pub fn next_version(month_dir: &Path) -> Result<usize> {
+        if !month_dir.exists() {
+            return Ok(1);
+        }
+        let mut max = 0;
+        for entry in std::fs::read_dir(month_dir)? {
+            let name = entry?.file_name();
+            if let Some(version) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix('v'))
+                .and_then(|v| v.parse::<usize>().ok())
+            {
+                max = max.max(version);
+            }
+        }
+        Ok(max + 1)
+    }
+
+    /// Writes a bz2-compressed CSV file, and returns its manifest entry.
+    pub fn write_csv(
+        dir: &Path,
+        name: &str,
+        header: &str,
+        rows: &[String],
+    ) -> Result<ManifestFile> {
+        let path = dir.join(name);
+        let mut encoder = BzEncoder::new(std::fs::File::create(&path)?, Compression::best());
+        writeln!(encoder, "{header}")?;
+        for row in rows {
+            writeln!(encoder, "{row}")?;
+        }
+        encoder.finish()?;
+        let data = std::fs::read(&path)?;
+        Ok(ManifestFile {
+            name: name.to_string(),
+            rows: rows.len(),
+            bytes: data.len() as u64,
+            sha256: format!("{:x}", Sha256::digest(&data)),
+        })
+    }
+
+    pub fn manifest(
+        ym: &YearMonth,
+        version: usize,
+        pageview_source: Option<String>,
+        groups: usize,
+        anomalies: usize,
+        files: &[ManifestFile],
+    ) -> Value {
+        json!({
+            "month": ym.to_string(),
+            "release": version,
+            "created": chrono::Utc::now().to_rfc3339(),
+            "code_version": CODE_VERSION,
+            "pageview_source": pageview_source,
+            "groups": groups,
+            "view_anomalies": anomalies,
+            "files": files.iter().map(|f| f.to_json()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_write_csv() {
+        let dir = std::env::temp_dir().join("baglama2_test_release_csv");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let rows = vec!["1,Foo,10".to_string(), "2,\"Bar,Baz\",20".to_string()];
+        let file =
+            Release::write_csv(&dir, "t.csv.bz2", "group_id,category,total_views", &rows).unwrap();
+        let data = std::fs::read(dir.join("t.csv.bz2")).unwrap();
+        let mut csv = String::new();
+        bzip2::read::BzDecoder::new(data.as_slice())
+            .read_to_string(&mut csv)
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(
+            csv,
+            "group_id,category,total_views\n1,Foo,10\n2,\"Bar,Baz\",20\n"
+        );
+        assert_eq!(file.rows, 2);
+        assert_eq!(file.bytes, data.len() as u64);
+        assert_eq!(file.sha256, format!("{:x}", Sha256::digest(&data)));
+    }
+
+    #[test]
+    fn test_next_version() {
+        let dir = std::env::temp_dir().join("baglama2_test_release_version");
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(Release::next_version(&dir).unwrap(), 1);
+        std::fs::create_dir_all(dir.join("v1")).unwrap();
+        std::fs::create_dir_all(dir.join("v3")).unwrap();
+        std::fs::create_dir_all(dir.join("other")).unwrap();
+        // Left behind by a failed run
+        std::fs::create_dir_all(dir.join("v5.tmp")).unwrap();
+        let version = Release::next_version(&dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(version, 4);
+    }
+
+    #[test]
+    fn test_manifest() {
+        let ym = YearMonth::new(2024, 2).unwrap();
+        let manifest = Release::manifest(&ym, 2, Some("dump".to_string()), 5, 1, &[]);
+        assert_eq!(manifest["month"], "2024-02");
+        assert_eq!(manifest["release"], 2);
+        assert_eq!(manifest["pageview_source"], "dump");
+        assert_eq!(manifest["view_anomalies"], 1);
+        assert_eq!(manifest["code_version"], CODE_VERSION);
+    }
+}
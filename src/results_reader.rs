@@ -0,0 +1,478 @@
+//! Reads the results of a group month back, whatever storage type they use.
+//!
+//! [`StorageReader`] picks the reader for the `storage` of a `group_status`
+//! row: the viewdata table for mysql2, the per-group SQLite file for sqlite3.
+//! The old `file` and `mysql` storage types cannot be read.
+
+use crate::{
+    db_mysql2::DbMySql2,
+    row_group_status::{RowGroupStatus, StorageType},
+    Baglama2, DbId, YearMonth,
+};
+use anyhow::{anyhow, Result};
+use mysql_async::{from_row, prelude::*};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ResultPage {
+    pub server: String,
+    pub namespace_id: i32,
+    pub title: String,
+}
+
+/// Views of a result page, its wiki page ID (0 if unknown), and whether the
+/// page is excluded from the totals.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PageViews {
+    pub views: Option<u64>,
+    pub page_id: DbId,
+    pub excluded: bool,
+}
+
+impl From<Option<u64>> for PageViews {
+    fn from(views: Option<u64>) -> Self {
+        Self {
+            views,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ResultCounts {
+    pub files: usize,
+    pub pages: usize,
+}
+
+/// Files, pages and views of a group month. `usages` links files and pages
+/// by their index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupResults {
+    pub group_status_id: DbId,
+    pub group_id: DbId,
+    pub ym: YearMonth,
+    pub files: Vec<String>,
+    pub pages: Vec<(ResultPage, PageViews)>,
+    pub usages: Vec<(usize, usize)>,
+}
+
+impl GroupResults {
+    pub fn new(gs: &RowGroupStatus) -> Result<Self> {
+        Ok(Self {
+            group_status_id: gs.id,
+            group_id: gs.group_id,
+            ym: YearMonth::new(gs.year, gs.month)?,
+            files: vec![],
+            pages: vec![],
+            usages: vec![],
+        })
+    }
+
+    /// Builds the model from (file, page, views) rows, one per usage.
+    pub fn add_usages<V: Into<PageViews>>(
+        &mut self,
+        rows: impl IntoIterator<Item = (String, ResultPage, V)>,
+    ) {
+        let mut file2idx: HashMap<String, usize> = HashMap::new();
+        let mut page2idx: HashMap<ResultPage, usize> = HashMap::new();
+        for (file, page, views) in rows {
+            let file_idx = *file2idx.entry(file).or_insert_with_key(|file| {
+                self.files.push(file.to_owned());
+                self.files.len() - 1
+            });
+            let page_idx = *page2idx.entry(page).or_insert_with_key(|page| {
+                self.pages.push((page.to_owned(), views.into()));
+                self.pages.len() - 1
+            });
+            self.usages.push((file_idx, page_idx));
+        }
+    }
+
+    pub fn counts(&self) -> ResultCounts {
+        ResultCounts {
+            files: self.files.len(),
+            pages: self.pages.len(),
+        }
+    }
+
+    /// Views of all distinct pages that are not excluded.
+    pub fn total_views(&self) -> u64 {
+        self.pages
+            .iter()
+            .filter(|(_, views)| !views.excluded)
+            .filter_map(|(_, views)| views.views)
+            .sum()
+    }
+
+    /// Views summed over all usages, so a page using several files counts several times.
+    /// Excluded pages are left out.
+    pub fn usage_views(&self) -> u64 {
+        self.included_usages()
+            .filter_map(|(_, page_idx)| self.pages[*page_idx].1.views)
+            .sum()
+    }
+
+    /// Views of all distinct excluded pages.
+    pub fn excluded_views(&self) -> u64 {
+        self.pages
+            .iter()
+            .filter(|(_, views)| views.excluded)
+            .filter_map(|(_, views)| views.views)
+            .sum()
+    }
+
+    /// Views per file (of all pages using it), and the number of those pages.
+    /// Excluded pages are left out.
+    pub fn file_views(&self) -> Vec<(String, u64, usize)> {
+        let mut ret: Vec<(String, u64, usize)> = self
+            .files
+            .iter()
+            .map(|file| (file.to_owned(), 0, 0))
+            .collect();
+        for (file_idx, page_idx) in self.included_usages() {
+            ret[*file_idx].1 += self.pages[*page_idx].1.views.unwrap_or_default();
+            ret[*file_idx].2 += 1;
+        }
+        ret
+    }
+
+    /// Number of files using each page.
+    pub fn page_file_counts(&self) -> Vec<usize> {
+        let mut ret = vec![0; self.pages.len()];
+        let unique: HashSet<&(usize, usize)> = self.usages.iter().collect();
+        for (_, page_idx) in unique {
+            ret[*page_idx] += 1;
+        }
+        ret
+    }
+
+    fn included_usages(&self) -> impl Iterator<Item = &(usize, usize)> {
+        self.usages
+            .iter()
+            .filter(|(_, page_idx)| !self.pages[*page_idx].1.excluded)
+    }
+}
+
+#[allow(async_fn_in_trait)]
+pub trait ResultsReader {
+    async fn read(&self, gs: &RowGroupStatus) -> Result<GroupResults>;
+
+    async fn read_counts(&self, gs: &RowGroupStatus) -> Result<ResultCounts> {
+        Ok(self.read(gs).await?.counts())
+    }
+}
+
+pub struct Mysql2Reader<'a> {
+    baglama: &'a Baglama2,
+}
+
+impl<'a> Mysql2Reader<'a> {
+    pub fn new(baglama: &'a Baglama2) -> Self {
+        Self { baglama }
+    }
+}
+
+impl ResultsReader for Mysql2Reader<'_> {
+    async fn read(&self, gs: &RowGroupStatus) -> Result<GroupResults> {
+        let mut ret = GroupResults::new(gs)?;
+        let table = DbMySql2::viewdata_table(&ret.ym);
+        let sql = format!(
+            "SELECT FROM_BASE64(TO_BASE64(files.name)),COALESCE(sites.server,''),pages.namespace_id,FROM_BASE64(TO_BASE64(pages.title)),page_views,COALESCE(pages.page_id,0),v.excluded
+            FROM `{table}` v,files,pages,sites
+            WHERE v.group_status_id=? AND files.id=v.files_id AND pages.id=v.pages_id AND sites.id=pages.site"
+        );
+        let rows = self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, (gs.id,))
+            .await?
+            .map_and_drop(from_row::<(String, String, i32, String, Option<u64>, DbId, u8)>)
+            .await?;
+        ret.add_usages(rows.into_iter().map(
+            |(file, server, namespace_id, title, views, page_id, excluded)| {
+                (
+                    file,
+                    ResultPage {
+                        server,
+                        namespace_id,
+                        title,
+                    },
+                    PageViews {
+                        views,
+                        page_id,
+                        excluded: excluded != 0,
+                    },
+                )
+            },
+        ));
+        Ok(ret)
+    }
+
+    async fn read_counts(&self, gs: &RowGroupStatus) -> Result<ResultCounts> {
+        let table = DbMySql2::viewdata_table(&YearMonth::new(gs.year, gs.month)?);
+        let sql = format!("SELECT COUNT(DISTINCT files_id),COUNT(DISTINCT pages_id) FROM `{table}` WHERE group_status_id=?");
+        let (files, pages) = self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, (gs.id,))
+            .await?
+            .map_and_drop(from_row::<(usize, usize)>)
+            .await?
+            .first()
+            .copied()
+            .unwrap_or_default();
+        Ok(ResultCounts { files, pages })
+    }
+}
+
+pub struct Sqlite3Reader;
+
+impl Sqlite3Reader {
+    fn open(gs: &RowGroupStatus) -> Result<rusqlite::Connection> {
+        let path = gs
+            .sqlite3
+            .as_ref()
+            .ok_or_else(|| anyhow!("group_status {} has no SQLite file", gs.id))?;
+        Ok(rusqlite::Connection::open_with_flags(
+            path,
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+        )?)
+    }
+
+    /// The `views.excluded` column, or 0 for files from before it was added.
+    pub fn excluded_column(conn: &rusqlite::Connection) -> &'static str {
+        match conn.prepare("SELECT `excluded` FROM `views` LIMIT 0") {
+            Ok(_) => "views.excluded",
+            Err(_) => "0",
+        }
+    }
+}
+
+impl ResultsReader for Sqlite3Reader {
+    async fn read(&self, gs: &RowGroupStatus) -> Result<GroupResults> {
+        let mut ret = GroupResults::new(gs)?;
+        let conn = Self::open(gs)?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT group2view.image,COALESCE(sites.server,''),views.namespace_id,views.title,views.views,views.page_id,{}
+            FROM group2view,views,sites
+            WHERE views.id=group2view.view_id AND sites.id=views.site",
+            Self::excluded_column(&conn)
+        ))?;
+        let rows = stmt
+            .query_map((), |row| {
+                let views: Option<i64> = row.get(4)?;
+                let page_id: i64 = row.get(5)?;
+                let excluded: i64 = row.get(6)?;
+                Ok((
+                    row.get::<_, String>(0)?,
+                    ResultPage {
+                        server: row.get(1)?,
+                        namespace_id: row.get(2)?,
+                        title: row.get(3)?,
+                    },
+                    PageViews {
+                        views: views.and_then(|v| u64::try_from(v).ok()),
+                        page_id: page_id as DbId,
+                        excluded: excluded != 0,
+                    },
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        ret.add_usages(rows);
+        Ok(ret)
+    }
+
+    async fn read_counts(&self, gs: &RowGroupStatus) -> Result<ResultCounts> {
+        let (files, pages) = Self::open(gs)?.query_row(
+            "SELECT COUNT(DISTINCT image),COUNT(DISTINCT view_id) FROM group2view",
+            (),
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+        )?;
+        Ok(ResultCounts {
+            files: files as usize,
+            pages: pages as usize,
+        })
+    }
+}
+
+/// Reads results from whichever storage a group_status row uses.
+pub struct StorageReader<'a> {
+    baglama: &'a Baglama2,
+}
+
+impl<'a> StorageReader<'a> {
+    pub fn new(baglama: &'a Baglama2) -> Self {
+        Self { baglama }
+    }
+
+    fn unsupported(gs: &RowGroupStatus) -> anyhow::Error {
+        anyhow!(
+            "Cannot read results of group_status {} from storage {:?}",
+            gs.id,
+            gs.storage
+        )
+    }
+}
+
+impl ResultsReader for StorageReader<'_> {
+    async fn read(&self, gs: &RowGroupStatus) -> Result<GroupResults> {
+        match gs.storage {
+            StorageType::Mysql2 => Mysql2Reader::new(self.baglama).read(gs).await,
+            StorageType::Sqlite3 => Sqlite3Reader.read(gs).await,
+            StorageType::File | StorageType::Mysql => Err(Self::unsupported(gs)),
+        }
+    }
+
+    async fn read_counts(&self, gs: &RowGroupStatus) -> Result<ResultCounts> {
+        match gs.storage {
+            StorageType::Mysql2 => Mysql2Reader::new(self.baglama).read_counts(gs).await,
+            StorageType::Sqlite3 => Sqlite3Reader.read_counts(gs).await,
+            StorageType::File | StorageType::Mysql => Err(Self::unsupported(gs)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group_status(storage: StorageType, sqlite3: Option<String>) -> RowGroupStatus {
+        RowGroupStatus {
+            id: 3,
+            group_id: 4,
+            year: 2024,
+            month: 5,
+            status: "VIEW DATA COMPLETE".to_string(),
+            total_views: None,
+            usage_views: None,
+            excluded_views: None,
+            file: None,
+            sqlite3,
+            storage,
+        }
+    }
+
+    fn page(title: &str) -> ResultPage {
+        ResultPage {
+            server: "de.wikipedia.org".to_string(),
+            namespace_id: 0,
+            title: title.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_add_usages() {
+        let mut results = GroupResults::new(&group_status(StorageType::Mysql2, None)).unwrap();
+        results.add_usages(vec![
+            ("A.jpg".to_string(), page("Berlin"), Some(100)),
+            ("B.jpg".to_string(), page("Berlin"), Some(100)),
+            ("B.jpg".to_string(), page("Hamburg"), Some(10)),
+        ]);
+        assert_eq!(results.counts(), ResultCounts { files: 2, pages: 2 });
+        assert_eq!(results.total_views(), 110);
+        assert_eq!(results.usage_views(), 210);
+        assert_eq!(
+            results.file_views(),
+            vec![("A.jpg".to_string(), 100, 1), ("B.jpg".to_string(), 110, 2)]
+        );
+        assert_eq!(results.page_file_counts(), vec![2, 1]);
+    }
+
+    #[test]
+    fn test_excluded_pages() {
+        let mut results = GroupResults::new(&group_status(StorageType::Mysql2, None)).unwrap();
+        let excluded = PageViews {
+            views: Some(1000),
+            page_id: 1,
+            excluded: true,
+        };
+        results.add_usages(vec![
+            ("A.jpg".to_string(), page("Hauptseite"), excluded),
+            ("A.jpg".to_string(), page("Berlin"), Some(100).into()),
+            ("B.jpg".to_string(), page("Hauptseite"), excluded),
+        ]);
+        assert_eq!(results.counts(), ResultCounts { files: 2, pages: 2 });
+        assert_eq!(results.total_views(), 100);
+        assert_eq!(results.usage_views(), 100);
+        assert_eq!(results.excluded_views(), 1000);
+        assert_eq!(
+            results.file_views(),
+            vec![("A.jpg".to_string(), 100, 1), ("B.jpg".to_string(), 0, 0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sqlite3_reader() {
+        let path = std::env::temp_dir().join("baglama2_test_results_reader.sqlite3");
+        let _ = std::fs::remove_file(&path);
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(&std::fs::read_to_string("baglama.sqlite3_schema").unwrap())
+            .unwrap();
+        conn.execute_batch(
+            "INSERT INTO sites (id,server) VALUES (1,'de.wikipedia.org');
+            INSERT INTO views (id,site,title,month,year,done,namespace_id,page_id,views) VALUES
+                (1,1,'Berlin',5,2024,1,0,11,100),(2,1,'Hamburg',5,2024,1,0,12,10);
+            INSERT INTO group2view (group_status_id,view_id,image) VALUES
+                (3,1,'A.jpg'),(3,1,'B.jpg'),(3,2,'B.jpg');",
+        )
+        .unwrap();
+        drop(conn);
+
+        let gs = group_status(
+            StorageType::Sqlite3,
+            Some(path.to_string_lossy().to_string()),
+        );
+        let results = Sqlite3Reader.read(&gs).await.unwrap();
+        let counts = Sqlite3Reader.read_counts(&gs).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(results.counts(), counts);
+        assert_eq!(counts, ResultCounts { files: 2, pages: 2 });
+        assert_eq!(results.total_views(), 110);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite3_reader_excluded() {
+        let path = std::env::temp_dir().join("baglama2_test_results_reader_excluded.sqlite3");
+        let gs = group_status(
+            StorageType::Sqlite3,
+            Some(path.to_string_lossy().to_string()),
+        );
+        let rows = "INSERT INTO sites (id,server) VALUES (1,'de.wikipedia.org');
+            INSERT INTO views (id,site,title,month,year,done,namespace_id,page_id,views) VALUES
+                (1,1,'Berlin',5,2024,1,0,11,100),(2,1,'Hauptseite',5,2024,1,0,12,1000);
+            INSERT INTO group2view (group_status_id,view_id,image) VALUES
+                (3,1,'A.jpg'),(3,2,'A.jpg');
+            UPDATE views SET excluded=1 WHERE title='Hauptseite';";
+
+        let _ = std::fs::remove_file(&path);
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(&std::fs::read_to_string("baglama.sqlite3_schema").unwrap())
+            .unwrap();
+        conn.execute_batch(rows).unwrap();
+        drop(conn);
+        let results = Sqlite3Reader.read(&gs).await.unwrap();
+        assert_eq!(results.total_views(), 100);
+        assert_eq!(results.excluded_views(), 1000);
+
+        // Files from before the `excluded` column have no excluded pages
+        let _ = std::fs::remove_file(&path);
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE sites (id INTEGER PRIMARY KEY,server VARCHAR);
+            CREATE TABLE views (id INTEGER PRIMARY KEY,site INTEGER,title VARCHAR,month INTEGER,year INTEGER,done INTEGER,namespace_id INTEGER,page_id INTEGER,views INTEGER);
+            CREATE TABLE group2view (id INTEGER PRIMARY KEY,group_status_id INTEGER,view_id INTEGER,image VARCHAR);",
+        )
+        .unwrap();
+        conn.execute_batch(
+            &rows.replace("UPDATE views SET excluded=1 WHERE title='Hauptseite';", ""),
+        )
+        .unwrap();
+        drop(conn);
+        let results = Sqlite3Reader.read(&gs).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(results.total_views(), 1100);
+        assert_eq!(results.excluded_views(), 0);
+    }
+}
@@ -31,7 +31,12 @@ pub struct RowGroupStatus {
     pub year: i32,
     pub month: u32,
     pub status: String,
+    /// Views of the distinct pages using the group's files.
     pub total_views: Option<isize>,
+    /// Views summed over (file, page) usages; a page using several files counts several times.
+    pub usage_views: Option<isize>,
+    /// Views of excluded pages, left out of the totals above.
+    pub excluded_views: Option<isize>,
     pub file: Option<String>,
     pub sqlite3: Option<String>,
     pub storage: StorageType,
@@ -62,6 +67,8 @@ impl FromRow for RowGroupStatus {
                 .get("status")
                 .ok_or_else(|| mysql_async::FromRowError(row.to_owned()))?,
             total_views: row.get("total_views").unwrap(),
+            usage_views: row.get("usage_views").unwrap_or_default(),
+            excluded_views: row.get("excluded_views").unwrap_or_default(),
             file: row.get("file").unwrap(),
             sqlite3: row.get("sqlite3").unwrap(),
             storage: storage
@@ -74,6 +81,7 @@ impl FromRow for RowGroupStatus {
 
 impl RowGroupStatus {
     pub fn sql_all() -> String {
-        "id,group_id,year,month,status,total_views,file,sqlite3,storage".to_string()
+        "id,group_id,year,month,status,total_views,usage_views,excluded_views,file,sqlite3,storage"
+            .to_string()
     }
 }
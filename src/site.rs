@@ -14,6 +14,18 @@ pub struct Site {
 }
 
 impl Site {
+    pub fn new(id: DbId, server: Option<String>) -> Self {
+        Self {
+            id,
+            grok_code: None,
+            server,
+            giu_code: None,
+            project: None,
+            language: None,
+            name: None,
+        }
+    }
+
     pub fn from_sqlite_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
         let id: isize = row.get(0)?;
         Ok(Self {
@@ -0,0 +1,284 @@
+//! Exports a mysql2 group month into a standalone SQLite file, following
+//! `baglama.sqlite3_schema`, so it can be used without tool DB access.
+
+use crate::{
+    results_reader::{GroupResults, Mysql2Reader, ResultsReader},
+    row_group::RowGroup,
+    row_group_status::{RowGroupStatus, StorageType},
+    site::Site,
+    Baglama2, GroupId, YearMonth,
+};
+use anyhow::{anyhow, Result};
+use rusqlite::Connection;
+use std::collections::HashMap;
+
+pub struct SqliteExport;
+
+impl SqliteExport {
+    /// Writes the group month to `path`, replacing any existing file.
+    pub async fn export(
+        baglama: &Baglama2,
+        group_id: GroupId,
+        ym: &YearMonth,
+        path: &str,
+    ) -> Result<()> {
+        let gs = baglama
+            .get_group_status(&group_id, ym)
+            .await?
+            .ok_or_else(|| anyhow!("No status for group {group_id} in {ym}"))?;
+        if gs.storage != StorageType::Mysql2 {
+            return Err(anyhow!(
+                "group_status {} uses storage {:?}, not mysql2",
+                gs.id,
+                gs.storage
+            ));
+        }
+        let group = baglama.get_group(&group_id).await?;
+        let results = Mysql2Reader::new(baglama).read(&gs).await?;
+        let sites = baglama.get_sites()?;
+
+        // Write to a temporary file first, so a failed export leaves no partial file
+        let path_tmp = format!("{path}.tmp");
+        let _ = std::fs::remove_file(&path_tmp);
+        let mut conn = Connection::open(&path_tmp)?;
+        conn.execute_batch(&std::fs::read_to_string(baglama.sqlite_schema_file())?)?;
+        Self::write(&mut conn, &sites, group.as_ref(), &gs, &results)?;
+        drop(conn);
+        std::fs::rename(&path_tmp, path)?;
+        Ok(())
+    }
+
+    /// Writes all rows into a connection with an empty schema.
+    pub fn write(
+        conn: &mut Connection,
+        sites: &[Site],
+        group: Option<&RowGroup>,
+        gs: &RowGroupStatus,
+        results: &GroupResults,
+    ) -> Result<()> {
+        let server2site_id: HashMap<&str, usize> = sites
+            .iter()
+            .filter_map(|site| Some((site.server().as_deref()?, site.id())))
+            .collect();
+        let tx = conn.transaction()?;
+        for site in sites {
+            tx.execute(
+                "INSERT INTO `sites` (id,grok_code,server,giu_code,project,language,name) VALUES (?,?,?,?,?,?,?)",
+                rusqlite::params![
+                    site.id() as isize,
+                    site.grok_code(),
+                    site.server(),
+                    site.giu_code(),
+                    site.project(),
+                    site.language(),
+                    site.name()
+                ],
+            )?;
+        }
+        if let Some(group) = group {
+            tx.execute(
+                "INSERT INTO `groups` (id,category,depth,added_by,just_added) VALUES (?,?,?,?,?)",
+                rusqlite::params![
+                    group.id() as isize,
+                    group.category(),
+                    group.depth(),
+                    group.added_by(),
+                    group.just_added(),
+                ],
+            )?;
+        }
+        tx.execute(
+            "INSERT INTO `group_status` (id,group_id,year,month,status,total_views,usage_views,excluded_views) VALUES (?,?,?,?,?,?,?,?)",
+            rusqlite::params![
+                gs.id as isize,
+                gs.group_id as isize,
+                gs.year,
+                gs.month,
+                gs.status,
+                results.total_views() as i64,
+                results.usage_views() as i64,
+                results.excluded_views() as i64,
+            ],
+        )?;
+        for (num, file) in results.files.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO `files` (id,filename) VALUES (?,?)",
+                rusqlite::params![num + 1, file],
+            )?;
+        }
+        // `page_id` is 0 for pages whose wiki page ID is not known yet
+        for (num, (page, views)) in results.pages.iter().enumerate() {
+            let site_id = server2site_id
+                .get(page.server.as_str())
+                .ok_or_else(|| anyhow!("Unknown server '{}'", page.server))?;
+            tx.execute(
+                "INSERT INTO `views` (id,site,title,month,year,done,namespace_id,page_id,views,excluded) VALUES (?,?,?,?,?,1,?,?,?,?)",
+                rusqlite::params![
+                    num + 1,
+                    *site_id as isize,
+                    page.title,
+                    gs.month,
+                    gs.year,
+                    page.namespace_id,
+                    views.page_id as i64,
+                    views.views.unwrap_or_default() as i64,
+                    views.excluded,
+                ],
+            )?;
+        }
+        for (file_idx, page_idx) in &results.usages {
+            tx.execute(
+                "INSERT INTO `group2view` (group_status_id,view_id,image) VALUES (?,?,?)",
+                rusqlite::params![gs.id as isize, page_idx + 1, results.files[*file_idx]],
+            )?;
+        }
+        tx.execute(
+            "INSERT INTO `gs2site` (id,group_status_id,site_id,pages,views) SELECT sites.id,?1,sites.id,COUNT(*),SUM(views) FROM `views`,`sites` WHERE views.site=sites.id AND views.excluded=0 GROUP BY sites.id",
+            rusqlite::params![gs.id as isize],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::results_reader::{PageViews, ResultCounts, ResultPage, Sqlite3Reader};
+
+    #[tokio::test]
+    async fn test_export_roundtrip() {
+        let path = std::env::temp_dir().join("baglama2_test_sqlite_export.sqlite3");
+        let _ = std::fs::remove_file(&path);
+        let mut gs = RowGroupStatus {
+            id: 12,
+            group_id: 3,
+            year: 2024,
+            month: 6,
+            status: "VIEW DATA COMPLETE".to_string(),
+            total_views: Some(60),
+            usage_views: None,
+            excluded_views: None,
+            file: None,
+            sqlite3: None,
+            storage: StorageType::Mysql2,
+        };
+        let page = |server: &str, title: &str| ResultPage {
+            server: server.to_string(),
+            namespace_id: 0,
+            title: title.to_string(),
+        };
+        let mut results = GroupResults::new(&gs).unwrap();
+        results.add_usages(vec![
+            (
+                "A.jpg".to_string(),
+                page("de.wikipedia.org", "Berlin"),
+                Some(50),
+            ),
+            (
+                "B.jpg".to_string(),
+                page("de.wikipedia.org", "Berlin"),
+                Some(50),
+            ),
+            (
+                "B.jpg".to_string(),
+                page("en.wikipedia.org", "Berlin"),
+                Some(10),
+            ),
+        ]);
+        let sites: Vec<Site> = [(1, "de.wikipedia.org"), (2, "en.wikipedia.org")]
+            .iter()
+            .map(|(id, server)| Site::new(*id, Some(server.to_string())))
+            .collect();
+
+        let mut conn = Connection::open(&path).unwrap();
+        conn.execute_batch(&std::fs::read_to_string("baglama.sqlite3_schema").unwrap())
+            .unwrap();
+        SqliteExport::write(&mut conn, &sites, None, &gs, &results).unwrap();
+        let site_views: Vec<(i64, i64, i64)> = conn
+            .prepare("SELECT site_id,pages,views FROM gs2site ORDER BY site_id")
+            .unwrap()
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        drop(conn);
+
+        gs.storage = StorageType::Sqlite3;
+        gs.sqlite3 = Some(path.to_string_lossy().to_string());
+        let read = Sqlite3Reader.read(&gs).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(site_views, vec![(1, 1, 50), (2, 1, 10)]);
+        assert_eq!(read.counts(), results.counts());
+        assert_eq!(read.total_views(), 60);
+        assert_eq!(read.file_views(), results.file_views());
+    }
+
+    #[tokio::test]
+    async fn test_export_namespaces_and_exclusions() {
+        let path = std::env::temp_dir().join("baglama2_test_sqlite_export_namespaces.sqlite3");
+        let _ = std::fs::remove_file(&path);
+        let mut gs = RowGroupStatus {
+            id: 13,
+            group_id: 3,
+            year: 2024,
+            month: 6,
+            status: "VIEW DATA COMPLETE".to_string(),
+            total_views: Some(50),
+            usage_views: None,
+            excluded_views: None,
+            file: None,
+            sqlite3: None,
+            storage: StorageType::Mysql2,
+        };
+        let page = |namespace_id: i32| ResultPage {
+            server: "de.wikipedia.org".to_string(),
+            namespace_id,
+            title: "Berlin".to_string(),
+        };
+        let mut results = GroupResults::new(&gs).unwrap();
+        results.add_usages(vec![
+            (
+                "A.jpg".to_string(),
+                page(0),
+                PageViews {
+                    views: Some(50),
+                    page_id: 1234,
+                    excluded: false,
+                },
+            ),
+            (
+                "A.jpg".to_string(),
+                page(1),
+                PageViews {
+                    views: Some(5),
+                    page_id: 0,
+                    excluded: true,
+                },
+            ),
+        ]);
+        let sites = vec![Site::new(1, Some("de.wikipedia.org".to_string()))];
+
+        let mut conn = Connection::open(&path).unwrap();
+        conn.execute_batch(&std::fs::read_to_string("baglama.sqlite3_schema").unwrap())
+            .unwrap();
+        SqliteExport::write(&mut conn, &sites, None, &gs, &results).unwrap();
+        let page_ids: Vec<(i32, i64)> = conn
+            .prepare("SELECT namespace_id,page_id FROM views ORDER BY namespace_id")
+            .unwrap()
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        drop(conn);
+
+        gs.storage = StorageType::Sqlite3;
+        gs.sqlite3 = Some(path.to_string_lossy().to_string());
+        let read = Sqlite3Reader.read(&gs).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(page_ids, vec![(0, 1234), (1, 0)]);
+        assert_eq!(read.counts(), ResultCounts { files: 1, pages: 2 });
+        assert_eq!(read.total_views(), 50);
+        assert_eq!(read.excluded_views(), 5);
+    }
+}
@@ -0,0 +1,302 @@
+//! Migrates per-group SQLite files (`group_status.storage='sqlite3'`) into
+//! the mysql2 tables.
+//!
+//! Each file is migrated in its own transaction: its viewdata rows are
+//! replaced, the view totals are checked against the file and `group_status`,
+//! and only then is the storage switched to mysql2. Migrated rows are skipped
+//! on the next run, so an interrupted migration can simply be restarted.
+
+use crate::{
+    db_mysql2::DbMySql2,
+    page::Page,
+    results_reader::{GroupResults, ResultsReader, Sqlite3Reader},
+    row_group_status::RowGroupStatus,
+    Baglama2, DbId, YearMonth,
+};
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use mysql_async::{from_row, prelude::*, Conn, TxOpts};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+const FILES_CHUNK_SIZE: usize = 1000;
+const PAGES_CHUNK_SIZE: usize = 500;
+const VIEWDATA_CHUNK_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MigrationSummary {
+    pub migrated: usize,
+    pub failed: usize,
+}
+
+pub struct SqliteMigration {
+    baglama: Arc<Baglama2>,
+    server2site_id: HashMap<String, DbId>,
+}
+
+impl SqliteMigration {
+    pub fn new(baglama: Arc<Baglama2>) -> Result<Self> {
+        let server2site_id = baglama
+            .get_sites()?
+            .iter()
+            .filter_map(|site| Some((site.server().to_owned()?, site.id())))
+            .collect();
+        Ok(Self {
+            baglama,
+            server2site_id,
+        })
+    }
+
+    /// Migrates all SQLite group months, or only those of one month.
+    pub async fn run(&self, ym: Option<YearMonth>) -> Result<MigrationSummary> {
+        let mut summary = MigrationSummary::default();
+        let rows = self.get_sqlite_group_status(ym).await?;
+        info!("{} SQLite group months to migrate", rows.len());
+        let mut months = BTreeSet::new();
+        for gs in rows {
+            let ym = YearMonth::new(gs.year, gs.month)?;
+            if months.insert(ym) {
+                DbMySql2::new(ym, self.baglama.clone())
+                    .await?
+                    .ensure_table_exists()
+                    .await?;
+            }
+            match self.migrate(&gs).await {
+                Ok(()) => {
+                    info!("Migrated group_status {} ({ym})", gs.id);
+                    summary.migrated += 1;
+                }
+                Err(e) => {
+                    warn!("Could not migrate group_status {} ({ym}): {e}", gs.id);
+                    summary.failed += 1;
+                }
+            }
+        }
+        for ym in months {
+            DbMySql2::new(ym, self.baglama.clone())
+                .await?
+                .recalculate_totals()
+                .await?;
+        }
+        Ok(summary)
+    }
+
+    async fn get_sqlite_group_status(&self, ym: Option<YearMonth>) -> Result<Vec<RowGroupStatus>> {
+        let mut sql = format!(
+            "SELECT {} FROM `group_status` WHERE `storage`='sqlite3' AND `status`='VIEW DATA COMPLETE' AND `sqlite3` IS NOT NULL",
+            RowGroupStatus::sql_all()
+        );
+        let mut params: Vec<mysql_async::Value> = vec![];
+        if let Some(ym) = ym {
+            sql += " AND `year`=? AND `month`=?";
+            params.push(ym.year().into());
+            params.push(ym.month().into());
+        }
+        sql += " ORDER BY `year`,`month`,`group_id`";
+        let rows = self
+            .baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, params)
+            .await?
+            .map_and_drop(from_row::<RowGroupStatus>)
+            .await?;
+        Ok(rows)
+    }
+
+    /// Keeps one row per (file, page), the one with the most views, as the
+    /// viewdata table is unique on them.
+    fn dedup_usages(rows: &mut Vec<(DbId, DbId, Option<u64>, bool)>) {
+        rows.sort_by_key(|(file_id, page_id, views, _)| (*file_id, *page_id, Reverse(*views)));
+        rows.dedup_by_key(|(file_id, page_id, _, _)| (*file_id, *page_id));
+    }
+
+    /// Migrates a single SQLite file.
+    pub async fn migrate(&self, gs: &RowGroupStatus) -> Result<()> {
+        let results = Sqlite3Reader.read(gs).await?;
+        let expected_views = results.total_views();
+        if let Some(total_views) = gs.total_views {
+            if total_views as u64 != expected_views {
+                return Err(anyhow!(
+                    "SQLite file has {expected_views} views, but group_status has {total_views}"
+                ));
+            }
+        }
+        let mut conn = self.baglama.get_tooldb_conn().await?;
+        let file_ids = Self::ensure_files(&mut conn, &results.files).await?;
+        let pages = self.result_pages(&results)?;
+        let page_ids = Self::ensure_pages(&mut conn, &pages).await?;
+        let wiki_page_ids: Vec<(DbId, DbId)> = pages
+            .iter()
+            .zip(&results.pages)
+            .filter_map(|(page, (_, views))| {
+                let key = (page.site_id, page.title.to_owned(), page.namespace_id);
+                Some((*page_ids.get(&key)?, views.page_id))
+            })
+            .collect();
+        DbMySql2::backfill_page_ids(&mut conn, &wiki_page_ids).await?;
+
+        let mut rows = vec![];
+        for (file_idx, page_idx) in &results.usages {
+            let file_id = file_ids
+                .get(&results.files[*file_idx])
+                .ok_or_else(|| anyhow!("No ID for file {}", results.files[*file_idx]))?;
+            let page = &pages[*page_idx];
+            let page_id = page_ids
+                .get(&(page.site_id, page.title.to_owned(), page.namespace_id))
+                .ok_or_else(|| anyhow!("No ID for page {}", page.title))?;
+            let views = &results.pages[*page_idx].1;
+            rows.push((*file_id, *page_id, views.views, views.excluded));
+        }
+        Self::dedup_usages(&mut rows);
+
+        let table = DbMySql2::viewdata_table(&results.ym);
+        let mut tx = conn.start_transaction(TxOpts::default()).await?;
+        tx.exec_drop(
+            format!("DELETE FROM `{table}` WHERE `group_status_id`=?"),
+            (gs.id,),
+        )
+        .await?;
+        for chunk in rows.chunks(VIEWDATA_CHUNK_SIZE) {
+            let placeholders = vec!["(?,?,?,?,?)"; chunk.len()].join(",");
+            let sql = format!("INSERT INTO `{table}` (`group_status_id`,`files_id`,`pages_id`,`page_views`,`excluded`) VALUES {placeholders}");
+            let params: Vec<mysql_async::Value> = chunk
+                .iter()
+                .flat_map(|(file_id, page_id, views, excluded)| {
+                    [
+                        gs.id.into(),
+                        (*file_id).into(),
+                        (*page_id).into(),
+                        views.unwrap_or_default().into(),
+                        (*excluded as u8).into(),
+                    ]
+                })
+                .collect();
+            tx.exec_drop(sql, params).await?;
+        }
+        let sql = format!(
+            "SELECT COALESCE(SUM(views),0) FROM (SELECT MAX(page_views) AS views FROM `{table}` WHERE group_status_id=? AND excluded=0 GROUP BY pages_id) t"
+        );
+        let stored_views = tx
+            .exec_first::<u64, _, _>(sql, (gs.id,))
+            .await?
+            .unwrap_or_default();
+        if stored_views != expected_views {
+            tx.rollback().await?;
+            return Err(anyhow!(
+                "Views do not match after migration: {stored_views} stored, {expected_views} expected"
+            ));
+        }
+        // Totals and per-site statistics are calculated from the view data
+        tx.exec_drop(
+            "UPDATE `group_status` SET `storage`='mysql2',`total_views`=NULL,`usage_views`=NULL,`excluded_views`=NULL WHERE `id`=?",
+            (gs.id,),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Maps the pages of the results to tool DB sites, by server.
+    fn result_pages(&self, results: &GroupResults) -> Result<Vec<Page>> {
+        results
+            .pages
+            .iter()
+            .map(|(page, _views)| {
+                let site_id = self
+                    .server2site_id
+                    .get(&page.server)
+                    .ok_or_else(|| anyhow!("Unknown server '{}'", page.server))?;
+                Ok(Page::new(
+                    *site_id,
+                    page.title.to_owned(),
+                    page.namespace_id,
+                ))
+            })
+            .collect()
+    }
+
+    async fn ensure_files(conn: &mut Conn, files: &[String]) -> Result<HashMap<String, DbId>> {
+        let mut ret = HashMap::new();
+        for chunk in files.chunks(FILES_CHUNK_SIZE) {
+            let placeholders = vec!["(?)"; chunk.len()].join(",");
+            let sql = format!("INSERT IGNORE INTO `files` (`name`) VALUES {placeholders}");
+            conn.exec_drop(sql, chunk.to_vec()).await?;
+            let placeholders = Baglama2::sql_placeholders(chunk.len());
+            let sql = format!("SELECT FROM_BASE64(TO_BASE64(`name`)),`id` FROM `files` WHERE `name` IN ({placeholders})");
+            let rows = conn
+                .exec_iter(sql, chunk.to_vec())
+                .await?
+                .map_and_drop(from_row::<(String, DbId)>)
+                .await?;
+            ret.extend(rows);
+        }
+        Ok(ret)
+    }
+
+    async fn ensure_pages(
+        conn: &mut Conn,
+        pages: &[Page],
+    ) -> Result<HashMap<(DbId, String, i32), DbId>> {
+        let mut ret = HashMap::new();
+        for chunk in pages.chunks(PAGES_CHUNK_SIZE) {
+            let params: Vec<mysql_async::Value> = chunk
+                .iter()
+                .flat_map(|p| {
+                    [
+                        p.site_id.into(),
+                        p.title.to_owned().into(),
+                        p.namespace_id.into(),
+                    ]
+                })
+                .collect();
+            let placeholders = vec!["(?,?,?)"; chunk.len()].join(",");
+            let sql = format!(
+                "INSERT IGNORE INTO `pages` (`site`,`title`,`namespace_id`) VALUES {placeholders}"
+            );
+            conn.exec_drop(sql, params.clone()).await?;
+            let conditions =
+                vec!["(site=? AND title=? AND namespace_id=?)"; chunk.len()].join(" OR ");
+            let sql = format!(
+                "SELECT {} FROM `pages` WHERE {conditions}",
+                Page::sql_fields()
+            );
+            let rows = conn
+                .exec_iter(sql, params)
+                .await?
+                .map_and_drop(from_row::<Page>)
+                .await?;
+            ret.extend(
+                rows.into_iter()
+                    .filter_map(|p| Some(((p.site_id, p.title, p.namespace_id), p.id?))),
+            );
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup_usages() {
+        let mut rows = vec![
+            (2, 1, Some(5), false),
+            (1, 1, Some(3), false),
+            (1, 1, Some(7), false),
+            (1, 2, None, true),
+            (1, 1, Some(3), false),
+        ];
+        SqliteMigration::dedup_usages(&mut rows);
+        assert_eq!(
+            rows,
+            vec![
+                (1, 1, Some(7), false),
+                (1, 2, None, true),
+                (2, 1, Some(5), false)
+            ]
+        );
+    }
+}
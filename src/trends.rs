@@ -0,0 +1,316 @@
+//! Per-group time series of views, files used, and distinct pages across
+//! months, with month-over-month and year-over-year changes.
+//!
+//! Total views come from `group_status`, for all storage types. File and page
+//! counts are read via [`StorageReader`]; they are unknown for the old `file`
+//! and `mysql` storage types.
+
+use crate::{
+    group_report::csv_field,
+    results_reader::{ResultsReader, StorageReader},
+    row_group_status::{RowGroupStatus, StorageType},
+    Baglama2, DbId, YearMonth,
+};
+use anyhow::Result;
+use log::warn;
+use mysql_async::{from_row, prelude::*};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+
+/// Relative month-over-month change above which a value is flagged.
+pub const TREND_FLAG_THRESHOLD: f64 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TrendPoint {
+    pub total_views: Option<u64>,
+    pub files: Option<usize>,
+    pub pages: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrendFlag {
+    pub ym: YearMonth,
+    pub metric: &'static str,
+    pub previous: u64,
+    pub current: u64,
+    pub change: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct GroupTrend {
+    pub group_id: DbId,
+    pub points: BTreeMap<YearMonth, TrendPoint>,
+}
+
+impl GroupTrend {
+    pub fn new(group_id: DbId) -> Self {
+        Self {
+            group_id,
+            points: BTreeMap::new(),
+        }
+    }
+
+    /// Relative change; `None` if either value is unknown, or the old one is 0.
+    pub fn change(previous: Option<u64>, current: Option<u64>) -> Option<f64> {
+        let previous = previous?;
+        if previous == 0 {
+            return None;
+        }
+        Some((current? as f64 - previous as f64) / previous as f64)
+    }
+
+    fn metrics(point: &TrendPoint) -> [(&'static str, Option<u64>); 3] {
+        [
+            ("total_views", point.total_views),
+            ("files", point.files.map(|x| x as u64)),
+            ("pages", point.pages.map(|x| x as u64)),
+        ]
+    }
+
+    fn point_at(&self, ym: Result<YearMonth>) -> Option<&TrendPoint> {
+        self.points.get(&ym.ok()?)
+    }
+
+    fn year_before(ym: &YearMonth) -> Result<YearMonth> {
+        YearMonth::new(ym.year() - 1, ym.month())
+    }
+
+    pub fn views_mom(&self, ym: &YearMonth) -> Option<f64> {
+        let previous = self.point_at(ym.previous())?;
+        Self::change(previous.total_views, self.points.get(ym)?.total_views)
+    }
+
+    pub fn views_yoy(&self, ym: &YearMonth) -> Option<f64> {
+        let previous = self.point_at(Self::year_before(ym))?;
+        Self::change(previous.total_views, self.points.get(ym)?.total_views)
+    }
+
+    /// All month-over-month changes above the threshold, in any metric.
+    pub fn flags(&self) -> Vec<TrendFlag> {
+        let mut ret = vec![];
+        for (ym, point) in &self.points {
+            let previous = match self.point_at(ym.previous()) {
+                Some(previous) => previous,
+                None => continue,
+            };
+            let current_metrics = Self::metrics(point);
+            let previous_metrics = Self::metrics(previous);
+            for ((metric, current), (_, previous)) in current_metrics.iter().zip(previous_metrics) {
+                if let Some(change) = Self::change(previous, *current) {
+                    if change.abs() > TREND_FLAG_THRESHOLD {
+                        ret.push(TrendFlag {
+                            ym: *ym,
+                            metric,
+                            previous: previous.unwrap_or_default(),
+                            current: current.unwrap_or_default(),
+                            change,
+                        });
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "group_id": self.group_id,
+            "months": self.points.iter().map(|(ym, p)| json!({
+                "month": ym.to_string(),
+                "total_views": p.total_views,
+                "files": p.files,
+                "pages": p.pages,
+                "views_mom": self.views_mom(ym),
+                "views_yoy": self.views_yoy(ym),
+            })).collect::<Vec<_>>(),
+            "flags": self.flags().iter().map(|f| json!({
+                "month": f.ym.to_string(),
+                "metric": f.metric,
+                "previous": f.previous,
+                "current": f.current,
+                "change": f.change,
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    /// CSV rows (without header) for this group.
+    pub fn csv_rows(&self) -> String {
+        let flags = self.flags();
+        let opt = |v: Option<String>| v.unwrap_or_default();
+        let mut ret = String::new();
+        for (ym, p) in &self.points {
+            let flagged: Vec<&str> = flags
+                .iter()
+                .filter(|f| f.ym == *ym)
+                .map(|f| f.metric)
+                .collect();
+            ret += &format!(
+                "{},{},{},{},{},{},{},{}\n",
+                self.group_id,
+                ym,
+                opt(p.total_views.map(|v| v.to_string())),
+                opt(p.files.map(|v| v.to_string())),
+                opt(p.pages.map(|v| v.to_string())),
+                opt(self.views_mom(ym).map(|c| format!("{c:.3}"))),
+                opt(self.views_yoy(ym).map(|c| format!("{c:.3}"))),
+                csv_field(&flagged.join(",")),
+            );
+        }
+        ret
+    }
+}
+
+pub struct Trends;
+
+impl Trends {
+    pub const CSV_HEADER: &'static str =
+        "group_id,month,total_views,files,pages,views_mom,views_yoy,flagged\n";
+
+    /// Loads the trends of all groups (or one group) from `from` to `to`, inclusive.
+    pub async fn load(
+        baglama: &Baglama2,
+        group_id: Option<DbId>,
+        from: &YearMonth,
+        to: &YearMonth,
+    ) -> Result<Vec<GroupTrend>> {
+        let mut sql = format!(
+            "SELECT {} FROM `group_status` WHERE `status`='VIEW DATA COMPLETE' AND `year`*100+`month` BETWEEN ? AND ?",
+            RowGroupStatus::sql_all()
+        );
+        if group_id.is_some() {
+            sql += " AND `group_id`=?";
+        }
+        let mut params: Vec<mysql_async::Value> = vec![
+            (from.year() * 100 + from.month() as i32).into(),
+            (to.year() * 100 + to.month() as i32).into(),
+        ];
+        if let Some(group_id) = group_id {
+            params.push(group_id.into());
+        }
+        let rows = baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, params)
+            .await?
+            .map_and_drop(from_row::<RowGroupStatus>)
+            .await?;
+        let counts = Self::load_counts(baglama, &rows).await;
+
+        let mut trends: BTreeMap<DbId, GroupTrend> = BTreeMap::new();
+        for gs in rows {
+            let ym = YearMonth::new(gs.year, gs.month)?;
+            let (files, pages) = counts.get(&gs.id).copied().unwrap_or_default();
+            let point = TrendPoint {
+                total_views: gs.total_views.and_then(|v| u64::try_from(v).ok()),
+                files,
+                pages,
+            };
+            trends
+                .entry(gs.group_id)
+                .or_insert_with(|| GroupTrend::new(gs.group_id))
+                .points
+                .insert(ym, point);
+        }
+        Ok(trends.into_values().collect())
+    }
+
+    /// Returns (files, pages) per group_status ID, where known.
+    async fn load_counts(
+        baglama: &Baglama2,
+        rows: &[RowGroupStatus],
+    ) -> HashMap<DbId, (Option<usize>, Option<usize>)> {
+        let reader = StorageReader::new(baglama);
+        let mut ret = HashMap::new();
+        for gs in rows {
+            if matches!(gs.storage, StorageType::File | StorageType::Mysql) {
+                continue;
+            }
+            match reader.read_counts(gs).await {
+                Ok(counts) => {
+                    ret.insert(gs.id, (Some(counts.files), Some(counts.pages)));
+                }
+                Err(e) => warn!("Could not read results of group_status {}: {e}", gs.id),
+            }
+        }
+        ret
+    }
+
+    pub fn to_csv(trends: &[GroupTrend]) -> String {
+        let mut ret = Self::CSV_HEADER.to_string();
+        for trend in trends {
+            ret += &trend.csv_rows();
+        }
+        ret
+    }
+
+    pub fn to_json(trends: &[GroupTrend]) -> Value {
+        json!(trends.iter().map(|t| t.to_json()).collect::<Vec<_>>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ym(year: i32, month: u32) -> YearMonth {
+        YearMonth::new(year, month).unwrap()
+    }
+
+    fn point(views: u64, files: usize) -> TrendPoint {
+        TrendPoint {
+            total_views: Some(views),
+            files: Some(files),
+            pages: None,
+        }
+    }
+
+    fn trend() -> GroupTrend {
+        let mut trend = GroupTrend::new(5);
+        trend.points.insert(ym(2023, 1), point(1000, 10));
+        trend.points.insert(ym(2023, 12), point(1000, 10));
+        trend.points.insert(ym(2024, 1), point(2000, 12));
+        trend.points.insert(ym(2024, 2), point(1800, 3));
+        trend
+    }
+
+    #[test]
+    fn test_change() {
+        assert_eq!(GroupTrend::change(Some(100), Some(150)), Some(0.5));
+        assert_eq!(GroupTrend::change(Some(0), Some(150)), None);
+        assert_eq!(GroupTrend::change(None, Some(150)), None);
+        assert_eq!(GroupTrend::change(Some(100), None), None);
+    }
+
+    #[test]
+    fn test_mom_yoy() {
+        let trend = trend();
+        assert_eq!(trend.views_mom(&ym(2024, 1)), Some(1.0));
+        assert_eq!(trend.views_yoy(&ym(2024, 1)), Some(1.0));
+        assert_eq!(trend.views_yoy(&ym(2024, 2)), None);
+        // No data for November 2023
+        assert_eq!(trend.views_mom(&ym(2023, 12)), None);
+    }
+
+    #[test]
+    fn test_flags() {
+        let flags = trend().flags();
+        let flagged: Vec<(String, &str)> =
+            flags.iter().map(|f| (f.ym.to_string(), f.metric)).collect();
+        assert_eq!(
+            flagged,
+            vec![
+                ("2024-01".to_string(), "total_views"),
+                ("2024-02".to_string(), "files"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_csv() {
+        let csv = Trends::to_csv(&[trend()]);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], Trends::CSV_HEADER.trim_end());
+        assert_eq!(lines[3], "5,2024-01,2000,12,,1.000,1.000,total_views");
+        assert_eq!(lines[4], "5,2024-02,1800,3,,-0.100,,files");
+    }
+}
@@ -0,0 +1,554 @@
+//! Detection of bot-like view spikes in the viewdata of a month.
+//!
+//! A page is flagged when its views are far above the median of its own
+//! previous months (`history`), or when it alone makes up most of the views
+//! of a group (`group`). Only pages with at least `min_views` views are
+//! checked. Flags are stored in the `view_anomalies` table. If `cap_factor`
+//! is set, the views of `history` anomalies are capped to that multiple of
+//! their median in the viewdata table; the original views stay in
+//! `view_anomalies`, and the totals of the affected groups are cleared so they
+//! are calculated again. Storing and capping happen once per month, recorded
+//! in `view_anomaly_runs`.
+//!
+//! Settings are read from the `anomaly_detection` config object; missing keys
+//! use the defaults of [`AnomalyConfig`].
+
+use crate::{db_mysql2::DbMySql2, results_reader::ResultPage, Baglama2, DbId, YearMonth};
+use anyhow::{anyhow, Result};
+use log::info;
+use mysql_async::{from_row, prelude::*, Conn, Transaction, TxOpts};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnomalyConfig {
+    /// Number of previous months to take the median from.
+    pub history_months: u32,
+    /// Minimum number of previous months with views of the page.
+    pub min_history: usize,
+    /// Views above this multiple of the median are flagged.
+    pub page_factor: f64,
+    pub min_views: u64,
+    /// Share of the views of a group above which a single page is flagged.
+    pub group_share: f64,
+    pub min_group_pages: usize,
+    pub cap_factor: Option<f64>,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        Self {
+            history_months: 6,
+            min_history: 3,
+            page_factor: 10.0,
+            min_views: 10_000,
+            group_share: 0.5,
+            min_group_pages: 20,
+            cap_factor: None,
+        }
+    }
+}
+
+impl AnomalyConfig {
+    pub fn from_config(config: &Value) -> Self {
+        let c = &config["anomaly_detection"];
+        let d = Self::default();
+        Self {
+            history_months: c["history_months"]
+                .as_u64()
+                .map_or(d.history_months, |v| v as u32),
+            min_history: c["min_history"]
+                .as_u64()
+                .map_or(d.min_history, |v| v as usize),
+            page_factor: c["page_factor"].as_f64().unwrap_or(d.page_factor),
+            min_views: c["min_views"].as_u64().unwrap_or(d.min_views),
+            group_share: c["group_share"].as_f64().unwrap_or(d.group_share),
+            min_group_pages: c["min_group_pages"]
+                .as_u64()
+                .map_or(d.min_group_pages, |v| v as usize),
+            cap_factor: c["cap_factor"].as_f64(),
+        }
+    }
+
+    /// Checks the views of a page against the views of its previous months.
+    pub fn history_anomaly(&self, pages_id: DbId, views: u64, history: &[u64]) -> Option<Anomaly> {
+        if views < self.min_views || history.len() < self.min_history {
+            return None;
+        }
+        let median = median(history)?;
+        if views as f64 <= self.page_factor * median.max(1) as f64 {
+            return None;
+        }
+        let capped_views = self
+            .cap_factor
+            .map(|factor| (factor * median as f64).round() as u64)
+            .filter(|capped| *capped < views);
+        Some(Anomaly {
+            pages_id,
+            group_status_id: None,
+            kind: AnomalyKind::History,
+            views,
+            reference: median,
+            capped_views,
+            page: None,
+        })
+    }
+
+    /// Checks the views of a page against the total views of a group.
+    pub fn group_anomaly(
+        &self,
+        pages_id: DbId,
+        group_status_id: DbId,
+        views: u64,
+        group_pages: usize,
+        group_views: u64,
+    ) -> Option<Anomaly> {
+        if views < self.min_views || group_pages < self.min_group_pages || group_views == 0 {
+            return None;
+        }
+        if (views as f64) / (group_views as f64) <= self.group_share {
+            return None;
+        }
+        Some(Anomaly {
+            pages_id,
+            group_status_id: Some(group_status_id),
+            kind: AnomalyKind::Group,
+            views,
+            reference: group_views,
+            capped_views: None,
+            page: None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnomalyKind {
+    History,
+    Group,
+}
+
+impl AnomalyKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnomalyKind::History => "history",
+            AnomalyKind::Group => "group",
+        }
+    }
+}
+
+impl TryFrom<&str> for AnomalyKind {
+    type Error = &'static str;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "history" => Ok(AnomalyKind::History),
+            "group" => Ok(AnomalyKind::Group),
+            _ => Err("Invalid anomaly kind!"),
+        }
+    }
+}
+
+/// A flagged page. `reference` is the median of the page for `history`
+/// anomalies, and the total views of the group for `group` anomalies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    pub pages_id: DbId,
+    pub group_status_id: Option<DbId>,
+    pub kind: AnomalyKind,
+    pub views: u64,
+    pub reference: u64,
+    pub capped_views: Option<u64>,
+    pub page: Option<ResultPage>,
+}
+
+impl Anomaly {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "pages_id": self.pages_id,
+            "group_status_id": self.group_status_id,
+            "kind": self.kind.as_str(),
+            "views": self.views,
+            "reference": self.reference,
+            "capped_views": self.capped_views,
+            "page": self.page.as_ref().map(|page| json!({
+                "server": page.server,
+                "namespace_id": page.namespace_id,
+                "title": page.title,
+            })),
+        })
+    }
+}
+
+/// The median; the mean of the middle two for an even number of values.
+pub fn median(values: &[u64]) -> Option<u64> {
+    let mut values = values.to_vec();
+    values.sort_unstable();
+    let mid = values.len() / 2;
+    match values.len() {
+        0 => None,
+        n if n % 2 == 1 => Some(values[mid]),
+        _ => Some((values[mid - 1] + values[mid]) / 2),
+    }
+}
+
+pub struct ViewAnomalies<'a> {
+    baglama: &'a Baglama2,
+    ym: YearMonth,
+    config: AnomalyConfig,
+}
+
+impl<'a> ViewAnomalies<'a> {
+    pub fn new(baglama: &'a Baglama2, ym: YearMonth) -> Self {
+        Self {
+            baglama,
+            ym,
+            config: AnomalyConfig::from_config(baglama.config()),
+        }
+    }
+
+    /// Flags anomalies in the viewdata of the month, and caps them if
+    /// configured, unless that was done before for the month. Returns the
+    /// anomalies found.
+    pub async fn run(&self) -> Result<Vec<Anomaly>> {
+        let mut conn = self.baglama.get_tooldb_conn().await?;
+        let sql = "SELECT COUNT(*) FROM `view_anomaly_runs` WHERE `year`=? AND `month`=?";
+        let runs: Option<usize> = conn
+            .exec_first(sql, (self.ym.year(), self.ym.month()))
+            .await?;
+        if runs.unwrap_or_default() > 0 {
+            info!("View anomalies of {} were checked before", self.ym);
+            return Ok(vec![]);
+        }
+        let anomalies = self.detect().await?;
+        let table = DbMySql2::viewdata_table(&self.ym);
+        // One transaction, so a failed run leaves nothing behind and is repeated
+        let mut tx = conn.start_transaction(TxOpts::default()).await?;
+        self.store(&mut tx, &anomalies).await?;
+        let capped = self.cap(&mut tx, &table, &anomalies).await?;
+        if !capped.is_empty() {
+            let sql = DbMySql2::reset_totals_sql(capped.len());
+            tx.exec_drop(sql, capped).await?;
+        }
+        let sql = "INSERT IGNORE INTO `view_anomaly_runs` (`year`,`month`) VALUES (?,?)";
+        tx.exec_drop(sql, (self.ym.year(), self.ym.month())).await?;
+        tx.commit().await?;
+        if !anomalies.is_empty() {
+            info!("{} view anomalies in {}", anomalies.len(), self.ym);
+        }
+        Ok(anomalies)
+    }
+
+    /// Finds anomalies in the viewdata of the month, without changing anything.
+    pub async fn detect(&self) -> Result<Vec<Anomaly>> {
+        let table = DbMySql2::viewdata_table(&self.ym);
+        let mut conn = self.baglama.get_tooldb_conn().await?;
+
+        // (group_status_id, pages_id, views) of pages with enough views to check
+        let sql = format!(
+            "SELECT group_status_id,pages_id,MAX(page_views) FROM `{table}`
+            WHERE page_views>=? AND excluded=0
+            GROUP BY group_status_id,pages_id"
+        );
+        let candidates = conn
+            .exec_iter(sql, (self.config.min_views,))
+            .await?
+            .map_and_drop(from_row::<(DbId, DbId, u64)>)
+            .await?;
+        if candidates.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let page_views: HashMap<DbId, u64> = candidates
+            .iter()
+            .map(|(_, pages_id, views)| (*pages_id, *views))
+            .collect();
+        let history = self.load_history(&mut conn, &page_views).await?;
+        let mut anomalies: Vec<Anomaly> = page_views
+            .iter()
+            .filter_map(|(pages_id, views)| {
+                let history = history.get(pages_id).map(|h| h.as_slice()).unwrap_or(&[]);
+                self.config.history_anomaly(*pages_id, *views, history)
+            })
+            .collect();
+
+        let mut gs_ids: Vec<DbId> = candidates.iter().map(|(gs_id, _, _)| *gs_id).collect();
+        gs_ids.sort_unstable();
+        gs_ids.dedup();
+        let group_stats = Self::load_group_stats(&mut conn, &table, &gs_ids).await?;
+        anomalies.extend(candidates.iter().filter_map(|(gs_id, pages_id, views)| {
+            let (group_pages, group_views) = group_stats.get(gs_id)?;
+            self.config
+                .group_anomaly(*pages_id, *gs_id, *views, *group_pages, *group_views)
+        }));
+        anomalies.sort_by_key(|a| (a.pages_id, a.group_status_id, a.kind.as_str()));
+        Ok(anomalies)
+    }
+
+    /// The views of the pages in each previous month that has a viewdata table.
+    async fn load_history(
+        &self,
+        conn: &mut Conn,
+        page_views: &HashMap<DbId, u64>,
+    ) -> Result<HashMap<DbId, Vec<u64>>> {
+        let pages_ids: Vec<DbId> = page_views.keys().copied().collect();
+        let mut ret: HashMap<DbId, Vec<u64>> = HashMap::new();
+        let mut ym = self.ym;
+        for _ in 0..self.config.history_months {
+            ym = ym.previous()?;
+            let table = DbMySql2::viewdata_table(&ym);
+            if !Self::table_exists(conn, &table).await? {
+                continue;
+            }
+            for chunk in pages_ids.chunks(1000) {
+                let sql = format!(
+                    "SELECT pages_id,MAX(page_views) FROM `{table}`
+                    WHERE pages_id IN ({}) AND page_views IS NOT NULL
+                    GROUP BY pages_id",
+                    Baglama2::sql_placeholders(chunk.len())
+                );
+                let rows = conn
+                    .exec_iter(sql, chunk.to_vec())
+                    .await?
+                    .map_and_drop(from_row::<(DbId, u64)>)
+                    .await?;
+                for (pages_id, views) in rows {
+                    ret.entry(pages_id).or_default().push(views);
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Distinct pages and their views, per group_status.
+    async fn load_group_stats(
+        conn: &mut Conn,
+        table: &str,
+        gs_ids: &[DbId],
+    ) -> Result<HashMap<DbId, (usize, u64)>> {
+        let mut ret = HashMap::new();
+        for chunk in gs_ids.chunks(1000) {
+            let sql = format!(
+                "SELECT group_status_id,COUNT(*),COALESCE(SUM(views),0) FROM (
+                    SELECT group_status_id,MAX(page_views) AS views FROM `{table}`
+                    WHERE group_status_id IN ({}) AND excluded=0
+                    GROUP BY group_status_id,pages_id) t
+                GROUP BY group_status_id",
+                Baglama2::sql_placeholders(chunk.len())
+            );
+            let rows = conn
+                .exec_iter(sql, chunk.to_vec())
+                .await?
+                .map_and_drop(from_row::<(DbId, usize, u64)>)
+                .await?;
+            ret.extend(
+                rows.into_iter()
+                    .map(|(gs_id, pages, views)| (gs_id, (pages, views))),
+            );
+        }
+        Ok(ret)
+    }
+
+    async fn table_exists(conn: &mut Conn, table: &str) -> Result<bool> {
+        let sql = "SELECT COUNT(*) FROM information_schema.tables WHERE table_schema=DATABASE() AND table_name=?";
+        let count: Option<usize> = conn.exec_first(sql, (table,)).await?;
+        Ok(count.unwrap_or_default() > 0)
+    }
+
+    /// Keeps the first record of an anomaly, so capped pages keep their original views.
+    async fn store(&self, tx: &mut Transaction<'_>, anomalies: &[Anomaly]) -> Result<()> {
+        let sql = "INSERT IGNORE INTO `view_anomalies`
+            (`year`,`month`,`pages_id`,`group_status_id`,`kind`,`views`,`reference`,`capped_views`)
+            VALUES (?,?,?,?,?,?,?,?)";
+        for a in anomalies {
+            tx.exec_drop(
+                sql,
+                (
+                    self.ym.year(),
+                    self.ym.month(),
+                    a.pages_id,
+                    a.group_status_id.unwrap_or_default(),
+                    a.kind.as_str(),
+                    a.views,
+                    a.reference,
+                    a.capped_views,
+                ),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Caps the views of anomalies. Returns the group_status IDs whose views changed.
+    async fn cap(
+        &self,
+        tx: &mut Transaction<'_>,
+        table: &str,
+        anomalies: &[Anomaly],
+    ) -> Result<Vec<DbId>> {
+        let mut ret = vec![];
+        for a in anomalies {
+            if let Some(capped_views) = a.capped_views {
+                let sql = format!(
+                    "SELECT DISTINCT group_status_id FROM `{table}` WHERE pages_id=? AND page_views>?"
+                );
+                ret.extend(
+                    tx.exec_iter(sql, (a.pages_id, capped_views))
+                        .await?
+                        .map_and_drop(from_row::<DbId>)
+                        .await?,
+                );
+                let sql =
+                    format!("UPDATE `{table}` SET page_views=? WHERE pages_id=? AND page_views>?");
+                tx.exec_drop(sql, (capped_views, a.pages_id, capped_views))
+                    .await?;
+            }
+        }
+        ret.sort_unstable();
+        ret.dedup();
+        Ok(ret)
+    }
+
+    /// The stored anomalies of a month, with their pages.
+    pub async fn load(baglama: &Baglama2, ym: &YearMonth) -> Result<Vec<Anomaly>> {
+        let sql = "SELECT a.pages_id,a.group_status_id,a.kind,a.views,a.reference,a.capped_views,
+                COALESCE(sites.server,''),pages.namespace_id,FROM_BASE64(TO_BASE64(pages.title))
+            FROM view_anomalies a,pages,sites
+            WHERE a.year=? AND a.month=? AND pages.id=a.pages_id AND sites.id=pages.site
+            ORDER BY a.views DESC,a.pages_id";
+        let rows = baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, (ym.year(), ym.month()))
+            .await?
+            .map_and_drop(
+                from_row::<(
+                    DbId,
+                    DbId,
+                    String,
+                    u64,
+                    u64,
+                    Option<u64>,
+                    String,
+                    i32,
+                    String,
+                )>,
+            )
+            .await?;
+        rows.into_iter()
+            .map(
+                |(
+                    pages_id,
+                    gs_id,
+                    kind,
+                    views,
+                    reference,
+                    capped_views,
+                    server,
+                    namespace_id,
+                    title,
+                )| {
+                    Ok(Anomaly {
+                        pages_id,
+                        group_status_id: (gs_id > 0).then_some(gs_id),
+                        kind: AnomalyKind::try_from(kind.as_str()).map_err(|e| anyhow!(e))?,
+                        views,
+                        reference,
+                        capped_views,
+                        page: Some(ResultPage {
+                            server,
+                            namespace_id,
+                            title,
+                        }),
+                    })
+                },
+            )
+            .collect()
+    }
+
+    /// Called from `DbMySql2::ensure_table_exists`.
+    pub async fn ensure_table_exists(baglama: &Baglama2) -> Result<()> {
+        let sql = "CREATE TABLE IF NOT EXISTS `view_anomalies` (
+              `id` int(11) unsigned NOT NULL AUTO_INCREMENT,
+              `year` int(11) NOT NULL,
+              `month` int(11) NOT NULL,
+              `pages_id` int(11) unsigned NOT NULL,
+              `group_status_id` int(11) unsigned NOT NULL DEFAULT 0,
+              `kind` varchar(16) NOT NULL,
+              `views` bigint(20) unsigned NOT NULL,
+              `reference` bigint(20) unsigned NOT NULL,
+              `capped_views` bigint(20) unsigned DEFAULT NULL,
+              PRIMARY KEY (`id`),
+              UNIQUE KEY `month_page` (`year`,`month`,`pages_id`,`group_status_id`,`kind`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=ascii";
+        let mut conn = baglama.get_tooldb_conn().await?;
+        conn.exec_drop(sql, ()).await?;
+        let sql = "CREATE TABLE IF NOT EXISTS `view_anomaly_runs` (
+              `year` int(11) NOT NULL,
+              `month` int(11) NOT NULL,
+              `run_at` timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+              PRIMARY KEY (`year`,`month`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=ascii";
+        conn.exec_drop(sql, ()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(&[]), None);
+        assert_eq!(median(&[5, 1, 3]), Some(3));
+        assert_eq!(median(&[4, 1, 3, 100]), Some(3));
+    }
+
+    #[test]
+    fn test_history_anomaly() {
+        let config = AnomalyConfig::default();
+        let history = [1000, 1200, 900, 1100];
+        // 10x the median of 1050 is not enough
+        assert_eq!(config.history_anomaly(1, 10_500, &history), None);
+        let anomaly = config.history_anomaly(1, 2_000_000, &history).unwrap();
+        assert_eq!(anomaly.kind, AnomalyKind::History);
+        assert_eq!(anomaly.reference, 1050);
+        assert_eq!(anomaly.capped_views, None);
+        // Too little history, or too few views
+        assert_eq!(config.history_anomaly(1, 2_000_000, &history[..2]), None);
+        assert_eq!(config.history_anomaly(1, 9_000, &[1, 1, 1]), None);
+
+        let config = AnomalyConfig {
+            cap_factor: Some(2.0),
+            ..Default::default()
+        };
+        let anomaly = config.history_anomaly(1, 2_000_000, &history).unwrap();
+        assert_eq!(anomaly.capped_views, Some(2100));
+    }
+
+    #[test]
+    fn test_group_anomaly() {
+        let config = AnomalyConfig::default();
+        let anomaly = config.group_anomaly(1, 7, 900_000, 50, 1_000_000).unwrap();
+        assert_eq!(anomaly.group_status_id, Some(7));
+        assert_eq!(anomaly.reference, 1_000_000);
+        assert_eq!(config.group_anomaly(1, 7, 400_000, 50, 1_000_000), None);
+        // Small groups are dominated by single pages anyway
+        assert_eq!(config.group_anomaly(1, 7, 900_000, 5, 1_000_000), None);
+    }
+
+    #[test]
+    fn test_from_config() {
+        let config =
+            json!({"anomaly_detection": {"page_factor": 20.0, "cap_factor": 5, "min_views": 500}});
+        let config = AnomalyConfig::from_config(&config);
+        assert_eq!(config.page_factor, 20.0);
+        assert_eq!(config.cap_factor, Some(5.0));
+        assert_eq!(config.min_views, 500);
+        assert_eq!(config.history_months, 6);
+        assert_eq!(
+            AnomalyConfig::from_config(&json!({})),
+            AnomalyConfig::default()
+        );
+    }
+}
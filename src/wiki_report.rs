@@ -0,0 +1,339 @@
+//! Renders a group month as wikitext (for posting on-wiki) and as a static
+//! HTML page.
+//!
+//! Both use a template with `%placeholder%` fields; the defaults can be
+//! replaced by files set in the `report_wikitext_template` and
+//! `report_html_template` config keys. Available placeholders: `%group_id%`,
+//! `%category%`, `%month%`, `%total_views%`, `%pages%`, `%wikis%`,
+//! `%sites_table%`, `%top_files_table%`, and (HTML only) `%top_files_chart%`.
+
+use crate::{
+    charts,
+    group_report::GroupReport,
+    results_reader::{GroupResults, ResultsReader, StorageReader},
+    row_group_status::{RowGroupStatus, StorageType},
+    Baglama2, GroupId, YearMonth,
+};
+use anyhow::{anyhow, Result};
+use mysql_async::{from_row, prelude::*};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::collections::BTreeMap;
+
+const DEFAULT_WIKITEXT_TEMPLATE: &str = "== BaGLAMa statistics for %category%, %month% ==
+* Total page views: %total_views%
+* Pages using files: %pages%
+* Wikis: %wikis%
+
+=== Views per wiki ===
+%sites_table%
+
+=== Top files ===
+%top_files_table%
+";
+
+const DEFAULT_HTML_TEMPLATE: &str = "<!DOCTYPE html>
+<html>
+<head><meta charset=\"utf-8\"><title>BaGLAMa: %category%, %month%</title></head>
+<body>
+<h1>%category%, %month%</h1>
+<ul>
+<li>Total page views: %total_views%</li>
+<li>Pages using files: %pages%</li>
+<li>Wikis: %wikis%</li>
+</ul>
+<h2>Views per wiki</h2>
+%sites_table%
+<h2>Top files</h2>
+%top_files_chart%
+%top_files_table%
+</body>
+</html>
+";
+
+/// Characters to encode in a file name in a URL path.
+const URL_TITLE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+const THUMBNAIL_WIDTH: usize = 120;
+const CHART_TOP_FILES: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SiteViews {
+    pub server: String,
+    pub pages: u64,
+    pub views: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WikiReport {
+    pub category: String,
+    pub report: GroupReport,
+    pub sites: Vec<SiteViews>,
+}
+
+impl WikiReport {
+    pub async fn load(
+        baglama: &Baglama2,
+        group_id: GroupId,
+        ym: &YearMonth,
+        limit: usize,
+    ) -> Result<Self> {
+        let group = baglama
+            .get_group(&group_id)
+            .await?
+            .ok_or_else(|| anyhow!("No group {group_id}"))?;
+        let gs = baglama
+            .get_group_status(&group_id, ym)
+            .await?
+            .ok_or_else(|| anyhow!("No status for group {group_id} in {ym}"))?;
+        let report = GroupReport::generate(baglama, group_id, ym, limit).await?;
+        let sites = Self::load_sites(baglama, &gs).await?;
+        Ok(Self::new(group.category().to_owned(), report, sites))
+    }
+
+    /// Pages and views per wiki of a group month: from `gs2site` for mysql2,
+    /// from the results otherwise, as `gs2site` is only written for mysql2.
+    pub async fn load_sites(baglama: &Baglama2, gs: &RowGroupStatus) -> Result<Vec<SiteViews>> {
+        if gs.storage != StorageType::Mysql2 {
+            return Ok(Self::site_views(
+                &StorageReader::new(baglama).read(gs).await?,
+            ));
+        }
+        let sql = "SELECT COALESCE(sites.server,''),gs2site.pages,gs2site.views FROM gs2site,sites WHERE gs2site.group_status_id=? AND sites.id=gs2site.site_id";
+        Ok(baglama
+            .get_tooldb_conn()
+            .await?
+            .exec_iter(sql, (gs.id,))
+            .await?
+            .map_and_drop(from_row::<(String, u64, u64)>)
+            .await?
+            .into_iter()
+            .map(|(server, pages, views)| SiteViews {
+                server,
+                pages,
+                views,
+            })
+            .collect())
+    }
+
+    /// Sites are sorted by views, descending.
+    pub fn new(category: String, report: GroupReport, mut sites: Vec<SiteViews>) -> Self {
+        sites.sort_by(|a, b| b.views.cmp(&a.views).then_with(|| a.server.cmp(&b.server)));
+        Self {
+            category,
+            report,
+            sites,
+        }
+    }
+
+    /// Pages and views per wiki, leaving out excluded pages like `gs2site`.
+    pub fn site_views(results: &GroupResults) -> Vec<SiteViews> {
+        let mut sites: BTreeMap<&str, SiteViews> = BTreeMap::new();
+        for (page, views) in results.pages.iter().filter(|(_, views)| !views.excluded) {
+            let site = sites.entry(&page.server).or_insert_with(|| SiteViews {
+                server: page.server.to_owned(),
+                pages: 0,
+                views: 0,
+            });
+            site.pages += 1;
+            site.views += views.views.unwrap_or_default();
+        }
+        sites.into_values().collect()
+    }
+
+    fn template(baglama: Option<&Baglama2>, key: &str, default: &str) -> Result<String> {
+        match baglama.and_then(|b| b.config()[key].as_str()) {
+            Some(path) => Ok(std::fs::read_to_string(path)?),
+            None => Ok(default.to_string()),
+        }
+    }
+
+    pub fn wikitext_template(baglama: Option<&Baglama2>) -> Result<String> {
+        Self::template(
+            baglama,
+            "report_wikitext_template",
+            DEFAULT_WIKITEXT_TEMPLATE,
+        )
+    }
+
+    pub fn html_template(baglama: Option<&Baglama2>) -> Result<String> {
+        Self::template(baglama, "report_html_template", DEFAULT_HTML_TEMPLATE)
+    }
+
+    fn fill(
+        &self,
+        template: &str,
+        sites_table: &str,
+        top_files_table: &str,
+        top_files_chart: &str,
+        escape: fn(&str) -> String,
+    ) -> String {
+        template
+            .replace("%group_id%", &self.report.group_id.to_string())
+            .replace("%category%", &escape(&self.category))
+            .replace("%month%", &self.report.ym.to_string())
+            .replace("%total_views%", &self.report.total_views.to_string())
+            .replace("%pages%", &self.report.distinct_pages.to_string())
+            .replace("%wikis%", &self.report.distinct_wikis.to_string())
+            .replace("%sites_table%", sites_table)
+            .replace("%top_files_table%", top_files_table)
+            .replace("%top_files_chart%", top_files_chart)
+    }
+
+    pub fn render_wikitext(&self, template: &str) -> String {
+        let mut sites_table =
+            "{| class=\"wikitable sortable\"\n! Wiki !! Pages !! Views\n".to_string();
+        for site in &self.sites {
+            sites_table += &format!(
+                "|-\n| {} || {} || {}\n",
+                wikitext_escape(&site.server),
+                site.pages,
+                site.views
+            );
+        }
+        sites_table += "|}";
+
+        let mut files_table =
+            "{| class=\"wikitable sortable\"\n! # !! File !! Name !! Views !! Pages\n".to_string();
+        for (num, file) in self.report.top_files.iter().enumerate() {
+            let title = wikitext_escape(&file.name.replace('_', " "));
+            files_table += &format!(
+                "|-\n| {} || [[File:{title}|{THUMBNAIL_WIDTH}px]] || [[:File:{title}|{title}]] || {} || {}\n",
+                num + 1,
+                file.views,
+                file.pages
+            );
+        }
+        files_table += "|}";
+        self.fill(template, &sites_table, &files_table, "", wikitext_escape)
+    }
+
+    pub fn render_html(&self, template: &str) -> String {
+        let mut sites_table =
+            "<table>\n<tr><th>Wiki</th><th>Pages</th><th>Views</th></tr>\n".to_string();
+        for site in &self.sites {
+            sites_table += &format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                html_escape(&site.server),
+                site.pages,
+                site.views
+            );
+        }
+        sites_table += "</table>";
+
+        let mut files_table =
+            "<table>\n<tr><th>#</th><th>File</th><th>Views</th><th>Pages</th></tr>\n".to_string();
+        for (num, file) in self.report.top_files.iter().enumerate() {
+            let url = format!(
+                "https://commons.wikimedia.org/wiki/File:{}",
+                utf8_percent_encode(&file.name.replace(' ', "_"), URL_TITLE)
+            );
+            files_table += &format!(
+                "<tr><td>{}</td><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+                num + 1,
+                html_escape(&url),
+                html_escape(&file.name.replace('_', " ")),
+                file.views,
+                file.pages
+            );
+        }
+        files_table += "</table>";
+        let chart = charts::top_files_chart("Top files", &self.report.top_files, CHART_TOP_FILES);
+        self.fill(template, &sites_table, &files_table, &chart, html_escape)
+    }
+}
+
+/// Replaces characters with a meaning in wikitext (links, templates, table
+/// cells, tags, signatures) by HTML entities, which MediaWiki decodes again.
+pub fn wikitext_escape(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '|' | '[' | ']' | '{' | '}' | '<' | '>' | '~' => format!("&#{};", c as u32),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+pub fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::group_report::TopFile;
+
+    fn wiki_report() -> WikiReport {
+        let report = GroupReport {
+            group_id: 7,
+            ym: YearMonth::new(2024, 3).unwrap(),
+            total_views: 1500,
+            distinct_files: 1,
+            distinct_pages: 3,
+            distinct_wikis: 2,
+            top_files: vec![TopFile {
+                name: "Tom_&_Jerry.jpg".to_string(),
+                views: 1500,
+                pages: 3,
+            }],
+            top_pages: vec![],
+        };
+        let sites = vec![
+            SiteViews {
+                server: "en.wikipedia.org".to_string(),
+                pages: 1,
+                views: 500,
+            },
+            SiteViews {
+                server: "de.wikipedia.org".to_string(),
+                pages: 2,
+                views: 1000,
+            },
+        ];
+        WikiReport::new("Museum <X>".to_string(), report, sites)
+    }
+
+    #[test]
+    fn test_render_wikitext() {
+        let report = wiki_report();
+        let text = report.render_wikitext(&WikiReport::wikitext_template(None).unwrap());
+        assert!(text.starts_with("== BaGLAMa statistics for Museum &#60;X&#62;, 2024-03 =="));
+        assert!(text.contains("| 1 || [[File:Tom & Jerry.jpg|120px]] || [[:File:Tom & Jerry.jpg|Tom & Jerry.jpg]] || 1500 || 3\n"));
+        // Sorted by views
+        let de = text.find("de.wikipedia.org").unwrap();
+        let en = text.find("en.wikipedia.org").unwrap();
+        assert!(de < en);
+    }
+
+    #[test]
+    fn test_wikitext_escape() {
+        assert_eq!(wikitext_escape("Tom & Jerry.jpg"), "Tom & Jerry.jpg");
+        assert_eq!(
+            wikitext_escape("A|B]]{{C}}~~~~"),
+            "A&#124;B&#93;&#93;&#123;&#123;C&#125;&#125;&#126;&#126;&#126;&#126;"
+        );
+    }
+
+    #[test]
+    fn test_render_html() {
+        let html = wiki_report().render_html(&WikiReport::html_template(None).unwrap());
+        assert!(html.contains("<h1>Museum &lt;X&gt;, 2024-03</h1>"));
+        assert!(html.contains("<svg "));
+        assert!(html.contains(
+            "<a href=\"https://commons.wikimedia.org/wiki/File:Tom_%26_Jerry.jpg\">Tom &amp; Jerry.jpg</a>"
+        ));
+    }
+
+    #[test]
+    fn test_custom_template() {
+        let text =
+            wiki_report().render_wikitext("%group_id%/%month%: %total_views% on %wikis% wikis");
+        assert_eq!(text, "7/2024-03: 1500 on 2 wikis");
+    }
+}
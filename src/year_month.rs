@@ -2,7 +2,7 @@ use crate::Baglama2;
 use anyhow::{anyhow, Result};
 use chrono::Datelike;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct YearMonth {
     year: i32,
     month: u32,
@@ -28,6 +28,15 @@ impl YearMonth {
         self.month
     }
 
+    /// The month before this one.
+    pub fn previous(&self) -> Result<Self> {
+        if self.month == 1 {
+            Self::new(self.year - 1, 12)
+        } else {
+            Self::new(self.year, self.month - 1)
+        }
+    }
+
     pub fn make_production_directory(&self, baglama: &Baglama2) -> Result<String> {
         let subdir = chrono::NaiveDate::from_ymd_opt(self.year, self.month, 1)
             .ok_or(anyhow!(format!("{}/{}", self.year, self.month)))?
@@ -95,6 +104,15 @@ mod tests {
         assert_eq!(ym.month(), 2);
     }
 
+    #[test]
+    fn test_previous() {
+        let ym = YearMonth::new(2020, 3).unwrap().previous().unwrap();
+        assert_eq!(ym.to_string().as_str(), "2020-02");
+        let ym = YearMonth::new(2020, 1).unwrap().previous().unwrap();
+        assert_eq!(ym.to_string().as_str(), "2019-12");
+        assert!(YearMonth::new(2000, 1).unwrap().previous().is_err());
+    }
+
     #[test]
     fn test_bad_month() {
         assert!(YearMonth::new(2020, 0).is_err());